@@ -0,0 +1,66 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Reading and writing of the files exported by the tool, gzip-compressed when their name ends with
+//! `.gz`, to keep large exports small.
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Return whether the file at `path` is gzip-compressed, according to its name.
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// Return `path` without its `.gz` extension, if any, to find the format of the content.
+pub fn strip_gz(path: &Path) -> PathBuf {
+    if is_gzip(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Write `content` to the file at `path`, compressing it if the name of the file ends with `.gz`.
+pub fn write(path: &Path, content: impl AsRef<[u8]>) -> io::Result<()> {
+    if !is_gzip(path) {
+        return fs::write(path, content);
+    }
+    let mut encoder = GzEncoder::new(fs::File::create(path)?, Compression::default());
+    encoder.write_all(content.as_ref())?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Read the content of the file at `path` as a string, decompressing it if the name of the file
+/// ends with `.gz`.
+pub fn read_to_string(path: &Path) -> io::Result<String> {
+    if !is_gzip(path) {
+        return fs::read_to_string(path);
+    }
+    let mut content = String::new();
+    GzDecoder::new(fs::File::open(path)?).read_to_string(&mut content)?;
+    Ok(content)
+}
@@ -21,28 +21,106 @@ SOFTWARE.
 */
 
 use anyhow::Result;
-use std::time;
+use std::{io, time};
 
 mod linux;
+mod memory;
 
 /// A trait for storage device operations.
+/// The operations are synchronous: the test relies on blocking O_DIRECT I/O, on timeouts enforced
+/// by helper threads and on progress bars driven from the I/O loop. An async application can run
+/// the test on a blocking thread (e.g. with tokio's `spawn_blocking`).
 pub trait Device {
     /// Returns the size of the device in bytes.
     fn get_size(&self) -> u64;
     /// Returns the device information.
     fn get_device_info(&mut self) -> Result<&DeviceInfo>;
-    /// Reads data from the device at the given offset.
+    /// Reads data from the device at the given offset. If `data` extends
+    /// beyond the end of the device, only the available bytes are read and
+    /// the rest of `data` is zeroed.
     /// Returns the time spent reading data.
     fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<time::Duration>;
-    /// Writes data to the device at the given offset.
+    /// Writes data to the device at the given offset. If `data` extends
+    /// beyond the end of the device, only the bytes fitting in the device are
+    /// written.
     /// Returns the time spent writing data.
     fn write(&mut self, offset: u64, data: &[u8]) -> Result<time::Duration>;
     /// Returns the block size (in bytes) memory operations needs to be aligned
     /// to for this device.
     fn get_memory_alignment(&self) -> usize;
+    /// Sets the maximum duration of a single read or write. Operations taking
+    /// longer fail with an error. `None` disables the timeout.
+    fn set_io_timeout(&mut self, timeout: Option<time::Duration>);
+    /// Sets the maximum time to wait for the device to reappear after it
+    /// disconnects during a read or write. The device is then reopened, if it
+    /// is the same device, and the operation retried. `None` disables waiting.
+    fn set_reconnect_wait(&mut self, wait: Option<time::Duration>);
+    /// Discards (TRIMs) `len` bytes at the given offset, so that the device
+    /// can erase them. Their content is undefined afterwards.
+    /// Returns false if the device does not support discard.
+    fn discard(&mut self, offset: u64, len: u64) -> Result<bool>;
+    /// Flushes the data written to the device and drops the OS caches holding
+    /// its content, so that the following reads access the media.
+    fn flush_caches(&mut self) -> Result<()>;
+    /// Returns the number of times the device was reopened after it
+    /// disconnected.
+    fn reconnections(&self) -> usize;
+    /// Returns the current temperature of the device in degrees Celsius, if
+    /// the OS exposes a sensor for it.
+    fn temperature(&self) -> Option<f64>;
+}
+
+/// The class of a failed read/write, derived from the OS error causing it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The media could not store or return the data (e.g. EIO).
+    Media,
+    /// The device is gone, e.g. because it was unplugged (ENXIO, ENODEV).
+    Disconnected,
+    /// The operation did not complete within the I/O timeout.
+    TimedOut,
+    /// There is no space left on the device (ENOSPC).
+    NoSpace,
+    Other,
+}
+
+impl ErrorKind {
+    /// Classify `err`, returned by a read/write of a device, from the first OS error in its chain.
+    pub fn of(err: &anyhow::Error) -> ErrorKind {
+        let Some(io_err) = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+        else {
+            return ErrorKind::Other;
+        };
+        match io_err.raw_os_error() {
+            Some(libc::EIO) | Some(libc::EILSEQ) | Some(libc::EBADMSG) | Some(libc::ENODATA) => {
+                ErrorKind::Media
+            }
+            Some(libc::ENXIO) | Some(libc::ENODEV) | Some(libc::ENOMEDIUM) => {
+                ErrorKind::Disconnected
+            }
+            Some(libc::ETIMEDOUT) => ErrorKind::TimedOut,
+            Some(libc::ENOSPC) => ErrorKind::NoSpace,
+            _ if io_err.kind() == io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Returns a short description of the error class, for error messages.
+    pub fn description(&self) -> &'static str {
+        match self {
+            ErrorKind::Media => "media error",
+            ErrorKind::Disconnected => "device disconnected",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::NoSpace => "no space left",
+            ErrorKind::Other => "I/O error",
+        }
+    }
 }
 
 /// Information about a storage device.
+#[derive(Clone)]
 pub struct DeviceInfo {
     pub vendor: String,
     pub model: String,
@@ -53,7 +131,41 @@ pub struct DeviceInfo {
     pub is_block_device: bool,
     pub logical_block_size: u64,
     pub physical_block_size: u64,
+    /// The smallest I/O size (in bytes) the device prefers, or 0 if not reported.
+    pub minimum_io_size: u64,
+    /// The I/O size (in bytes) giving the device its best throughput, or 0 if not reported.
+    pub optimal_io_size: u64,
+    /// Whether the device has rotating media (HDD) rather than flash, if reported.
+    pub rotational: Option<bool>,
+    /// Whether the device has removable media (e.g. a card reader), if reported.
+    pub removable: Option<bool>,
+    /// The number of commands the device can queue, if reported (SCSI devices, including USB
+    /// mass storage).
+    pub queue_depth: String,
     pub subsystems: Vec<String>,
+    /// Whether the disk of the device holds the root filesystem of the running system.
+    pub holds_root_filesystem: bool,
+    /// Whether the device is a partition rather than a whole disk.
+    pub is_partition: bool,
+    /// The partition number, if the device is a partition.
+    pub partition_number: String,
+    /// The path of the disk containing the partition, if the device is a partition.
+    pub partition_disk: String,
+    /// The offset (in bytes) of the partition in its disk, if the device is a partition.
+    pub partition_start: u64,
+    /// The alignment (in bytes) required for the memory buffers used for I/O, or 0 if none.
+    pub memory_alignment: usize,
+    /// Whether the device is accessed with direct I/O (O_DIRECT), bypassing the OS cache.
+    pub direct_io: bool,
+    /// Whether writes are synchronous (O_SYNC): they complete only once the data is committed to
+    /// the media.
+    pub sync_io: bool,
+    /// The space (in bytes) allocated on the filesystem for an image file, which is lower than its
+    /// size if the file is sparse. `None` for block devices.
+    pub allocated_size: Option<u64>,
+    /// The free space (in bytes) of the filesystem holding an image file. `None` for block
+    /// devices.
+    pub filesystem_free: Option<u64>,
     pub usb_driver: String,
     pub usb_vendor_id: String,
     pub usb_product_id: String,
@@ -62,6 +174,22 @@ pub struct DeviceInfo {
     pub usb_serial_number: String,
     pub usb_version: String,
     pub usb_speed: String,
+    /// The bus and port path of the USB device, e.g. "2-1.4.1" for port 1 of the hub on port 4
+    /// of the hub on port 1 of bus 2, identifying the physical port the device is connected to.
+    pub usb_port_path: String,
+    /// Whether the USB device advertises SuperSpeed (5 Gbps or faster) capability, either through
+    /// its USB version or its BOS descriptors.
+    pub usb_superspeed_capable: bool,
+    /// The manufacturer ID from the CID register of an SD card or eMMC device.
+    pub mmc_manfid: String,
+    /// The OEM/application ID from the CID register of an SD card or eMMC device.
+    pub mmc_oemid: String,
+    /// The product name from the CID register of an SD card or eMMC device.
+    pub mmc_name: String,
+    /// The product serial number from the CID register of an SD card or eMMC device.
+    pub mmc_serial: String,
+    /// The manufacturing date (month/year) from the CID register of an SD card or eMMC device.
+    pub mmc_date: String,
 }
 
 impl DeviceInfo {
@@ -76,7 +204,22 @@ impl DeviceInfo {
             is_block_device: false,
             logical_block_size: 0,
             physical_block_size: 0,
+            minimum_io_size: 0,
+            optimal_io_size: 0,
+            rotational: None,
+            removable: None,
+            queue_depth: String::new(),
             subsystems: Vec::new(),
+            holds_root_filesystem: false,
+            is_partition: false,
+            partition_number: String::new(),
+            partition_disk: String::new(),
+            partition_start: 0,
+            memory_alignment: 0,
+            direct_io: false,
+            sync_io: false,
+            allocated_size: None,
+            filesystem_free: None,
             usb_vendor_id: String::new(),
             usb_product_id: String::new(),
             usb_manufacturer: String::new(),
@@ -84,59 +227,369 @@ impl DeviceInfo {
             usb_serial_number: String::new(),
             usb_version: String::new(),
             usb_speed: String::new(),
+            usb_port_path: String::new(),
             usb_driver: String::new(), // Add the missing field 'usb_driver'
+            usb_superspeed_capable: false,
+            mmc_manfid: String::new(),
+            mmc_oemid: String::new(),
+            mmc_name: String::new(),
+            mmc_serial: String::new(),
+            mmc_date: String::new(),
         }
     }
 
-    /// Prints the device information to stdout.
+    /// Returns a fingerprint identifying the physical drive across runs, as a hex string: a hash
+    /// of its stable identity fields (serial numbers, model, firmware, USB and MMC manufacturer IDs
+    /// and size). Empty fields are hashed as such, so that the fingerprint is still stable for
+    /// drives not reporting some of them.
+    pub fn fingerprint(&self) -> String {
+        let size = self.size.to_string();
+        let fields = [
+            self.serial.as_str(),
+            self.model.as_str(),
+            self.revision.as_str(),
+            self.firmware_revision.as_str(),
+            self.usb_vendor_id.as_str(),
+            self.usb_product_id.as_str(),
+            self.usb_serial_number.as_str(),
+            self.mmc_manfid.as_str(),
+            self.mmc_oemid.as_str(),
+            self.mmc_serial.as_str(),
+            size.as_str(),
+        ];
+        // The fields are separated by a NUL byte, so that moving characters between adjacent
+        // fields changes the hash.
+        format!(
+            "{:016x}",
+            crate::checksum::fnv1a64(fields.join("\0").as_bytes())
+        )
+    }
+
+    /// Prints the device information to stdout, as a table with a section for each group of
+    /// fields. Empty fields and sections are omitted.
     pub fn print(&self) {
-        print_if_not_empty("Vendor", &self.vendor);
-        print_if_not_empty("Model", &self.model);
-        print_if_not_empty("Serial number", &self.serial);
-        print_if_not_empty("Revision", &self.revision);
-        print_if_not_empty("Firmware revision", &self.firmware_revision);
-        println!(
-            "Device size: {} bytes ({:.3} GiB, {:.3} GB)",
-            self.size,
-            self.size as f64 / 1024.0 / 1024.0 / 1024.0,
-            self.size as f64 / 1_000_000_000.0,
-        );
+        let general = vec![
+            ("Vendor", self.vendor.clone()),
+            ("Model", self.model.clone()),
+            ("Serial number", self.serial.clone()),
+            ("Revision", self.revision.clone()),
+            ("Firmware revision", self.firmware_revision.clone()),
+            ("Device size", crate::size::format_bytes(self.size)),
+            (
+                "I/O alignment",
+                if self.memory_alignment > 0 {
+                    format!("{} bytes", self.memory_alignment)
+                } else {
+                    String::from("none")
+                },
+            ),
+            (
+                "Direct I/O (O_DIRECT)",
+                String::from(if self.direct_io {
+                    "enabled"
+                } else {
+                    "disabled"
+                }),
+            ),
+            (
+                "Synchronous writes (O_SYNC)",
+                String::from(if self.sync_io { "enabled" } else { "disabled" }),
+            ),
+            (
+                "Allocated size",
+                self.allocated_size.map_or(String::new(), |allocated_size| {
+                    format!("{} bytes", allocated_size)
+                }),
+            ),
+            ("Subsystems", self.subsystems.join(", ")),
+            ("Fingerprint", self.fingerprint()),
+        ];
+
+        let mut block = Vec::new();
         if self.is_block_device {
+            block.push((
+                "Block size (physical/logical)",
+                format!(
+                    "{}/{} bytes",
+                    self.physical_block_size, self.logical_block_size
+                ),
+            ));
+            // Not known when sysfs is not available.
+            if self.minimum_io_size > 0 {
+                block.push((
+                    "I/O size (minimum/optimal)",
+                    format!("{}/{} bytes", self.minimum_io_size, self.optimal_io_size),
+                ));
+            }
+            block.push((
+                "Rotational",
+                match self.rotational {
+                    Some(true) => String::from("yes (HDD)"),
+                    Some(false) => String::from("no"),
+                    None => String::new(),
+                },
+            ));
+            block.push((
+                "Removable",
+                match self.removable {
+                    Some(true) => String::from("yes"),
+                    Some(false) => String::from("no"),
+                    None => String::new(),
+                },
+            ));
+            block.push(("Queue depth", self.queue_depth.clone()));
+            block.push((
+                "Device type",
+                if self.is_partition {
+                    format!(
+                        "partition {} of {} (offset {} bytes, size {} bytes)",
+                        self.partition_number, self.partition_disk, self.partition_start, self.size
+                    )
+                } else {
+                    String::from("whole disk")
+                },
+            ));
+            if self.holds_root_filesystem {
+                block.push((
+                    "System disk",
+                    String::from("yes (holds the root filesystem)"),
+                ));
+            }
+        }
+
+        let mut usb = vec![("Driver", self.usb_driver.clone())];
+        if !self.usb_vendor_id.is_empty() || !self.usb_product_id.is_empty() {
+            usb.push((
+                "Vendor/product ID",
+                format!("{}:{}", self.usb_vendor_id, self.usb_product_id),
+            ));
+        }
+        usb.push(("Port path", self.usb_port_path.clone()));
+        usb.push(("Manufacturer", self.usb_manufacturer.clone()));
+        usb.push(("Product", self.usb_product.clone()));
+        usb.push(("Serial number", self.usb_serial_number.clone()));
+        if !self.usb_version.is_empty() || !self.usb_speed.is_empty() {
+            usb.push((
+                "Version (speed)",
+                format!("{} ({} Mbps)", self.usb_version, self.usb_speed),
+            ));
+        }
+
+        let mmc = vec![
+            ("Manufacturer ID", self.mmc_manfid.clone()),
+            ("OEM ID", self.mmc_oemid.clone()),
+            ("Product name", self.mmc_name.clone()),
+            ("Serial number", self.mmc_serial.clone()),
+            ("Manufacturing date", self.mmc_date.clone()),
+        ];
+
+        print_table(&[
+            ("General", general),
+            ("Block device", block),
+            ("USB", usb),
+            ("MMC/SD", mmc),
+        ]);
+
+        if self.is_partition {
             println!(
-                "Block size (physical/logical): {}/{} bytes",
-                self.physical_block_size, self.logical_block_size
+                "{}",
+                console::style(format!(
+                    "Warning: only the partition will be tested, to validate the whole drive \
+                     test {} instead",
+                    self.partition_disk
+                ))
+                .yellow()
             );
         }
-        print_if_not_empty("Subsystems", &self.subsystems.join(", "));
-        print_if_not_empty("USB driver", &self.usb_driver);
-        if !self.usb_vendor_id.is_empty() || !self.usb_product_id.is_empty() {
+        if let Some(warning) = self.usb_speed_warning() {
             println!(
-                "USB vendor/product ID: {}:{}",
-                self.usb_vendor_id, self.usb_product_id
+                "{}",
+                console::style(format!("Warning: {}", warning)).yellow()
             );
         }
-        print_if_not_empty("USB manufacturer", &self.usb_manufacturer);
-        print_if_not_empty("USB product", &self.usb_product);
-        print_if_not_empty("USB serial number", &self.usb_serial_number);
-        if !self.usb_version.is_empty() || !self.usb_speed.is_empty() {
+        if let Some(warning) = self.serial_warning() {
             println!(
-                "USB version (speed): {} ({} Mbps)",
-                self.usb_version, self.usb_speed
+                "{}",
+                console::style(format!("Warning: {}", warning)).yellow()
             );
         }
     }
+
+    /// Returns whether the device looks like removable media: it reports removable media, or is
+    /// attached through USB or MMC/SD, as USB drives often do not report removable media.
+    pub fn is_removable(&self) -> bool {
+        self.removable == Some(true)
+            || self
+                .subsystems
+                .iter()
+                .any(|subsystem| subsystem == "usb" || subsystem == "mmc")
+    }
+
+    /// Returns a warning message if the USB or MMC/SD device reports no serial number, or a
+    /// serial number looking like a placeholder, as counterfeit drives often do.
+    pub fn serial_warning(&self) -> Option<String> {
+        let is_usb = self.subsystems.iter().any(|subsystem| subsystem == "usb");
+        let is_mmc = self.subsystems.iter().any(|subsystem| subsystem == "mmc");
+        // Other devices, such as loop devices, have no serial number to check.
+        if !is_usb && !is_mmc {
+            return None;
+        }
+        let serials = [
+            self.serial.as_str(),
+            self.usb_serial_number.as_str(),
+            self.mmc_serial.as_str(),
+        ];
+        if serials.iter().all(|serial| serial.is_empty()) {
+            return Some(String::from(
+                "device reports no serial number, which is common for counterfeit drives",
+            ));
+        }
+        let serial = serials
+            .iter()
+            .find(|serial| is_placeholder_serial(serial))?;
+        Some(format!(
+            "serial number {:?} looks like a placeholder, which is common for counterfeit drives",
+            serial
+        ))
+    }
+
+    /// Returns a warning message if the USB device supports SuperSpeed but negotiated a slower
+    /// connection with the host, which typically happens with USB 2.0 ports, hubs or cables.
+    pub fn usb_speed_warning(&self) -> Option<String> {
+        // The speed is reported by sysfs in Mbps, e.g. "480" or "5000" (or "1.5" for low speed).
+        let speed = self.usb_speed.parse::<f64>().ok()?;
+        if !self.usb_superspeed_capable || speed >= 5000.0 {
+            return None;
+        }
+        Some(format!(
+            "device supports SuperSpeed but negotiated at {} Mbps, check your port/cable",
+            self.usb_speed
+        ))
+    }
+}
+
+/// Serial numbers commonly reported by counterfeit drives, besides repeated and sequential digits.
+const PLACEHOLDER_SERIALS: [&str; 6] = ["none", "n/a", "null", "serial", "default", "unknown"];
+/// The minimum length of a serial number made of repeated characters or sequential digits to be
+/// considered a placeholder, as short real serial numbers may happen to be such.
+const MIN_PATTERN_SERIAL_LEN: usize = 6;
+
+/// Returns whether `serial` looks like a placeholder rather than a real serial number: a known
+/// placeholder, or at least `MIN_PATTERN_SERIAL_LEN` characters that are the same character
+/// repeated (e.g. "00000000") or consecutive decimal digits (e.g. "123456789").
+fn is_placeholder_serial(serial: &str) -> bool {
+    let serial = serial.trim();
+    // MMC/SD serial numbers are reported in hexadecimal, e.g. "0x00000000".
+    let serial = serial.strip_prefix("0x").unwrap_or(serial);
+    if serial.is_empty() {
+        return false;
+    }
+    if PLACEHOLDER_SERIALS
+        .iter()
+        .any(|placeholder| serial.eq_ignore_ascii_case(placeholder))
+    {
+        return true;
+    }
+    if serial.chars().count() < MIN_PATTERN_SERIAL_LEN {
+        return false;
+    }
+    let mut chars = serial.chars();
+    let first = chars.next().unwrap_or_default();
+    if chars.all(|c| c.eq_ignore_ascii_case(&first)) {
+        return true;
+    }
+    let digits = serial
+        .chars()
+        .map(|c| c.to_digit(10))
+        .collect::<Option<Vec<_>>>();
+    digits.is_some_and(|digits| {
+        digits
+            .windows(2)
+            .all(|pair| pair[1] == pair[0] + 1 || (pair[0] == 9 && pair[1] == 0))
+    })
 }
 
 /// Opens the storage device at the given path.
 ///
 /// If `read_only` is true, the device is opened in read-only mode.
-pub fn open(device: &str, read_only: bool) -> Result<Box<dyn Device>> {
-    Ok(Box::new(linux::open(device, read_only)?) as Box<dyn Device>)
+/// If `cached` is true, the device is accessed through the OS cache, instead of bypassing it.
+/// If `sync` is true, writes complete only once the data is committed to the media.
+pub fn open(device: &str, read_only: bool, cached: bool, sync: bool) -> Result<Box<dyn Device>> {
+    Ok(Box::new(linux::open(device, read_only, cached, sync)?) as Box<dyn Device>)
+}
+
+/// Lists the block devices present on the system, with the information available without opening
+/// them. Returns the path and information of each device.
+pub fn list() -> Result<Vec<(String, DeviceInfo)>> {
+    linux::list_devices()
+}
+
+/// Prints the sysfs directory of `device` and the attributes read to find its information, to
+/// diagnose devices whose information is missing or wrong.
+pub fn dump_sysfs(device: &str) -> Result<()> {
+    linux::dump_sysfs(device)
 }
 
-/// If `value` is not empty, prints `label: value` to stdout.
-fn print_if_not_empty(label: &str, value: &str) {
-    if !value.is_empty() {
-        println!("{}: {}", label, value);
+/// Opens an in-memory device of `size` bytes, to benchmark the tool itself.
+pub fn open_memory(size: u64) -> Box<dyn Device> {
+    Box::new(memory::open(size))
+}
+
+/// Prints the `label`/`value` rows of each section to stdout, under the section name, with the
+/// values aligned in a column. Rows with an empty value and sections without rows are omitted.
+fn print_table(sections: &[(&str, Vec<(&str, String)>)]) {
+    let rows = || {
+        sections
+            .iter()
+            .flat_map(|(_, rows)| rows)
+            .filter(|(_, value)| !value.is_empty())
+    };
+    let width = rows().map(|(label, _)| label.len()).max().unwrap_or(0);
+    for (name, rows) in sections {
+        if rows.iter().all(|(_, value)| value.is_empty()) {
+            continue;
+        }
+        println!("{}", console::style(name).bold().underlined());
+        for (label, value) in rows.iter().filter(|(_, value)| !value.is_empty()) {
+            println!(
+                "  {}  {}",
+                console::style(format!("{:<width$}", label)).dim(),
+                value
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholder_serials_are_detected() {
+        for serial in [
+            "0x00000000",
+            "FFFFFFFF",
+            "123456789",
+            "7890123",
+            " Unknown ",
+            "N/A",
+        ] {
+            assert!(is_placeholder_serial(serial), "{serial:?}");
+        }
+    }
+
+    #[test]
+    fn real_serials_are_not_placeholders() {
+        for serial in [
+            "",
+            "0x0",
+            "1111",
+            "1234",
+            "89abcdef",
+            "0xabcdef01",
+            "4C530001231106115",
+        ] {
+            assert!(!is_placeholder_serial(serial), "{serial:?}");
+        }
     }
 }
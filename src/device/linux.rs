@@ -20,52 +20,159 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-///! Linux implementation for accessing a storage device.
-use anyhow::{Context, Result};
+//! Linux implementation for accessing a storage device.
+use anyhow::{anyhow, Context, Result};
 use std::{
     cmp::max,
     fs::{self, File, OpenOptions},
     io::{ErrorKind, Read, Seek, SeekFrom, Write},
-    os::unix::fs::{MetadataExt, OpenOptionsExt},
-    path, time,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+    },
+    path,
+    sync::mpsc,
+    thread, time,
 };
 
 use super::DeviceInfo;
+use crate::aligned;
+
+/// The BLKDISCARD ioctl request, _IO(0x12, 119), not exported by the libc crate.
+const BLKDISCARD: libc::c_ulong = 0x1277;
+/// The BLKGETSIZE64 ioctl request, _IOR(0x12, 114, size_t), not exported by the libc crate.
+const BLKGETSIZE64: libc::c_ulong = 0x80081272;
+/// The interval between the attempts to reopen a device that disconnected.
+const RECONNECT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+/// The file to write to drop the clean page cache of the whole system.
+const DROP_CACHES_PATH: &str = "/proc/sys/vm/drop_caches";
+/// The sysfs directory holding a subdirectory for each block device (disks, not partitions).
+const SYS_BLOCK_PATH: &str = "/sys/block";
+/// The number of levels of subdirectories of the sysfs directory of a disk listed by `dump_sysfs`.
+const DUMP_SYSFS_DEPTH: usize = 2;
+/// The maximum number of characters of each attribute value printed by `dump_sysfs`.
+const DUMP_SYSFS_MAX_VALUE_LEN: usize = 200;
 
 /// Struct implementing the Device trait for Linux.
 pub struct LinuxDevice {
     path: String,
-    drive: File,
+    /// The open device, or `None` after it disconnected and could not be reopened: all the
+    /// following I/O then fails as disconnected.
+    drive: Option<File>,
     size: u64,
     device_info: DeviceInfo,
     has_device_info: bool,
     memory_alignment: usize,
+    io_timeout: Option<time::Duration>,
+    /// The thread of the last write that timed out, if it may still be running. All I/O fails
+    /// until it finishes, so that its data cannot land after later writes.
+    timed_out_write: Option<thread::JoinHandle<()>>,
+    /// The maximum time to wait for the device to reappear after it disconnects.
+    reconnect_wait: Option<time::Duration>,
+    /// The number of times the device was reopened after it disconnected.
+    reconnections: usize,
+    /// The hwmon temperature input of the device in sysfs, if any.
+    temperature_input: Option<path::PathBuf>,
+    /// Whether the device was opened read-only, to reopen it the same way after it reconnects.
+    read_only: bool,
+    /// Whether the device was opened to go through the OS cache.
+    cached: bool,
+    /// Whether the device was opened with O_SYNC.
+    sync: bool,
+    /// Whether the device was opened with O_DIRECT.
+    direct_io: bool,
+    /// Whether the OS cache is explicitly flushed and dropped around each I/O operation, to
+    /// approximate direct I/O when the device was not opened with O_DIRECT.
+    drop_cache: bool,
 }
 
-pub fn open(device: &str, read_only: bool) -> Result<LinuxDevice> {
-    let mut options = OpenOptions::new();
-    options.read(true);
-    let mut flags = libc::O_DIRECT | libc::O_SYNC;
-    if !read_only {
-        options.write(true);
-        flags |= libc::O_EXCL;
-    }
-    options.custom_flags(flags);
-    let mut drive = options
-        .open(device)
-        .context(format!("opening {}", device))?;
-    let size = drive
-        .seek(SeekFrom::End(0))
-        .context(format!("seeking to end of device {}", device))?;
+pub fn open(device: &str, read_only: bool, cached: bool, sync: bool) -> Result<LinuxDevice> {
+    let sync_flag = if sync { libc::O_SYNC } else { 0 };
+    let open_with_flags = |flags: libc::c_int| {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let mut flags = flags;
+        if !read_only {
+            options.write(true);
+            flags |= libc::O_EXCL;
+        }
+        options.custom_flags(flags);
+        options.open(device)
+    };
+    let mut direct_io = !cached;
+    let mut drive = match open_with_flags(if cached {
+        sync_flag
+    } else {
+        libc::O_DIRECT | sync_flag
+    }) {
+        // Some filesystems do not support O_DIRECT and fail the open with EINVAL.
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            log::warn!(
+                "{} does not support direct I/O (O_DIRECT), falling back to buffered I/O",
+                device
+            );
+            direct_io = false;
+            open_with_flags(sync_flag)
+        }
+        result => result,
+    }
+    .map_err(|err| {
+        let message = match err.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) => format!(
+                "Permission denied opening {}: run with sudo or add yourself to the 'disk' group",
+                device
+            ),
+            Some(libc::ENOMEDIUM) => format!(
+                "{} has no medium: if it is a card reader, is a card inserted?",
+                device
+            ),
+            Some(libc::EBUSY) => format!(
+                "{} is busy: it is likely mounted, unmount its partitions before testing it",
+                device
+            ),
+            _ => format!("opening {}", device),
+        };
+        anyhow::Error::new(err).context(message)
+    })?;
+    let is_block_device = drive
+        .metadata()
+        .context(format!("reading metadata of {}", device))?
+        .file_type()
+        .is_block_device();
+    // The size of block devices is authoritatively reported by the kernel, while seeking to the end
+    // may be misleading for some device-mapper targets or zoned devices.
+    let size = if is_block_device {
+        let mut size: u64 = 0;
+        let ret = unsafe { libc::ioctl(drive.as_raw_fd(), BLKGETSIZE64, &mut size) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("reading the size of block device {}", device));
+        }
+        size
+    } else {
+        drive
+            .seek(SeekFrom::End(0))
+            .context(format!("seeking to end of device {}", device))?
+    };
     let mut device_info = DeviceInfo::new();
     device_info.size = size;
     Ok(LinuxDevice {
         path: String::from(device),
-        drive,
+        drive: Some(drive),
         size,
         device_info,
         has_device_info: false,
         memory_alignment: 0,
+        io_timeout: None,
+        timed_out_write: None,
+        reconnect_wait: None,
+        reconnections: 0,
+        temperature_input: None,
+        read_only,
+        cached,
+        sync,
+        direct_io,
+        drop_cache: !direct_io && !cached,
     })
 }
 
@@ -83,51 +190,307 @@ impl super::Device for LinuxDevice {
     }
 
     fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<time::Duration> {
-        self.drive.seek(SeekFrom::Start(offset)).context(format!(
-            "seeking to offset {offset} in drive {:?}",
-            self.drive
-        ))?;
+        match self.read_once(offset, data) {
+            Err(err) if self.reconnect_after(&err) => self.read_once(offset, data),
+            result => result,
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<time::Duration> {
+        match self.write_once(offset, data) {
+            Err(err) if self.reconnect_after(&err) => self.write_once(offset, data),
+            result => result,
+        }
+    }
+
+    fn get_memory_alignment(&self) -> usize {
+        self.memory_alignment
+    }
+
+    fn set_io_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.io_timeout = timeout;
+    }
+
+    fn set_reconnect_wait(&mut self, wait: Option<time::Duration>) {
+        self.reconnect_wait = wait;
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<bool> {
+        if !self.get_device_info()?.is_block_device {
+            return Ok(false);
+        }
+        let range: [u64; 2] = [offset, len];
+        let ret = unsafe { libc::ioctl(self.drive()?.as_raw_fd(), BLKDISCARD, &range) };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY)
+            ) {
+                return Ok(false);
+            }
+            return Err(err).context(format!(
+                "discarding {len} bytes at offset {offset} of {}",
+                self.path
+            ));
+        }
+        Ok(true)
+    }
+
+    fn flush_caches(&mut self) -> Result<()> {
+        flush_and_drop_cache(self.drive()?, 0, 0)?;
+        // Dropping all the clean page cache also covers the caches of other layers (e.g. a loop
+        // device backing file), but requires root privileges.
+        if let Err(err) = fs::write(DROP_CACHES_PATH, "1") {
+            log::debug!("Cannot write to {}: {}", DROP_CACHES_PATH, err);
+        }
+        Ok(())
+    }
+
+    fn reconnections(&self) -> usize {
+        self.reconnections
+    }
+
+    fn temperature(&self) -> Option<f64> {
+        // hwmon reports temperatures in millidegrees Celsius.
+        let input = self.temperature_input.as_ref()?;
+        let millidegrees = read_and_trim(input).parse::<f64>().ok()?;
+        Some(millidegrees / 1000.0)
+    }
+}
+
+impl LinuxDevice {
+    /// Read `data` at `offset`, without reconnecting if the device is gone.
+    fn read_once(&mut self, offset: u64, data: &mut [u8]) -> Result<time::Duration> {
+        self.check_timed_out_write()?;
+        let (data, beyond_end) = data.split_at_mut(self.available_len(offset, data.len()));
+        beyond_end.fill(0);
+        if let Some(timeout) = self.io_timeout {
+            return self.read_with_timeout(offset, data, timeout);
+        }
+        let mut drive = self.drive()?;
+        if self.drop_cache {
+            drop_cache(drive, offset, data.len())?;
+        }
+        drive
+            .seek(SeekFrom::Start(offset))
+            .context(format!("seeking to offset {offset} in drive {:?}", drive))?;
         let start = time::Instant::now();
-        self.drive.read_exact(data).context(format!(
-            "reading at offset {offset} from drive {:?}",
-            self.drive
-        ))?;
+        drive
+            .read_exact(data)
+            .context(format!("reading at offset {offset} from drive {:?}", drive))?;
         let duration = start.elapsed();
         Ok(duration)
     }
 
-    fn write(&mut self, offset: u64, data: &[u8]) -> Result<time::Duration> {
-        self.drive.seek(SeekFrom::Start(offset)).context(format!(
-            "seeking at offset {offset} in drive {:?}",
-            self.drive
-        ))?;
+    /// Write `data` at `offset`, without reconnecting if the device is gone.
+    fn write_once(&mut self, offset: u64, data: &[u8]) -> Result<time::Duration> {
+        self.check_timed_out_write()?;
+        let data = &data[..self.available_len(offset, data.len())];
+        if let Some(timeout) = self.io_timeout {
+            return self.write_with_timeout(offset, data, timeout);
+        }
+        let mut drive = self.drive()?;
+        drive
+            .seek(SeekFrom::Start(offset))
+            .context(format!("seeking at offset {offset} in drive {:?}", drive))?;
         let start = time::Instant::now();
-        self.drive.write_all(data).context(format!(
-            "writing at offset {offset} on drive {:?}",
-            self.drive
-        ))?;
+        drive
+            .write_all(data)
+            .context(format!("writing at offset {offset} on drive {:?}", drive))?;
+        if self.drop_cache {
+            flush_and_drop_cache(drive, offset, data.len())?;
+        }
         let duration = start.elapsed();
         Ok(duration)
     }
 
-    fn get_memory_alignment(&self) -> usize {
-        self.memory_alignment
+    /// Return the open device, or a disconnection error (ENODEV) if it could not be reopened after
+    /// it disconnected.
+    fn drive(&self) -> Result<&File> {
+        self.drive
+            .as_ref()
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENODEV))
+            .context(format!("{} disconnected and was not reopened", self.path))
+    }
+
+    /// Fail if the thread of a write that timed out is still running: the device is unusable until
+    /// the write completes or fails, as its data could otherwise overwrite the data of later
+    /// writes, e.g. the restored original content.
+    fn check_timed_out_write(&mut self) -> Result<()> {
+        if self
+            .timed_out_write
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "a write to {} that timed out is still in progress",
+                    self.path
+                ),
+            )
+            .into());
+        }
+        self.timed_out_write = None;
+        Ok(())
+    }
+
+    /// Wait for the device to reappear after `err`, returned by a read/write, if it reports the
+    /// device disconnected and reconnecting is enabled. Reopen the device if it is the same as
+    /// before, identified by its fingerprint.
+    /// Returns whether the device was reopened, so that the operation can be retried. Otherwise the
+    /// device stays closed, and is not waited for again.
+    fn reconnect_after(&mut self, err: &anyhow::Error) -> bool {
+        let Some(wait) = self.reconnect_wait else {
+            return false;
+        };
+        if super::ErrorKind::of(err) != super::ErrorKind::Disconnected
+            || !self.has_device_info
+            || self.drive.is_none()
+        {
+            return false;
+        }
+        let fingerprint = self.device_info.fingerprint();
+        // Close the stale file descriptor: the kernel only reuses the name of a gone device once
+        // all its openers have closed it, and the exclusive open of the old device prevents
+        // reopening it.
+        self.drive = None;
+        log::warn!(
+            "{} disconnected, waiting up to {} s for it to reappear",
+            self.path,
+            wait.as_secs()
+        );
+        let deadline = time::Instant::now() + wait;
+        while time::Instant::now() < deadline {
+            thread::sleep(RECONNECT_POLL_INTERVAL);
+            let Ok(mut device) = open(&self.path, self.read_only, self.cached, self.sync) else {
+                continue;
+            };
+            match super::Device::get_device_info(&mut device) {
+                Ok(device_info) if device_info.fingerprint() == fingerprint => {}
+                Ok(_) => {
+                    log::error!("{} reappeared as a different device", self.path);
+                    return false;
+                }
+                // The device may not be fully set up yet.
+                Err(_) => continue,
+            }
+            log::warn!("{} reappeared, resuming the test", self.path);
+            *self = LinuxDevice {
+                io_timeout: self.io_timeout,
+                timed_out_write: self.timed_out_write.take(),
+                reconnect_wait: self.reconnect_wait,
+                reconnections: self.reconnections + 1,
+                ..device
+            };
+            return true;
+        }
+        log::error!("{} did not reappear within {} s", self.path, wait.as_secs());
+        false
+    }
+
+    /// Return the number of the `len` bytes at `offset` that are within the device. The last block
+    /// tested may be partially beyond the end of the device, if its size is not a multiple of the
+    /// block size. Accesses starting beyond the end are left to fail.
+    fn available_len(&self, offset: u64, len: usize) -> usize {
+        if offset >= self.size {
+            return len;
+        }
+        (self.size - offset).min(len as u64) as usize
+    }
+
+    /// Same as `read`, but the read is performed in a separate thread and fails if it does not
+    /// complete within `timeout`.
+    fn read_with_timeout(
+        &mut self,
+        offset: u64,
+        data: &mut [u8],
+        timeout: time::Duration,
+    ) -> Result<time::Duration> {
+        let drive = self
+            .drive()?
+            .try_clone()
+            .context(format!("duplicating file descriptor of {}", self.path))?;
+        let len = data.len();
+        let memory_alignment = self.memory_alignment;
+        let drop_os_cache = self.drop_cache;
+        let (buffer, duration) = run_with_timeout(timeout, &mut None, move || -> Result<_> {
+            let mut buffer = aligned::AlignedBuffer::new(len, memory_alignment);
+            if drop_os_cache {
+                drop_cache(&drive, offset, len)?;
+            }
+            let start = time::Instant::now();
+            drive
+                .read_exact_at(&mut buffer[..], offset)
+                .context(format!("reading at offset {offset} from drive {:?}", drive))?;
+            Ok((buffer, start.elapsed()))
+        })??;
+        data.copy_from_slice(&buffer[..]);
+        Ok(duration)
+    }
+
+    /// Same as `write`, but the write is performed in a separate thread and fails if it does not
+    /// complete within `timeout`.
+    fn write_with_timeout(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        timeout: time::Duration,
+    ) -> Result<time::Duration> {
+        let drive = self
+            .drive()?
+            .try_clone()
+            .context(format!("duplicating file descriptor of {}", self.path))?;
+        let mut buffer = aligned::AlignedBuffer::new(data.len(), self.memory_alignment);
+        buffer.copy_from_slice(data);
+        let drop_os_cache = self.drop_cache;
+        run_with_timeout(timeout, &mut self.timed_out_write, move || -> Result<_> {
+            let start = time::Instant::now();
+            drive
+                .write_all_at(&buffer[..], offset)
+                .context(format!("writing at offset {offset} on drive {:?}", drive))?;
+            if drop_os_cache {
+                flush_and_drop_cache(&drive, offset, buffer.len())?;
+            }
+            Ok(start.elapsed())
+        })?
     }
-}
 
-impl LinuxDevice {
     /// Populate the device information struct reading data from block device
     /// ioctls and sysfs.
     fn fill_device_info(&mut self) -> Result<()> {
+        // Check the flags actually in effect on the file descriptor, rather than the requested
+        // ones.
+        let flags = unsafe { libc::fcntl(self.drive()?.as_raw_fd(), libc::F_GETFL) };
+        if flags == -1 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("reading file status flags of {}", self.path));
+        }
+        self.device_info.direct_io = flags & libc::O_DIRECT != 0;
+        self.device_info.sync_io = flags & libc::O_SYNC == libc::O_SYNC;
         let block_dev = match io_block::os::BlockDev::from_file(
-            self.drive
+            self.drive()?
                 .try_clone()
                 .context(format!("reading block device properties of {}", self.path))?,
         ) {
             Ok(block_dev) => block_dev,
             Err(err) => {
                 if err.kind() == ErrorKind::InvalidInput {
-                    println!("Warning: {} is not a block device", self.path);
+                    log::warn!("{} is not a block device", self.path);
+                    let metadata = self
+                        .drive()?
+                        .metadata()
+                        .context(format!("reading metadata of {}", self.path))?;
+                    if self.direct_io {
+                        // Regular files opened with O_DIRECT need buffers aligned to the
+                        // filesystem block size.
+                        self.memory_alignment = metadata.blksize() as usize;
+                        self.device_info.memory_alignment = self.memory_alignment;
+                    }
+                    // st_blocks counts 512-byte units, regardless of the filesystem block size.
+                    self.device_info.allocated_size = Some(metadata.blocks() * 512);
+                    self.device_info.filesystem_free = self.filesystem_free();
                     return Ok(());
                 } else {
                     return Err(err)
@@ -143,111 +506,366 @@ impl LinuxDevice {
             .context(format!("reading physical block size of {}", self.path))?;
         // When opening a block device with O_DIRECT, I/O operations needs to be aligned to the
         // block size. Taking the maximum of the logical and physical block size should be safe.
-        self.memory_alignment = max(
-            self.device_info.logical_block_size,
-            self.device_info.physical_block_size,
-        ) as usize;
-        // Despite the name, block_count returns the size in bytes: https://github.com/jmesmon/io-block/issues/4
-        let size = io_block::BlockSize::block_count(&block_dev)
-            .context(format!("reading device size of {}", self.path))?;
-        if size != self.device_info.size {
-            // Safeguard check. It should never happen.
-            return Err(anyhow::anyhow!(
-                "Block device size ({} bytes) does not match the size reported by seeking to the end of the device ({} bytes)",
-                size,
-                self.device_info.size
-            ));
+        if self.direct_io {
+            self.memory_alignment = max(
+                self.device_info.logical_block_size,
+                self.device_info.physical_block_size,
+            ) as usize;
+        }
+        self.device_info.memory_alignment = self.memory_alignment;
+        // The information from sysfs is not needed by the test, and sysfs may be missing or
+        // incomplete, e.g. in containers: keep what the ioctls returned.
+        if let Err(err) = self.fill_sysfs_device_info() {
+            log::warn!(
+                "Cannot read all the information about {} from sysfs: {:#}",
+                self.path,
+                err
+            );
         }
+        Ok(())
+    }
+
+    /// Populate the device information reading data from the sysfs directory of the device.
+    fn fill_sysfs_device_info(&mut self) -> Result<()> {
         let devno = parse_devno(
-            self.drive
+            self.drive()?
                 .metadata()
                 .context(format!("reading device metadata of {}", self.path))?
                 .rdev(),
         );
-        let sys_path = get_sys_path_for_devno(&devno);
-        self.device_info.vendor = read_and_trim(sys_path.join("device/vendor").as_path());
-        self.device_info.model = read_and_trim(sys_path.join("device/model").as_path());
-        self.device_info.serial = read_and_trim(sys_path.join("device/serial").as_path());
-        self.device_info.revision = read_and_trim(sys_path.join("device/rev").as_path());
-        self.device_info.firmware_revision =
-            read_and_trim(sys_path.join("device/firmware_rev").as_path());
-        self.device_info.subsystems = get_subsystems_for_sys_path(&sys_path)
-            .context(format!("getting subsystems for sys path {:?}", sys_path))?;
-        if self.device_info.subsystems.contains(&String::from("usb")) {
-            self.fill_usb_device_info(&sys_path)?;
+        let mut sys_path = get_sys_path_for_devno(&devno);
+        log::debug!("Reading device information from {:?}", sys_path);
+        if !sys_path.exists() {
+            return Err(anyhow!("{:?} does not exist", sys_path));
         }
-        Ok(())
+        if sys_path.join("partition").exists() {
+            // The device information of a partition is found in the sysfs directory of its disk.
+            sys_path = self.fill_partition_info(&sys_path)?;
+        }
+        self.device_info.holds_root_filesystem = holds_root_filesystem(&sys_path);
+        self.temperature_input = find_temperature_input(&sys_path);
+        fill_sysfs_disk_info(&mut self.device_info, &sys_path)
+    }
+
+    /// Return the free space (in bytes) available to unprivileged users on the filesystem holding
+    /// the device file, or `None` if it cannot be determined.
+    fn filesystem_free(&self) -> Option<u64> {
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        let ret = unsafe { libc::fstatvfs(self.drive.as_ref()?.as_raw_fd(), stat.as_mut_ptr()) };
+        if ret != 0 {
+            log::debug!(
+                "Cannot read filesystem statistics of {}: {}",
+                self.path,
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        let stat = unsafe { stat.assume_init() };
+        Some(stat.f_bavail * stat.f_frsize)
     }
 
-    /// Populate the USB device information struct reading data from sysfs.
-    fn fill_usb_device_info(&mut self, sys_path: &path::Path) -> Result<()> {
-        // We traverse the sysfs tree upwards until we find a directory named "driver" in the "usb"
-        // subsystem. The parent directory of "driver" contains the USB device information.
-        // We stop traversing the tree if we find a directory named "sys", which is the root of the
-        // sysfs tree.
-        let sys_path_link =
-            fs::canonicalize(&sys_path).context(format!("canonicalizing {:?}", sys_path))?;
-        let mut path_iter = sys_path_link.as_path();
-        while path_iter
+    /// Populate the partition information reading data from the sysfs directory `sys_path` of the
+    /// partition.
+    /// Returns the sysfs directory of the disk containing the partition.
+    fn fill_partition_info(&mut self, sys_path: &path::Path) -> Result<path::PathBuf> {
+        self.device_info.is_partition = true;
+        self.device_info.partition_number = read_and_trim(sys_path.join("partition").as_path());
+        // The start of the partition is reported in 512-byte sectors, regardless of the block
+        // size of the device.
+        let start = read_and_trim(sys_path.join("start").as_path());
+        self.device_info.partition_start = start.parse::<u64>().context(format!(
+            "parsing partition start {:?} of {:?}",
+            start, sys_path
+        ))? * 512;
+        // The sysfs directory of a partition is a subdirectory of the one of its disk.
+        let disk_sys_path = fs::canonicalize(sys_path)
+            .context(format!("canonicalizing {:?}", sys_path))?
+            .parent()
+            .context(format!("getting parent of {:?}", sys_path))?
+            .to_path_buf();
+        let disk_name = disk_sys_path
             .file_name()
-            .context(format!("getting base name from {:?}", path_iter))?
-            != "sys"
-        {
-            let subsystem_path = path_iter.join("subsystem");
-            if subsystem_path.exists() {
-                let subsystem_link = subsystem_path
-                    .read_link()
-                    .context(format!("reading symlink {:?}", subsystem_path))?;
-                if subsystem_link
-                    .file_name()
-                    .context(format!("getting base name from {:?}", subsystem_link))?
-                    == "usb"
-                {
-                    let driver_path = path_iter.join("driver");
-                    if driver_path.exists() {
-                        let driver_link = driver_path
-                            .read_link()
-                            .context(format!("reading symlink {:?}", driver_path))?;
-                        let driver = driver_link
-                            .file_name()
-                            .context(format!("getting base name from {:?}", driver_link))?;
-                        // The USB driver is either "uas" (newer) or "usb-storage" (older).
-                        if driver == "uas" || driver == "usb-storage" {
-                            self.device_info.usb_driver = driver.to_string_lossy().to_string();
-                            let parent = path_iter
-                                .parent()
-                                .context(format!("getting parent of {:?}", path_iter))?;
-                            if parent.join("idVendor").exists() {
-                                self.device_info.usb_vendor_id =
-                                    read_and_trim(parent.join("idVendor").as_path());
-                                self.device_info.usb_product_id =
-                                    read_and_trim(parent.join("idProduct").as_path());
-                                // Manufacturer and product reported by the USB subsystem often
-                                // match those from the block device, but not always.
-                                self.device_info.usb_manufacturer =
-                                    read_and_trim(parent.join("manufacturer").as_path());
-                                self.device_info.usb_product =
-                                    read_and_trim(parent.join("product").as_path());
-                                self.device_info.usb_serial_number =
-                                    read_and_trim(parent.join("serial").as_path());
-                                self.device_info.usb_version =
-                                    read_and_trim(parent.join("version").as_path());
-                                self.device_info.usb_speed =
-                                    read_and_trim(parent.join("speed").as_path());
-                                break;
-                            }
+            .context(format!("getting base name from {:?}", disk_sys_path))?;
+        self.device_info.partition_disk = format!("/dev/{}", disk_name.to_string_lossy());
+        Ok(disk_sys_path)
+    }
+}
+
+/// List the block devices in sysfs, sorted by path, with the information found in sysfs. Devices
+/// without media (reporting a size of 0, like empty card readers or unused loop devices) are
+/// skipped, as they cannot be tested.
+pub fn list_devices() -> Result<Vec<(String, DeviceInfo)>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(SYS_BLOCK_PATH).context(format!("listing {}", SYS_BLOCK_PATH))? {
+        let sys_path = entry.context(format!("listing {}", SYS_BLOCK_PATH))?.path();
+        let Some(name) = sys_path.file_name() else {
+            continue;
+        };
+        let path = format!("/dev/{}", name.to_string_lossy());
+        let mut device_info = DeviceInfo::new();
+        // The size is reported in 512-byte sectors, regardless of the block size of the device.
+        device_info.size = read_and_trim(&sys_path.join("size"))
+            .parse::<u64>()
+            .unwrap_or(0)
+            * 512;
+        if device_info.size == 0 {
+            log::debug!("Skipping {}: no media", path);
+            continue;
+        }
+        device_info.is_block_device = true;
+        device_info.logical_block_size = read_and_trim(&sys_path.join("queue/logical_block_size"))
+            .parse()
+            .unwrap_or(0);
+        device_info.physical_block_size =
+            read_and_trim(&sys_path.join("queue/physical_block_size"))
+                .parse()
+                .unwrap_or(0);
+        if let Err(err) = fill_sysfs_disk_info(&mut device_info, &sys_path) {
+            log::warn!(
+                "Cannot read all the information about {} from sysfs: {:#}",
+                path,
+                err
+            );
+        }
+        devices.push((path, device_info));
+    }
+    devices.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(devices)
+}
+
+/// Print the sysfs directory of `device` and the attributes it holds, with those of its disk
+/// (recursively) and of each parent device, where the device information is looked for.
+pub fn dump_sysfs(device: &str) -> Result<()> {
+    let metadata = fs::metadata(device).context(format!("reading metadata of {}", device))?;
+    if !metadata.file_type().is_block_device() {
+        return Err(anyhow!(
+            "{} is not a block device: it has no sysfs directory",
+            device
+        ));
+    }
+    let devno = parse_devno(metadata.rdev());
+    let sys_path = get_sys_path_for_devno(&devno);
+    let canonical_path =
+        fs::canonicalize(&sys_path).context(format!("canonicalizing {:?}", sys_path))?;
+    println!("Device: {} ({}:{})", device, devno.major, devno.minor);
+    println!(
+        "sysfs path: {} -> {}",
+        sys_path.display(),
+        canonical_path.display()
+    );
+    let mut disk_path = canonical_path.clone();
+    if canonical_path.join("partition").exists() {
+        // The sysfs directory of a partition is a subdirectory of the one of its disk.
+        disk_path.pop();
+        println!("Disk sysfs path: {}", disk_path.display());
+        dump_sysfs_dir(&canonical_path, 0);
+    }
+    dump_sysfs_dir(&disk_path, DUMP_SYSFS_DEPTH);
+    // The USB and MMC information is found in the parent devices, up to the root of sysfs.
+    for dir in disk_path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.file_name().is_some_and(|name| name != "sys"))
+    {
+        dump_sysfs_dir(dir, 0);
+    }
+    Ok(())
+}
+
+/// Print the path of the sysfs directory `dir`, followed by its attributes and symlinks, and those
+/// of its subdirectories up to `depth` levels below.
+fn dump_sysfs_dir(dir: &path::Path, depth: usize) {
+    println!("\n{}/", dir.display());
+    dump_sysfs_entries(dir, dir, depth);
+}
+
+/// Print the attributes and symlinks in `dir`, named relative to `root`, and those of its
+/// subdirectories up to `depth` levels below.
+fn dump_sysfs_entries(root: &path::Path, dir: &path::Path, depth: usize) {
+    let mut paths = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            println!("  {}: <{}>", dir.display(), err);
+            return;
+        }
+    };
+    paths.sort();
+    let mut subdirs = Vec::new();
+    for path in paths {
+        let name = path.strip_prefix(root).unwrap_or(&path).display();
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.file_type().is_symlink() {
+            match fs::read_link(&path) {
+                Ok(target) => println!("  {} -> {}", name, target.display()),
+                Err(err) => println!("  {} -> <{}>", name, err),
+            }
+        } else if metadata.is_dir() {
+            subdirs.push(path);
+        } else {
+            println!("  {} = {}", name, dump_sysfs_attribute(&path));
+        }
+    }
+    if depth > 0 {
+        for subdir in subdirs {
+            dump_sysfs_entries(root, &subdir, depth - 1);
+        }
+    }
+}
+
+/// Return the value of the sysfs attribute `path` on a single line, shortened if too long, or a
+/// description of the attribute if it cannot be read (e.g. write-only) or is binary.
+fn dump_sysfs_attribute(path: &path::Path) -> String {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => return format!("<{}>", err),
+    };
+    match String::from_utf8(data) {
+        Ok(text) if !text.contains('\0') => {
+            let value = text.trim().replace('\n', "\\n");
+            if value.chars().count() > DUMP_SYSFS_MAX_VALUE_LEN {
+                let prefix: String = value.chars().take(DUMP_SYSFS_MAX_VALUE_LEN).collect();
+                format!("{}...", prefix)
+            } else {
+                value
+            }
+        }
+        Ok(text) => format!("<{} bytes of binary data>", text.len()),
+        Err(err) => format!("<{} bytes of binary data>", err.as_bytes().len()),
+    }
+}
+
+/// Populate `device_info` reading data from the sysfs directory `sys_path` of a disk.
+fn fill_sysfs_disk_info(device_info: &mut DeviceInfo, sys_path: &path::Path) -> Result<()> {
+    // Devices not reporting a preferred I/O size expose 0.
+    device_info.minimum_io_size = read_and_trim(&sys_path.join("queue/minimum_io_size"))
+        .parse()
+        .unwrap_or(0);
+    device_info.optimal_io_size = read_and_trim(&sys_path.join("queue/optimal_io_size"))
+        .parse()
+        .unwrap_or(0);
+    device_info.rotational = match read_and_trim(&sys_path.join("queue/rotational")).as_str() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    };
+    device_info.removable = match read_and_trim(&sys_path.join("removable")).as_str() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    };
+    device_info.queue_depth = read_and_trim(&sys_path.join("device/queue_depth"));
+    device_info.vendor = read_and_trim(sys_path.join("device/vendor").as_path());
+    device_info.model = read_and_trim(sys_path.join("device/model").as_path());
+    device_info.serial = read_and_trim(sys_path.join("device/serial").as_path());
+    device_info.revision = read_and_trim(sys_path.join("device/rev").as_path());
+    device_info.firmware_revision = read_and_trim(sys_path.join("device/firmware_rev").as_path());
+    device_info.subsystems = get_subsystems_for_sys_path(sys_path)
+        .context(format!("getting subsystems for sys path {:?}", sys_path))?;
+    if device_info.subsystems.contains(&String::from("usb")) {
+        fill_usb_device_info(device_info, sys_path)?;
+    }
+    if device_info.subsystems.contains(&String::from("mmc")) {
+        fill_mmc_device_info(device_info, sys_path);
+    }
+    Ok(())
+}
+
+/// Populate the MMC/SD device information reading data from sysfs.
+/// The fields of the card CID register are exposed by the kernel in the sysfs directory of the
+/// card, which is the "device" of the block device. Unlike the block device vendor and model,
+/// they are programmed by the card manufacturer and are harder to fake.
+fn fill_mmc_device_info(device_info: &mut DeviceInfo, sys_path: &path::Path) {
+    let card_path = sys_path.join("device");
+    log::debug!("Reading MMC device information from {:?}", card_path);
+    device_info.mmc_manfid = read_and_trim(card_path.join("manfid").as_path());
+    device_info.mmc_oemid = read_and_trim(card_path.join("oemid").as_path());
+    device_info.mmc_name = read_and_trim(card_path.join("name").as_path());
+    device_info.mmc_serial = read_and_trim(card_path.join("serial").as_path());
+    device_info.mmc_date = read_and_trim(card_path.join("date").as_path());
+}
+
+/// Populate the USB device information struct reading data from sysfs.
+fn fill_usb_device_info(device_info: &mut DeviceInfo, sys_path: &path::Path) -> Result<()> {
+    // We traverse the sysfs tree upwards until we find a directory named "driver" in the "usb"
+    // subsystem. The parent directory of "driver" contains the USB device information.
+    // We stop traversing the tree if we find a directory named "sys", which is the root of the
+    // sysfs tree.
+    log::debug!("Looking for USB device information from {:?}", sys_path);
+    let sys_path_link =
+        fs::canonicalize(sys_path).context(format!("canonicalizing {:?}", sys_path))?;
+    let mut path_iter = sys_path_link.as_path();
+    while path_iter
+        .file_name()
+        .context(format!("getting base name from {:?}", path_iter))?
+        != "sys"
+    {
+        let subsystem_path = path_iter.join("subsystem");
+        if subsystem_path.exists() {
+            let subsystem_link = subsystem_path
+                .read_link()
+                .context(format!("reading symlink {:?}", subsystem_path))?;
+            if subsystem_link
+                .file_name()
+                .context(format!("getting base name from {:?}", subsystem_link))?
+                == "usb"
+            {
+                let driver_path = path_iter.join("driver");
+                if driver_path.exists() {
+                    let driver_link = driver_path
+                        .read_link()
+                        .context(format!("reading symlink {:?}", driver_path))?;
+                    let driver = driver_link
+                        .file_name()
+                        .context(format!("getting base name from {:?}", driver_link))?;
+                    // The USB driver is either "uas" (newer) or "usb-storage" (older).
+                    if driver == "uas" || driver == "usb-storage" {
+                        device_info.usb_driver = driver.to_string_lossy().to_string();
+                        let parent = path_iter
+                            .parent()
+                            .context(format!("getting parent of {:?}", path_iter))?;
+                        if parent.join("idVendor").exists() {
+                            device_info.usb_vendor_id =
+                                read_and_trim(parent.join("idVendor").as_path());
+                            device_info.usb_product_id =
+                                read_and_trim(parent.join("idProduct").as_path());
+                            // Manufacturer and product reported by the USB subsystem often
+                            // match those from the block device, but not always.
+                            device_info.usb_manufacturer =
+                                read_and_trim(parent.join("manufacturer").as_path());
+                            device_info.usb_product =
+                                read_and_trim(parent.join("product").as_path());
+                            device_info.usb_serial_number =
+                                read_and_trim(parent.join("serial").as_path());
+                            device_info.usb_version =
+                                read_and_trim(parent.join("version").as_path());
+                            device_info.usb_speed = read_and_trim(parent.join("speed").as_path());
+                            // The USB device directory is named after its bus and port path.
+                            device_info.usb_port_path = parent
+                                .file_name()
+                                .map(|name| name.to_string_lossy().to_string())
+                                .unwrap_or_default();
+                            device_info.usb_superspeed_capable = is_usb_superspeed_capable(
+                                &device_info.usb_version,
+                                parent.join("bos_descriptors").as_path(),
+                            );
+                            break;
                         }
                     }
                 }
             }
-            let parent_path = path_iter.parent();
-            if parent_path.is_none() {
-                break;
-            }
-            path_iter = parent_path.unwrap();
         }
-        Ok(())
+        let parent_path = path_iter.parent();
+        if parent_path.is_none() {
+            break;
+        }
+        path_iter = parent_path.unwrap();
     }
+    Ok(())
 }
 
 struct DevNo {
@@ -273,6 +891,72 @@ fn get_sys_path_for_devno(devno: &DevNo) -> path::PathBuf {
     path
 }
 
+/// Return whether the disk with sysfs directory `disk_sys_path` holds the root filesystem, in one
+/// of its partitions or below a device-mapper/MD device (LVM, LUKS, RAID).
+fn holds_root_filesystem(disk_sys_path: &path::Path) -> bool {
+    let root_devno = match fs::metadata("/") {
+        Ok(metadata) => parse_devno(metadata.dev()),
+        Err(err) => {
+            log::debug!("Cannot read the device of the root filesystem: {}", err);
+            return false;
+        }
+    };
+    let Ok(disk_sys_path) = fs::canonicalize(disk_sys_path) else {
+        return false;
+    };
+    let mut backing_disks = Vec::new();
+    collect_backing_disks(&get_sys_path_for_devno(&root_devno), &mut backing_disks);
+    log::debug!("Root filesystem on disks {:?}", backing_disks);
+    backing_disks.contains(&disk_sys_path)
+}
+
+/// Add to `disks` the canonical sysfs directories of the disks holding the block device with
+/// sysfs directory `sys_path`, following the devices it is stacked on.
+fn collect_backing_disks(sys_path: &path::Path, disks: &mut Vec<path::PathBuf>) {
+    // Filesystems not backed by a block device (tmpfs, overlay, network filesystems) have no
+    // sysfs directory.
+    let Ok(mut sys_path) = fs::canonicalize(sys_path) else {
+        return;
+    };
+    if let Ok(slaves) = fs::read_dir(sys_path.join("slaves")) {
+        let mut has_slaves = false;
+        for slave in slaves.flatten() {
+            has_slaves = true;
+            collect_backing_disks(&slave.path(), disks);
+        }
+        if has_slaves {
+            return;
+        }
+    }
+    if sys_path.join("partition").exists() {
+        // The sysfs directory of a partition is a subdirectory of the one of its disk.
+        sys_path.pop();
+    }
+    if !disks.contains(&sys_path) {
+        disks.push(sys_path);
+    }
+}
+
+/// Return the temperature input of the hwmon sensor of the disk with sysfs directory `sys_path`, if
+/// any. The nvme driver registers it in the directory of the controller, the "device" of the block
+/// device, and the drivetemp driver for SATA drives in the "hwmon" subdirectory of the device.
+fn find_temperature_input(sys_path: &path::Path) -> Option<path::PathBuf> {
+    let device_path = sys_path.join("device");
+    for dir in [device_path.join("hwmon"), device_path] {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let input = entry.path().join("temp1_input");
+            if entry.file_name().to_string_lossy().starts_with("hwmon") && input.exists() {
+                log::debug!("Reading the temperature from {:?}", input);
+                return Some(input);
+            }
+        }
+    }
+    None
+}
+
 /// Read a file into a string and trim whitespace.
 /// Returns an empty string if the file does not exist.
 fn read_and_trim(path: &path::Path) -> String {
@@ -315,3 +999,92 @@ fn get_subsystems_for_sys_path(sys_path: &path::Path) -> Result<Vec<String>> {
     }
     Ok(subsystems)
 }
+
+/// Returns whether a USB device advertises SuperSpeed capability.
+/// `usb_version` is the version from the device descriptor (bcdUSB). As a device operating at high
+/// speed usually reports version 2.10 even if it supports SuperSpeed, the BOS descriptors are
+/// checked as well for a SuperSpeed or SuperSpeedPlus device capability. The `bos_descriptors`
+/// sysfs attribute is available only on recent kernels and is ignored if missing.
+fn is_usb_superspeed_capable(usb_version: &str, bos_descriptors_path: &path::Path) -> bool {
+    if usb_version
+        .parse::<f64>()
+        .is_ok_and(|version| version >= 3.0)
+    {
+        return true;
+    }
+    let bos = match fs::read(bos_descriptors_path) {
+        Ok(bos) => bos,
+        Err(_) => return false,
+    };
+    // The BOS descriptor header is 5 bytes long and is followed by the device capability
+    // descriptors, each starting with bLength, bDescriptorType and bDevCapabilityType.
+    const USB_DT_DEVICE_CAPABILITY: u8 = 0x10;
+    const USB_SS_CAP_TYPE: u8 = 0x03;
+    const USB_SSP_CAP_TYPE: u8 = 0x0a;
+    let mut pos = match bos.first() {
+        Some(&len) if len > 0 => len as usize,
+        _ => return false,
+    };
+    while pos + 2 < bos.len() {
+        let len = bos[pos] as usize;
+        if len == 0 {
+            break;
+        }
+        if bos[pos + 1] == USB_DT_DEVICE_CAPABILITY
+            && (bos[pos + 2] == USB_SS_CAP_TYPE || bos[pos + 2] == USB_SSP_CAP_TYPE)
+        {
+            return true;
+        }
+        pos += len;
+    }
+    false
+}
+
+/// Run `f` in a separate thread and wait at most `timeout` for its result.
+/// If the timeout expires an error is returned, and the thread is left running in the background,
+/// as an I/O operation stuck in the kernel cannot be cancelled. Its handle is then stored in
+/// `timed_out_thread`.
+fn run_with_timeout<T: Send + 'static>(
+    timeout: time::Duration,
+    timed_out_thread: &mut Option<thread::JoinHandle<()>>,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        // The receiver is gone if the timeout already expired.
+        let _ = sender.send(f());
+    });
+    receiver.recv_timeout(timeout).map_err(|_| {
+        *timed_out_thread = Some(thread);
+        anyhow::Error::new(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("I/O operation timed out after {} ms", timeout.as_millis()),
+        ))
+    })
+}
+
+/// Drop the OS cache for the `len` bytes at `offset` of `file`, so that the following read
+/// accesses the device. Used when direct I/O is not available.
+fn drop_cache(file: &File, offset: u64, len: usize) -> Result<()> {
+    let ret = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret))
+            .context(format!("dropping cache at offset {offset} of {:?}", file));
+    }
+    Ok(())
+}
+
+/// Flush the data written to `file` to the device and drop the OS cache for the `len` bytes at
+/// `offset`. Used when direct I/O is not available.
+fn flush_and_drop_cache(file: &File, offset: u64, len: usize) -> Result<()> {
+    file.sync_data()
+        .context(format!("flushing data written to {:?}", file))?;
+    drop_cache(file, offset, len)
+}
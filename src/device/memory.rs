@@ -0,0 +1,117 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! In-memory implementation of a storage device, used to benchmark the tool itself.
+use anyhow::{anyhow, Result};
+use std::time;
+
+use super::DeviceInfo;
+
+/// Struct implementing the Device trait backed by a memory buffer.
+pub struct MemoryDevice {
+    data: Vec<u8>,
+    device_info: DeviceInfo,
+}
+
+pub fn open(size: u64) -> MemoryDevice {
+    let mut device_info = DeviceInfo::new();
+    device_info.model = String::from("RAM benchmark device");
+    device_info.size = size;
+    MemoryDevice {
+        data: vec![0; size as usize],
+        device_info,
+    }
+}
+
+impl MemoryDevice {
+    /// Return the range of `data` corresponding to `len` bytes at `offset`, truncated to the end of
+    /// the device.
+    fn range(&self, offset: u64, len: usize) -> Result<std::ops::Range<usize>> {
+        let start = offset as usize;
+        if start >= self.data.len() {
+            return Err(anyhow!(
+                "accessing {} bytes at offset {} beyond the end of the device",
+                len,
+                offset
+            ));
+        }
+        Ok(start..self.data.len().min(start + len))
+    }
+}
+
+impl super::Device for MemoryDevice {
+    fn get_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn get_device_info(&mut self) -> Result<&DeviceInfo> {
+        Ok(&self.device_info)
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<time::Duration> {
+        let start = time::Instant::now();
+        let range = self.range(offset, data.len())?;
+        let (data, beyond_end) = data.split_at_mut(range.len());
+        data.copy_from_slice(&self.data[range]);
+        beyond_end.fill(0);
+        Ok(start.elapsed())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<time::Duration> {
+        let start = time::Instant::now();
+        let range = self.range(offset, data.len())?;
+        self.data[range.clone()].copy_from_slice(&data[..range.len()]);
+        Ok(start.elapsed())
+    }
+
+    fn get_memory_alignment(&self) -> usize {
+        0
+    }
+
+    fn set_io_timeout(&mut self, _timeout: Option<time::Duration>) {
+        // Memory accesses never hang.
+    }
+
+    fn set_reconnect_wait(&mut self, _wait: Option<time::Duration>) {
+        // Memory never disconnects.
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<bool> {
+        let range = self.range(offset, len as usize)?;
+        self.data[range].fill(0);
+        Ok(true)
+    }
+
+    fn flush_caches(&mut self) -> Result<()> {
+        // Memory is not cached.
+        Ok(())
+    }
+
+    fn reconnections(&self) -> usize {
+        0
+    }
+
+    fn temperature(&self) -> Option<f64> {
+        // Memory has no temperature sensor.
+        None
+    }
+}
@@ -0,0 +1,122 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Parsing of the output of f3probe and f3read, from the f3 suite, to cross-check their verdict on
+//! the capacity of a drive with the one of valixdrive.
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+use crate::compressed;
+
+/// The size of the sectors counted by f3read, in bytes.
+const F3READ_SECTOR_SIZE: u64 = 512;
+
+/// The f3 tool whose output was parsed, which determines how the usable size was measured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tool {
+    /// f3probe, which reports the size usable from the beginning of the drive.
+    Probe,
+    /// f3read, which reports the total size of the files read back intact.
+    Read,
+}
+
+impl Tool {
+    /// Return the name of the tool.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::Probe => "f3probe",
+            Tool::Read => "f3read",
+        }
+    }
+}
+
+/// The capacity verdict of f3 on a drive.
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    /// The tool that produced the output.
+    pub tool: Tool,
+    /// The usable size of the drive, in bytes.
+    pub usable_size: u64,
+    /// The verdict line of f3probe (e.g. "Good news: The device `/dev/sdb' is the real thing"), if
+    /// any.
+    pub verdict: Option<String>,
+}
+
+/// Read the output of f3probe or f3read saved to the file at `path`.
+pub fn read(path: &Path) -> Result<Report> {
+    let content =
+        compressed::read_to_string(path).context(format!("reading f3 output from {:?}", path))?;
+    parse(&content).context(format!("parsing f3 output from {:?}", path))
+}
+
+/// Parse the output of f3probe or f3read.
+pub fn parse(output: &str) -> Result<Report> {
+    if let Some(blocks) = find_count(output, "*Usable* size:") {
+        // f3probe counts blocks of the physical block size of the drive, reported as a power of 2.
+        let block_order = output
+            .lines()
+            .find(|line| line.trim_start().starts_with("Physical block size:"))
+            .and_then(|line| line.split("(2^").nth(1))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|order| order.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("f3probe output without the physical block size"))?;
+        let usable_size = 1u64
+            .checked_shl(block_order)
+            .and_then(|block_size| blocks.checked_mul(block_size))
+            .ok_or_else(|| anyhow!("f3probe usable size too large"))?;
+        let verdict = output
+            .lines()
+            .map(str::trim)
+            .find(|line| line.starts_with("Good news:") || line.starts_with("Bad news:"))
+            .map(str::to_string);
+        return Ok(Report {
+            tool: Tool::Probe,
+            usable_size,
+            verdict,
+        });
+    }
+    if let Some(sectors) = find_count(output, "Data OK:") {
+        return Ok(Report {
+            tool: Tool::Read,
+            usable_size: sectors * F3READ_SECTOR_SIZE,
+            verdict: None,
+        });
+    }
+    Err(anyhow!(
+        "neither the usable size of f3probe nor the intact data of f3read found"
+    ))
+}
+
+/// Return the count in parentheses on the line starting with `label`, e.g. 16477879 in
+/// "*Usable* size: 7.86 GB (16477879 blocks)".
+fn find_count(output: &str, label: &str) -> Option<u64> {
+    let line = output
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with(label))?;
+    line.split('(')
+        .nth(1)?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,82 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Handling of interruptions, by the user with Ctrl-C (SIGINT) or by a service manager or job
+//! scheduler with SIGTERM, so that the test can be stopped cleanly and the original blocks content
+//! restored before exiting.
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The signal that requested an interruption, or 0 if none did.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// The signals handled as an interruption.
+const SIGNALS: [(libc::c_int, &str); 2] = [(libc::SIGINT, "SIGINT"), (libc::SIGTERM, "SIGTERM")];
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    // Only async-signal-safe functions can be called here.
+    if SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        unsafe { libc::_exit(exit_status(signal)) };
+    }
+    let message: &[u8] = if signal == libc::SIGTERM {
+        b"\nTerminated, stopping the test (terminate again to exit immediately)\n"
+    } else {
+        b"\nInterrupted, stopping the test (interrupt again to exit immediately)\n"
+    };
+    unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+}
+
+/// Return the exit status of a process stopped by `signal`, following the shell convention.
+fn exit_status(signal: libc::c_int) -> i32 {
+    128 + signal
+}
+
+/// Install the handler for SIGINT and SIGTERM.
+/// On the first interruption, `is_interrupted` starts returning true. On the second one, the
+/// process exits immediately.
+pub fn install_handler() -> Result<()> {
+    let handler = handle_signal as extern "C" fn(libc::c_int);
+    for (signal, name) in SIGNALS {
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(anyhow!(
+                "installing {} handler: {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether an interruption has been requested.
+pub fn is_interrupted() -> bool {
+    SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+/// Returns the exit status to use after an interruption (128 plus the signal number, e.g. 130 for
+/// SIGINT and 143 for SIGTERM), if one has been requested.
+pub fn exit_status_if_interrupted() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(exit_status(signal)),
+    }
+}
@@ -0,0 +1,127 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Test reports in the JUnit XML format, consumed by CI systems such as Jenkins or GitLab.
+//! Each tested drive is a test suite, with a test case for the validation of the drive and one for
+//! each test phase, whose duration is the wall-clock duration of the phase.
+use anyhow::{Context, Result};
+use std::{path::Path, time::Duration};
+
+use crate::compressed;
+
+/// The wall-clock duration of a test phase.
+pub struct PhaseReport {
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// The result of the test of a drive.
+pub struct DriveReport {
+    pub drive_path: String,
+    /// The reason the drive failed validation, if it did.
+    pub failure: Option<String>,
+    /// The error that stopped the test of the drive, if any.
+    pub error: Option<String>,
+    pub phases: Vec<PhaseReport>,
+}
+
+impl DriveReport {
+    /// Return the number of test cases of the drive.
+    fn num_tests(&self) -> usize {
+        1 + self.phases.len()
+    }
+
+    /// Return the total duration of the test phases of the drive.
+    fn duration(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.duration).sum()
+    }
+}
+
+/// Return `text` with the characters that are special in XML attributes and text escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write the JUnit XML report of the tests of the drives in `reports` to the file at `path`.
+pub fn write_junit(path: &Path, reports: &[DriveReport]) -> Result<()> {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"valixdrive\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+        reports.iter().map(DriveReport::num_tests).sum::<usize>(),
+        reports.iter().filter(|report| report.failure.is_some()).count(),
+        reports.iter().filter(|report| report.error.is_some()).count(),
+        reports
+            .iter()
+            .map(DriveReport::duration)
+            .sum::<Duration>()
+            .as_secs_f64()
+    ));
+    for report in reports {
+        let drive_path = escape(&report.drive_path);
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+            drive_path,
+            report.num_tests(),
+            report.failure.is_some() as usize,
+            report.error.is_some() as usize,
+            report.duration().as_secs_f64()
+        ));
+        xml.push_str(&format!(
+            "    <testcase classname=\"{}\" name=\"validation\" time=\"{:.3}\"",
+            drive_path,
+            report.duration().as_secs_f64()
+        ));
+        match (&report.failure, &report.error) {
+            (_, Some(error)) => xml.push_str(&format!(
+                ">\n      <error message=\"{}\"/>\n    </testcase>\n",
+                escape(error)
+            )),
+            (Some(failure), None) => xml.push_str(&format!(
+                ">\n      <failure message=\"{}\"/>\n    </testcase>\n",
+                escape(failure)
+            )),
+            (None, None) => xml.push_str("/>\n"),
+        }
+        for phase in &report.phases {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"/>\n",
+                drive_path,
+                escape(&phase.name),
+                phase.duration.as_secs_f64()
+            ));
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    compressed::write(path, xml).context(format!("writing JUnit report to {:?}", path))
+}
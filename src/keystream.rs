@@ -0,0 +1,142 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Content written to the tested blocks.
+//! By default, the content of each block is a ChaCha keystream keyed by a master seed, using the
+//! block number as stream. Alternatively, the content of a data file can be tiled over the whole
+//! drive. Either way, the expected content of any block can be regenerated when verifying it,
+//! without keeping the written data in memory.
+//! Optionally, each block starts with a header identifying the test run and the block it was
+//! written for, so that data found at the wrong offset proves aliasing.
+use anyhow::{anyhow, Context, Result};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::{fs, path::Path, rc::Rc};
+
+/// The magic number starting the header of the blocks written with headers.
+const HEADER_MAGIC: [u8; 8] = *b"VALIXDRV";
+/// The length of the header: the magic number, the run ID and the block number.
+const HEADER_LEN: usize = 24;
+
+/// Generator of the content of the tested blocks.
+pub struct Keystream {
+    source: Source,
+    /// The ID of the test run written in the header of each block, if headers are written.
+    run_id: Option<u64>,
+}
+
+/// The header found at the beginning of a block.
+pub struct BlockHeader {
+    /// The ID of the test run that wrote the block.
+    pub run_id: u64,
+    /// The number of the block the data was written for.
+    pub block_num: u64,
+}
+
+/// The source of the content of the tested blocks.
+enum Source {
+    /// A ChaCha keystream keyed by this master seed.
+    ChaCha([u8; 32]),
+    /// Data repeated over the whole drive, starting at offset 0.
+    Data(Rc<[u8]>),
+}
+
+impl Keystream {
+    /// Create a new keystream with a master seed taken from `rng`.
+    pub fn new(rng: &mut impl RngCore) -> Self {
+        let mut seed = [0; 32];
+        rng.fill_bytes(&mut seed);
+        Self {
+            source: Source::ChaCha(seed),
+            run_id: None,
+        }
+    }
+
+    /// Create a new keystream repeating `data` over the whole drive, so that the byte at offset
+    /// `n` of the drive is `data[n % data.len()]`. `data` must not be empty.
+    pub fn from_data(data: Rc<[u8]>) -> Self {
+        Self {
+            source: Source::Data(data),
+            run_id: None,
+        }
+    }
+
+    /// Start each block with a header containing a magic number, `run_id` and the block number,
+    /// followed by the content.
+    pub fn with_header(self, run_id: u64) -> Self {
+        Self {
+            run_id: Some(run_id),
+            ..self
+        }
+    }
+
+    /// Fill `buf` with the content of the block with number `block_num` on the drive. The size of
+    /// `buf` must be the block size.
+    pub fn fill(&self, block_num: u64, buf: &mut [u8]) {
+        match &self.source {
+            Source::ChaCha(seed) => {
+                let mut rng = ChaCha8Rng::from_seed(*seed);
+                rng.set_stream(block_num);
+                rng.fill_bytes(buf);
+            }
+            Source::Data(data) => {
+                // The data is tiled by offset on the drive, so that the content of a block does
+                // not depend on the order the blocks are written.
+                let mut start = ((block_num * buf.len() as u64) % data.len() as u64) as usize;
+                let mut filled = 0;
+                while filled < buf.len() {
+                    let len = (buf.len() - filled).min(data.len() - start);
+                    buf[filled..filled + len].copy_from_slice(&data[start..start + len]);
+                    filled += len;
+                    start = 0;
+                }
+            }
+        }
+        if let Some(run_id) = self.run_id {
+            if buf.len() >= HEADER_LEN {
+                buf[..8].copy_from_slice(&HEADER_MAGIC);
+                buf[8..16].copy_from_slice(&run_id.to_le_bytes());
+                buf[16..24].copy_from_slice(&block_num.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Parse the header at the beginning of `buf`, if it starts with one.
+pub fn parse_header(buf: &[u8]) -> Option<BlockHeader> {
+    if buf.len() < HEADER_LEN || buf[..8] != HEADER_MAGIC {
+        return None;
+    }
+    Some(BlockHeader {
+        run_id: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        block_num: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+    })
+}
+
+/// Read the data file at `path`, whose content is written to the tested blocks.
+pub fn read_data_file(path: &Path) -> Result<Rc<[u8]>> {
+    let data = fs::read(path).context(format!("reading data file {:?}", path))?;
+    if data.is_empty() {
+        return Err(anyhow!("data file {:?} is empty", path));
+    }
+    Ok(data.into())
+}
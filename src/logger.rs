@@ -0,0 +1,96 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Minimal logger for diagnostic messages, printed to stderr.
+//! User-facing results (device information, validation map, statistics) are printed to stdout
+//! instead, so that the two streams can be separated.
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+
+/// Function receiving the log messages instead of stderr, while a full-screen UI is shown.
+type Capture = Box<dyn Fn(Level, String) + Send>;
+
+/// The function currently capturing the log messages, if any.
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+/// Logger printing messages to stderr, colored by level.
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        match CAPTURE.lock().unwrap().as_ref() {
+            Some(capture) => capture(record.level(), message),
+            None => print(record.level(), &message),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Print `message` of `level` to stderr.
+pub fn print(level: Level, message: &str) {
+    let message = format!("[{}] {}", level, message);
+    let style = match level {
+        Level::Error => console::style(message).red(),
+        Level::Warn => console::style(message).yellow(),
+        Level::Info => console::style(message).cyan(),
+        Level::Debug | Level::Trace => console::style(message).dim(),
+    };
+    eprintln!("{}", style);
+}
+
+/// Send the log messages to `capture` instead of stderr, or back to stderr if `None`.
+pub fn capture(capture: Option<Capture>) {
+    *CAPTURE.lock().unwrap() = capture;
+}
+
+/// Install the logger. The level is taken from the `RUST_LOG` environment variable if set (e.g.
+/// `RUST_LOG=debug`), otherwise it is derived from the number of `--verbose` flags: warnings and
+/// errors are always shown, and each flag enables one more level.
+pub fn init(verbose: u8) -> Result<()> {
+    let level = match std::env::var("RUST_LOG") {
+        Ok(value) => value
+            .parse::<LevelFilter>()
+            .map_err(|_| anyhow!("invalid RUST_LOG level {:?}", value))?,
+        Err(_) => match verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        },
+    };
+    log::set_boxed_logger(Box::new(Logger { level }))
+        .map_err(|err| anyhow!("installing logger: {}", err))?;
+    log::set_max_level(level);
+    Ok(())
+}
@@ -20,37 +20,730 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use anyhow::{anyhow, Result};
-use clap::Parser;
-use rand::{self, rngs, seq::SliceRandom, RngCore, SeedableRng};
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rand::{self, seq::SliceRandom, Rng, SeedableRng};
 use std::{
-    ops::{DerefMut, Range},
-    time::Duration,
+    collections::HashMap,
+    io::Write,
+    ops::{Deref, DerefMut, Range},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
+mod aligned;
+mod checksum;
+mod compressed;
 mod device;
+mod f3;
+mod interrupt;
+mod junit;
+mod keystream;
+mod logger;
+mod map_export;
+mod mismatch;
+mod progress;
+mod results;
+mod silence;
+mod size;
+mod text_progress;
+mod thermal;
+mod tui;
 
+/// The block size used when none is given on the command line and the drive does not report its
+/// optimal I/O size.
+const DEFAULT_BLOCK_SIZE: u64 = 4096;
+/// The maximum block size chosen from the optimal I/O size of the drive, to cap the memory holding
+/// the original content of the tested blocks.
+const MAX_AUTO_BLOCK_SIZE: u64 = 256 * 1024;
+/// The block size below which testing a rotational drive is dominated by seeks.
+const ROTATIONAL_MIN_BLOCK_SIZE: u64 = 64 * 1024;
+/// The default number of blocks to test.
+const DEFAULT_NUM_BLOCKS: usize = 576;
+/// The maximum number of blocks chosen with --auto-blocks, to cap the test duration.
+const MAX_AUTO_NUM_BLOCKS: usize = 16384;
+/// The number of blocks read to estimate the test duration before starting.
+const CALIBRATION_BLOCKS: usize = 8;
+/// The number of I/O errors of a phase logged as they happen, before the others are deferred to
+/// the end of the phase.
+const MAX_IMMEDIATE_ERRORS: usize = 20;
+/// The coefficient of variation of the I/O durations of a phase above which the drive is reported
+/// as having inconsistent performance.
+const HIGH_CV_THRESHOLD: f64 = 1.0;
+/// The minimum time between two progress lines, when stderr is not a terminal.
+const TEXT_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// The number of blocks tested in each round of --find-breakpoint.
+const BREAKPOINT_PROBES: u64 = 16;
+/// The minimum number of I/O operations of a phase to interpret their coefficient of variation.
+const MIN_CV_SAMPLES: usize = 16;
+/// Exit status used when the validated size of some drives is below --min-capacity.
+const EXIT_STATUS_BELOW_MIN_CAPACITY: i32 = 3;
+/// The upper bounds of the latency buckets of the speed map, as multiples of the median latency.
+/// Slower blocks fall in the last bucket.
+const SPEED_MAP_BUCKETS: [f64; 3] = [1.5, 3.0, 10.0];
+/// Maximum number of offsets listed when reporting the blocks whose original content could not be
+/// restored.
+const MAX_LISTED_RESTORE_FAILURES: usize = 10;
+/// Minimum interval between the status lines printed during --soak.
+const SOAK_STATUS_INTERVAL: Duration = Duration::from_secs(30);
+/// The maximum number of blocks failing verification in each phase whose content is printed with
+/// --diff-on-fail.
+const MAX_DIFFED_BLOCKS: usize = 4;
+/// The maximum number of tested blocks whose expected content is compared with the content of a
+/// block failing verification, to find which block it holds the data of.
+const MAX_SOURCE_CANDIDATES: usize = 4096;
+
+/// Validate the actual size of drives, against their declared size.
 #[derive(Parser)]
 #[clap(version = "1.0")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// The storage device to test.
-    #[arg(short, long)]
-    drive: String,
-    /// The block size to read/write in KiB.
-    #[arg(short = 'b', long = "block-size-kb", default_value = "4")]
-    block_size_kb: u64,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The arguments of the test command, which is run when no command is given.
+    #[command(flatten)]
+    test: TestArgs,
+    /// Print more diagnostic messages on stderr. Can be repeated. The RUST_LOG environment
+    /// variable (e.g. RUST_LOG=debug) takes precedence.
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// The units in which sizes are displayed.
+    #[arg(long = "units", value_enum, default_value = "both", global = true)]
+    units: size::Units,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Test drives by overwriting blocks with random data and verifying them (the default).
+    Test(TestArgs),
+    /// Only print the information about drives, without reading or writing any block. The drives
+    /// are opened read-only and non-exclusively.
+    Info(InfoArgs),
+    /// List the block devices present on the system, to find the path of the drive to test.
+    List,
+    /// Only read the tested blocks, without writing them (same as test --read-only).
+    Verify(TestArgs),
+    /// Compare two result files written with --csv-out for the same drive, and report the blocks
+    /// whose state changed and the difference in validated size.
+    Diff {
+        /// The result file of the earlier test.
+        old: PathBuf,
+        /// The result file of the later test.
+        new: PathBuf,
+    },
+    /// Run the test against an in-memory device instead of a drive, to measure the overhead and
+    /// maximum throughput of the tool itself.
+    Benchmark {
+        /// The size of the memory device in MiB.
+        #[arg(value_name = "SIZE_MIB")]
+        size_mib: u64,
+        #[command(flatten)]
+        options: TestOptions,
+    },
+}
+
+/// The drives to operate on.
+#[derive(Args)]
+struct DriveArgs {
+    /// The storage device to test. Can be repeated, or given as a comma-separated list, to test
+    /// multiple devices in sequence.
+    #[arg(short, long = "drive", value_delimiter = ',', required = true)]
+    drives: Vec<String>,
+}
+
+/// The arguments of the info command.
+#[derive(Args)]
+struct InfoArgs {
+    #[command(flatten)]
+    drives: DriveArgs,
+    /// Also print the sysfs directory of each drive and the attributes read to find its
+    /// information, to attach to bug reports about missing or wrong information.
+    #[arg(long = "dump-sysfs", hide = true)]
+    dump_sysfs: bool,
+}
+
+/// The arguments of the commands testing drives.
+#[derive(Args)]
+struct TestArgs {
+    #[command(flatten)]
+    drives: DriveArgs,
+    #[command(flatten)]
+    options: TestOptions,
+}
+
+/// The options controlling the test of a drive.
+#[derive(Args, Clone)]
+struct TestOptions {
+    /// The block size to read/write in KiB. A comma-separated list (e.g. 4,64,1024) runs the test
+    /// at each block size in turn. Defaults to the optimal I/O size reported by the drive, or 4 KiB
+    /// if it reports none.
+    #[arg(
+        short = 'b',
+        long = "block-size-kb",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    block_size_kb: Vec<u64>,
+    /// The block size to read/write, with an optional unit suffix (e.g. 512K or 1M), as an
+    /// alternative to --block-size-kb. A comma-separated list runs the test at each block size in
+    /// turn.
+    #[arg(
+        long = "block-size",
+        value_delimiter = ',',
+        value_parser = size::parse_block_size,
+        conflicts_with = "block_size_kb"
+    )]
+    block_size: Vec<u64>,
     /// The number of blocks to test.
-    #[arg(short = 'n', long = "num-blocks", default_value = "576")]
+    #[arg(
+        short = 'n',
+        long = "num-blocks",
+        default_value_t = DEFAULT_NUM_BLOCKS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
     num_blocks: usize,
+    /// Choose the number of blocks to test so that they total this size, with an optional unit
+    /// suffix (e.g. 64G).
+    #[arg(
+        long = "test-size",
+        value_parser = size::parse_size,
+        conflicts_with_all = ["num_blocks", "auto_blocks", "blocks_from", "wipe"]
+    )]
+    test_size: Option<u64>,
+    /// Choose the number of blocks to test based on the drive size, so that each block represents
+    /// at most --granularity-mib MiB of the drive.
+    #[arg(long = "auto-blocks", conflicts_with = "num_blocks")]
+    auto_blocks: bool,
+    /// The maximum size of the drive area represented by each tested block, in MiB, used with
+    /// --auto-blocks.
+    #[arg(
+        long = "granularity-mib",
+        default_value = "256",
+        requires = "auto_blocks"
+    )]
+    granularity_mib: u64,
+    /// Test only the blocks listed in this file, one per line: either a block number, or a byte
+    /// offset prefixed with '@'. Empty lines and lines starting with '#' are ignored.
+    #[arg(long = "blocks-from", conflicts_with_all = ["num_blocks", "auto_blocks"])]
+    blocks_from: Option<PathBuf>,
+    /// Retest only the blocks that failed in this result file, written by an earlier test of the
+    /// drive with --csv-out, to check whether the failures are persistent or transient. The
+    /// results exported with --csv-out merge the new results into the earlier ones.
+    #[arg(
+        long = "retest-from",
+        conflicts_with_all = [
+            "num_blocks", "auto_blocks", "test_size", "blocks_from", "checksums_from", "stride",
+            "wipe", "full"
+        ]
+    )]
+    retest_from: Option<PathBuf>,
+    /// Test one block every this many bytes of the drive, starting from its beginning, with an
+    /// optional unit suffix (e.g. 1G). It must be a multiple of the block size.
+    #[arg(
+        long = "stride",
+        value_parser = size::parse_size,
+        conflicts_with_all = [
+            "num_blocks", "auto_blocks", "test_size", "blocks_from", "checksums_from", "wipe",
+            "full"
+        ]
+    )]
+    stride: Option<u64>,
+    /// Test only the last block of the drive: a minimal, fast and low-risk check detecting the
+    /// simplest fake drives, which fail beyond their real capacity. It cannot detect most fakes:
+    /// run the full test for a reliable verdict.
+    #[arg(
+        long = "canary",
+        conflicts_with_all = [
+            "num_blocks", "auto_blocks", "test_size", "blocks_from", "retest_from",
+            "checksums_from", "stride", "read_only", "wipe", "full"
+        ]
+    )]
+    canary: bool,
     /// Perform only a read test.
     #[arg(short = 'R', long = "read-only")]
     read_only: bool,
+    /// Do not read or write the tested blocks overlapping the bytes from START (included) to END
+    /// (excluded), e.g. 0:1M to protect a bootloader, and report them as excluded. Can be
+    /// repeated.
+    #[arg(
+        long = "exclude",
+        value_name = "START:END",
+        value_parser = size::parse_range,
+        conflicts_with_all = ["warmup", "find_breakpoint"]
+    )]
+    exclude: Vec<Range<u64>>,
+    /// Read the tested blocks over and over for this long (e.g. 90s, 30m or 8h), reporting the
+    /// errors and the reconnections of the drive, to reveal problems showing only under
+    /// sustained load, like overheating or an unstable power supply. Only valid with --read-only
+    /// (or the verify command).
+    #[arg(
+        long = "soak",
+        value_parser = parse_duration,
+        conflicts_with = "checksums_from"
+    )]
+    soak: Option<Duration>,
     /// Width in columns of the validation map printed on the terminal.
-    #[arg(short = 'w', long = "map-width", default_value = "64")]
+    #[arg(
+        short = 'w',
+        long = "map-width",
+        default_value = "64",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
     map_width: usize,
-    /// Do not read and restore original blocks content.
+    /// Use plain ASCII characters in the validation map, for terminals or fonts not rendering its
+    /// Unicode symbols.
+    #[arg(long = "ascii-map")]
+    ascii_map: bool,
+    /// Print a speed map next to the validation map, laid out in the same grid, representing the
+    /// latency of the reads or writes of each block.
+    #[arg(long = "speed-map", value_enum, conflicts_with_all = ["wipe", "full"])]
+    speed_map: Option<SpeedMapPhase>,
+    /// Do not restore original blocks content at the end of the test.
     #[arg(short = 'O', long = "no-restore-original")]
     no_restore_original: bool,
+    /// Allow writing to block devices that are not removable media, or that hold the root
+    /// filesystem. By default only removable, USB and SD/MMC drives are written to.
+    #[arg(long = "allow-internal", conflicts_with = "read_only")]
+    allow_internal: bool,
+    /// Read back the original blocks content after writing it back, to confirm that it was
+    /// restored.
+    #[arg(
+        long = "verify-restore",
+        conflicts_with_all = ["read_only", "no_read_original", "no_restore_original"]
+    )]
+    verify_restore: bool,
+    /// Do not read original blocks content before writing (implies --no-restore-original).
+    #[arg(long = "no-read-original", conflicts_with = "read_only")]
+    no_read_original: bool,
+    /// The test phases to run, as a comma-separated list, instead of choosing them with
+    /// --read-only, --no-read-original and --no-restore-original. E.g. "write,verify" writes and
+    /// verifies the blocks without reading and restoring their original content.
+    #[arg(
+        long = "phases",
+        value_enum,
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "read_only", "no_read_original", "no_restore_original", "ignore_read_errors", "wipe"
+        ]
+    )]
+    phases: Option<Vec<Phase>>,
+    /// Whether the written blocks are not read back and verified. Set after parsing the command
+    /// line, from --phases.
+    #[arg(skip)]
+    no_verify: bool,
+    /// Do not stop the test if reading the original content of some blocks fails: skip those
+    /// blocks and test the others.
+    #[arg(long = "ignore-read-errors", conflicts_with_all = ["read_only", "no_read_original"])]
+    ignore_read_errors: bool,
+    /// Cap the sustained I/O throughput to this many MB/s, to avoid overheating cheap controllers.
+    #[arg(long = "throttle-mbps", value_parser = parse_throughput)]
+    throttle_mbps: Option<f64>,
+    /// Pause for --pause-ms milliseconds every this many blocks, to let the controller cool down.
+    #[arg(
+        long = "pause-every",
+        requires = "pause_ms",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pause_every: Option<usize>,
+    /// The duration of the pauses requested with --pause-every, in milliseconds.
+    #[arg(
+        long = "pause-ms",
+        requires = "pause_every",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pause_ms: Option<u64>,
+    /// Sample the drive temperature every few seconds while writing, report its peak, and flag
+    /// throughput drops concurrent with a temperature rise as thermal throttling. Requires a
+    /// temperature sensor exposed by the kernel (NVMe drives, or SATA drives with the drivetemp
+    /// module).
+    #[arg(long = "monitor-temperature", conflicts_with = "read_only")]
+    monitor_temperature: bool,
+    /// Read every block of the drive, in ascending order, and report the unreadable regions. Only
+    /// valid with --read-only (or the verify command). Use a large block size, as every block is
+    /// tracked in memory.
+    #[arg(
+        long = "full",
+        conflicts_with_all = ["num_blocks", "auto_blocks", "blocks_from", "test_size", "checksums_from"]
+    )]
+    full: bool,
+    /// Wipe the drive: write the --wipe-pattern to every block, verify it and do not restore the
+    /// original content (implies --no-read-original). Asks for confirmation before starting. Use a
+    /// large block size, as every block is tracked in memory.
+    #[arg(
+        long = "wipe",
+        conflicts_with_all = ["read_only", "num_blocks", "auto_blocks", "blocks_from"]
+    )]
+    wipe: bool,
+    /// The pattern written to every block by --wipe. Only random data also verifies the capacity
+    /// of the drive: a drive wrapping around to its start stores a constant pattern correctly.
+    #[arg(
+        long = "wipe-pattern",
+        value_enum,
+        default_value_t = WipePattern::Random,
+        conflicts_with = "data_file"
+    )]
+    wipe_pattern: WipePattern,
+    /// Discard (TRIM) the tested blocks before writing them, so that the write timings measure
+    /// the programming of erased flash rather than overwrites. Skipped if the drive does not
+    /// support discard.
+    #[arg(long = "trim-before-write", conflicts_with = "read_only")]
+    trim_before_write: bool,
+    /// Fail the read/write of a block if it takes longer than this many milliseconds. A write that
+    /// timed out cannot be cancelled: all the following reads and writes fail until it completes.
+    #[arg(long = "io-timeout", value_parser = clap::value_parser!(u64).range(1..))]
+    io_timeout: Option<u64>,
+    /// When the drive disconnects during the test, wait up to this many seconds for it to
+    /// reappear, then reopen it and resume the test if it is the same drive.
+    #[arg(long = "reconnect-wait")]
+    reconnect_wait: Option<u64>,
+    /// Test blocks in ascending physical order instead of a random order. This helps correlating
+    /// timings and failures with the position on the drive, but makes the detection of drives
+    /// wrapping writes around less reliable.
+    #[arg(long = "no-shuffle")]
+    no_shuffle: bool,
+    /// Read back the written blocks in descending physical order, instead of the order they were
+    /// written. Blocks validating in one order but not in the other reveal order-dependent
+    /// caching or aliasing.
+    #[arg(long = "reverse-verify")]
+    reverse_verify: bool,
+    /// Read each block twice when verifying the written data, and report blocks returning
+    /// different data on the two reads as unstable.
+    #[arg(long = "double-read")]
+    double_read: bool,
+    /// Read back and verify only this fraction (between 0 and 1) of the written blocks, chosen at
+    /// random, to shorten the test of large drives at the cost of confidence. The other blocks are
+    /// reported as written but not verified.
+    #[arg(
+        long = "verify-fraction",
+        value_parser = parse_fraction,
+        conflicts_with_all = ["read_only", "wipe"]
+    )]
+    verify_fraction: Option<f64>,
+    /// Flush the written data and drop the OS caches for the drive before verifying it, also
+    /// dropping the page cache of the whole system when running as root.
+    #[arg(long = "drop-caches")]
+    drop_caches: bool,
+    /// After the test, find precisely where the storage of the drive ends: repeatedly test blocks
+    /// evenly spaced between the highest validated block and the following failed block,
+    /// narrowing the interval until they are adjacent.
+    #[arg(long = "find-breakpoint", conflicts_with_all = ["read_only", "wipe", "full"])]
+    find_breakpoint: bool,
+    /// Open the drive without O_SYNC, so that writes complete as soon as the drive accepts the
+    /// data, before it is committed to the media. Faster, but the drive cache may hide missing
+    /// storage: combine with --drop-caches to flush the written data before verifying it.
+    #[arg(long = "no-sync", conflicts_with = "read_only")]
+    no_sync: bool,
+    /// Before the measured phases, read and write back this many random blocks, so that the burst
+    /// cache of the drive is filled and the timings reflect its steady-state performance. The
+    /// content of the blocks is not changed, and their timings are not reported.
+    #[arg(long = "warmup", value_name = "BLOCKS")]
+    warmup: Option<usize>,
+    /// Check that an image file is fully allocated on its filesystem: like a fake drive, a sparse
+    /// file can report a size larger than the filesystem can store.
+    #[arg(long = "preallocate-check")]
+    preallocate_check: bool,
+    /// After the verification, test pairs of far-apart blocks for aliasing: write the first, write
+    /// the second, then read back the first to check whether writing the second overwrote it.
+    #[arg(long = "interference-test", conflicts_with = "read_only")]
+    interference_test: bool,
+    /// After the verification, write each tested block again and read it back right away, before
+    /// writing the next one, and show the results in a separate map. This detects media failing
+    /// immediately, like bad sectors, but not fake capacity, as the drive cache still holds the
+    /// data when it is read back.
+    #[arg(long = "immediate-verify", conflicts_with_all = ["read_only", "wipe"])]
+    immediate_verify: bool,
+    /// Repeat the test accessing the drive through the OS cache, and compare the results and
+    /// throughput with the test bypassing it. The output files are written only for the first test.
+    #[arg(long = "compare-caching", conflicts_with = "wipe")]
+    compare_caching: bool,
+    /// How the validated drive size is computed from the tested blocks: "contiguous" counts the
+    /// drive only up to the first block failing validation, "total" counts all the validated
+    /// blocks, including those following failed ones.
+    #[arg(long = "capacity-metric", value_enum, default_value_t = CapacityMetric::Contiguous)]
+    capacity_metric: CapacityMetric,
+    /// Fail the test, with exit status 3, if the validated drive size is below this size, with an
+    /// optional unit suffix (e.g. 64G).
+    #[arg(
+        long = "min-capacity",
+        value_parser = size::parse_size,
+        conflicts_with_all = ["read_only", "blocks_from"]
+    )]
+    min_capacity: Option<u64>,
+    /// Compare the validated drive size with the usable size found by f3probe or f3read, from
+    /// the f3 suite, whose output was saved to this file, and report whether they agree.
+    #[arg(long = "compare-f3", conflicts_with_all = ["read_only", "canary", "blocks_from"])]
+    compare_f3: Option<PathBuf>,
+    /// Repeat the write and verification of the tested blocks this many times, with different
+    /// random data each time, and report the blocks failing only in some of the passes as
+    /// marginal. The original content is restored after the last pass.
+    #[arg(
+        long = "repeat",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "read_only"
+    )]
+    repeat: u32,
+    /// Write the content of this file to the tested blocks, repeated over the whole drive, instead
+    /// of random data. Requires --block-header: a drive wrapping around at a multiple of the file
+    /// size stores the repeated content correctly, and only the headers tell the blocks apart.
+    #[arg(long = "data-file", requires = "block_header")]
+    data_file: Option<PathBuf>,
+    /// Seed the random generator choosing and shuffling the tested blocks and generating the
+    /// written data with this number, so that a run can be reproduced exactly.
+    #[arg(long = "seed", conflicts_with = "seed_file")]
+    seed: Option<u64>,
+    /// Read the random seed from this file if it exists, or save the seed used to it otherwise,
+    /// so that consecutive runs are reproducible without passing --seed.
+    #[arg(long = "seed-file")]
+    seed_file: Option<PathBuf>,
+    /// Start each written block with a header holding a magic number, the test run ID and the
+    /// block number, so that a block read back with the header of another block proves aliasing.
+    /// The header also identifies the data when examining the drive later.
+    #[arg(long = "block-header", conflicts_with = "read_only")]
+    block_header: bool,
+    /// Verify the written blocks by comparing the CRC-32 of their content, recorded when writing
+    /// them, rather than their whole content.
+    #[arg(long = "checksum", conflicts_with = "read_only")]
+    checksum: bool,
+    /// Print the first bytes expected and read back, side by side, of the first blocks failing
+    /// verification in each phase, and how their content differs: all zeros, a constant byte, the
+    /// expected data shifted, or the data written to another tested block.
+    #[arg(long = "diff-on-fail", conflicts_with_all = ["read_only", "checksum"])]
+    diff_on_fail: bool,
+    /// Export the CRC-32 of the content written to each tested block to this CSV file, so that the
+    /// blocks can be verified later with --checksums-from, e.g. after some time to check data
+    /// retention. Use with --no-restore-original to keep the written content on the drive.
+    #[arg(long = "checksums-out", conflicts_with = "read_only")]
+    checksums_out: Option<PathBuf>,
+    /// Verify the blocks listed in this CSV file, exported with --checksums-out, against their
+    /// CRC-32. Only valid with --read-only (or the verify command).
+    #[arg(
+        long = "checksums-from",
+        conflicts_with_all = ["num_blocks", "auto_blocks", "blocks_from", "test_size", "no_read_original"]
+    )]
+    checksums_from: Option<PathBuf>,
+    /// Write machine-parseable progress events, as newline-delimited JSON, to this file
+    /// descriptor (e.g. 2 for stderr).
+    #[arg(long = "progress-fd")]
+    progress_fd: Option<i32>,
+    /// Export the validation map to this file: a PNG image if the name ends with .png, otherwise a
+    /// binary file with one byte per block.
+    #[arg(long = "map-out")]
+    map_out: Option<PathBuf>,
+    /// Export the result of each tested block to this CSV file, which can be compared with a later
+    /// test of the same drive with the diff command.
+    #[arg(long = "csv-out")]
+    csv_out: Option<PathBuf>,
+    /// Export the duration of every read and write of the tested blocks, in nanoseconds, along with
+    /// their offset on the drive, to this CSV file.
+    #[arg(long = "timings-out")]
+    timings_out: Option<PathBuf>,
+    /// Write a JUnit XML report of the test to this file, for CI systems: the validation of each
+    /// drive is a test case, failing if the drive is not fully validated, and each test phase is a
+    /// test case with the duration of the phase.
+    #[arg(long = "junit")]
+    junit: Option<PathBuf>,
+    /// Show a full-screen dashboard with the live validation map, throughput, errors and latency
+    /// instead of the progress bars. Ignored if stdout is not a terminal.
+    #[arg(long = "tui")]
+    tui: bool,
+    /// Print only one line per drive, with its path, model, serial number, advertised size,
+    /// validated size and verdict, instead of the detailed output and progress bars.
+    #[arg(long = "summary-only", conflicts_with_all = ["tui", "wipe"])]
+    summary_only: bool,
+    /// Whether multiple drives are tested, in which case the output files are named after each
+    /// drive. Set after parsing the command line.
+    #[arg(skip)]
+    multiple_drives: bool,
+    /// Whether the test runs at multiple block sizes. Set after parsing the command line.
+    #[arg(skip)]
+    multiple_block_sizes: bool,
+}
+
+impl TestOptions {
+    /// Return the block size in bytes, given with either --block-size or --block-size-kb, or
+    /// DEFAULT_BLOCK_SIZE if none. When multiple block sizes are given, return the first one.
+    fn block_size(&self) -> u64 {
+        self.block_sizes()[0]
+    }
+
+    /// Return the block sizes in bytes to test, given with either --block-size or
+    /// --block-size-kb, or DEFAULT_BLOCK_SIZE if none.
+    fn block_sizes(&self) -> Vec<u64> {
+        if !self.block_size.is_empty() {
+            self.block_size.clone()
+        } else if !self.block_size_kb.is_empty() {
+            self.block_size_kb.iter().map(|size| size * 1024).collect()
+        } else {
+            vec![DEFAULT_BLOCK_SIZE]
+        }
+    }
+
+    /// Return whether the block size was given on the command line.
+    fn has_block_size(&self) -> bool {
+        !self.block_size.is_empty() || !self.block_size_kb.is_empty()
+    }
+
+    /// Set the options selecting the test phases from the list given with --phases, if any.
+    /// Returns an error if the list is not a meaningful combination of phases.
+    fn apply_phases(&mut self) -> Result<()> {
+        let Some(phases) = &self.phases else {
+            return Ok(());
+        };
+        if self.read_only {
+            return Err(anyhow!("--phases cannot be used with the verify command"));
+        }
+        let read_original = phases.contains(&Phase::ReadOriginal);
+        let write = phases.contains(&Phase::Write);
+        let verify = phases.contains(&Phase::Verify);
+        let restore = phases.contains(&Phase::Restore);
+        if verify && !write {
+            return Err(anyhow!("the verify phase requires the write phase"));
+        }
+        if !verify && self.min_capacity.is_some() {
+            return Err(anyhow!("--min-capacity requires the verify phase"));
+        }
+        if restore && !(read_original && write) {
+            return Err(anyhow!(
+                "the restore phase requires the read-original and write phases"
+            ));
+        }
+        if !write {
+            if !read_original {
+                return Err(anyhow!(
+                    "--phases must include at least a read or write phase"
+                ));
+            }
+            self.read_only = true;
+        }
+        self.no_read_original = !read_original;
+        self.no_restore_original = !restore;
+        self.no_verify = !verify;
+        Ok(())
+    }
+}
+
+/// Options controlling how blocks are read and written.
+#[derive(Clone)]
+struct IoOptions {
+    /// The maximum sustained throughput in bytes per second, if any.
+    max_bytes_per_sec: Option<f64>,
+    /// The number of blocks after which to pause, and the duration of the pause.
+    pause: Option<(usize, Duration)>,
+    /// Whether to stop issuing I/O operations when the user interrupts the test.
+    interruptible: bool,
+    /// The emitter of machine-parseable progress events.
+    progress: progress::ProgressEvents,
+    /// The dashboard showing the progress instead of the progress bars, if any.
+    dashboard: Option<tui::Dashboard>,
+    /// Whether to hide the progress bars and the statistics of each phase.
+    quiet: bool,
+    /// Whether to print how the content of the first blocks failing verification differs from
+    /// the expected one.
+    diff_on_fail: bool,
+    /// Whether to sample the temperature of the drive while writing.
+    monitor_temperature: bool,
+}
+
+impl IoOptions {
+    /// Create a new `IoOptions` structure from the command line arguments.
+    fn from_cli(cli: &TestOptions, progress: progress::ProgressEvents) -> Self {
+        Self {
+            max_bytes_per_sec: cli.throttle_mbps.map(|mbps| mbps * 1_000_000.0),
+            pause: cli
+                .pause_every
+                .zip(cli.pause_ms)
+                .map(|(every, ms)| (every, Duration::from_millis(ms))),
+            interruptible: true,
+            progress,
+            dashboard: None,
+            quiet: cli.summary_only,
+            diff_on_fail: cli.diff_on_fail,
+            monitor_temperature: cli.monitor_temperature,
+        }
+    }
+
+    /// Return a copy of these options for operations that must run to completion even if the user
+    /// interrupts the test, like restoring the original blocks content.
+    fn uninterruptible(&self) -> Self {
+        Self {
+            interruptible: false,
+            ..self.clone()
+        }
+    }
+
+    /// Create the progress bar of a phase of `len` steps, each transferring `step_bytes` bytes,
+    /// drawn in `color`. It shows the elapsed time and the current throughput in MB/s.
+    /// The bar is hidden if the dashboard is shown instead, or if the output is quiet. When stderr
+    /// is not a terminal, the progress is printed as a plain line every TEXT_PROGRESS_INTERVAL
+    /// instead.
+    fn progress_bar(&self, len: usize, color: &str, step_bytes: usize) -> indicatif::ProgressBar {
+        if self.dashboard.is_some() || self.quiet {
+            return indicatif::ProgressBar::hidden();
+        }
+        let (bar, template, finished_key) = if console::Term::stderr().is_term() {
+            (
+                indicatif::ProgressBar::new(len as u64),
+                format!(
+                    "[{{elapsed_precise}}] [ETA:{{eta}}] {{bar:40.{}}} {{pos:>4}}/{{len:4}} {{rate}} {{msg}}",
+                    color
+                ),
+                None,
+            )
+        } else {
+            let text_progress = text_progress::TextProgress::new(TEXT_PROGRESS_INTERVAL);
+            let finished_key = text_progress.finished_key();
+            (
+                indicatif::ProgressBar::with_draw_target(
+                    Some(len as u64),
+                    indicatif::ProgressDrawTarget::term_like(Box::new(text_progress)),
+                ),
+                String::from(
+                    "progress: {pos}/{len} blocks [{elapsed_precise}] [ETA:{eta}] {rate} {msg}{finished}",
+                ),
+                Some(finished_key),
+            )
+        };
+        let mut style = indicatif::ProgressStyle::with_template(&template).unwrap();
+        if let Some(finished_key) = finished_key {
+            style = style.with_key("finished", finished_key);
+        }
+        bar.set_style(style.with_key(
+            "rate",
+            move |state: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| {
+                write!(
+                    w,
+                    "{:7.2} MB/s",
+                    state.per_sec() * step_bytes as f64 / 1_000_000.0
+                )
+                .unwrap()
+            },
+        ));
+        bar
+    }
+
+    /// Returns whether the user interrupted the test and no more I/O should be issued.
+    fn is_interrupted(&self) -> bool {
+        self.interruptible && interrupt::is_interrupted()
+    }
+
+    /// Sleep as needed after the `count`-th I/O operation of `bytes` bytes, which took
+    /// `io_duration`, to honor the throttling options.
+    /// The sleeps are not part of the durations recorded for the statistics.
+    fn throttle(&self, count: usize, bytes: usize, io_duration: Duration) {
+        if let Some(max_bytes_per_sec) = self.max_bytes_per_sec {
+            let min_duration = Duration::from_secs_f64(bytes as f64 / max_bytes_per_sec);
+            if min_duration > io_duration {
+                std::thread::sleep(min_duration - io_duration);
+            }
+        }
+        if let Some((every, pause)) = self.pause {
+            if count.is_multiple_of(every) {
+                std::thread::sleep(pause);
+            }
+        }
+    }
 }
 
 /// Convert a Duration to milliseconds.
@@ -58,95 +751,717 @@ fn as_millis_f64(d: &Duration) -> f64 {
     d.as_nanos() as f64 / 1_000_000.0
 }
 
+/// Print how the content `actual` of the block `block_idx` differs from the `expected` one, and
+/// which other block of `spot_blocks` it holds the data of, if any. The lowest blocks are searched
+/// first, as a drive smaller than advertised usually wraps around to the start.
+fn print_mismatch(
+    block_idx: &BlockIdx,
+    block_size: usize,
+    expected: &[u8],
+    actual: &[u8],
+    keystream: &keystream::Keystream,
+    spot_blocks: &[BlockIdx],
+) {
+    let offset = block_idx.num * block_size as u64;
+    println!(
+        "Block {} (offset {}) differs: {}",
+        block_idx.idx,
+        offset,
+        mismatch::describe(expected, actual)
+    );
+    let mut candidates: Vec<u64> = spot_blocks
+        .iter()
+        .map(|block| block.num)
+        .filter(|&num| num != block_idx.num)
+        .collect();
+    candidates.sort_unstable();
+    let mut candidate_block = vec![0; block_size];
+    for num in candidates.into_iter().take(MAX_SOURCE_CANDIDATES) {
+        keystream.fill(num, &mut candidate_block);
+        if candidate_block[..actual.len()] == *actual {
+            println!(
+                "  It holds the data written for offset {}",
+                num * block_size as u64
+            );
+            break;
+        }
+    }
+    for line in mismatch::side_by_side(expected, actual) {
+        println!("  {}", line);
+    }
+}
+
 /// Read all blocks identified by `spot_blocks`` from `drive`.
-/// Read timings statistics are printed to stdout.
-/// Returns a vector of blocks containing the read data and any errors.
+/// Read timings statistics are printed to stdout. Progress events are emitted for `phase`.
+/// If the test is interrupted, the remaining blocks are not read.
+/// If `reread` is true, each block is read a second time right after the first read, and blocks
+/// returning different data are marked as unstable.
+/// If `expected` is provided, each block is compared with its expected content or checksum as soon
+/// as it is read, and the read data is not kept.
+/// Returns a vector of blocks containing the read data (unless `expected` is provided) and any
+/// errors, and the result of the phase.
 fn read_blocks(
     drive: &mut dyn device::Device,
-    spot_blocks: &Vec<BlockIdx>,
+    phase: &str,
+    spot_blocks: &[BlockIdx],
     block_size: usize,
-) -> Blocks {
-    let mut blocks = Blocks::new(block_size, spot_blocks.len(), drive.get_memory_alignment());
+    io_options: &IoOptions,
+    reread: bool,
+    expected: Option<Expected>,
+) -> (Blocks, PhaseResult) {
+    let mut blocks = if expected.is_some() {
+        Blocks::new_streaming(block_size, spot_blocks.len(), drive.get_memory_alignment())
+    } else {
+        Blocks::new(block_size, spot_blocks.len(), drive.get_memory_alignment())
+    };
+    let mut expected_block = vec![
+        0;
+        if matches!(expected, Some(Expected::Content(_))) {
+            block_size
+        } else {
+            0
+        }
+    ];
+    let mut reread_block = Blocks::new(block_size, 1, drive.get_memory_alignment());
+    let mut num_diffed = 0;
 
-    let bar = indicatif::ProgressBar::new(spot_blocks.len() as u64);
-    bar.set_style(
-        indicatif::ProgressStyle::with_template("[ETA:{eta}] {bar:40.blue} {pos:>4}/{len:4} {msg}")
-            .unwrap(),
-    );
+    let bar = io_options.progress_bar(spot_blocks.len(), "blue", block_size);
+    let mut errors = ErrorLog::default();
     bar.tick();
-    let mut durations = Vec::with_capacity(spot_blocks.len());
-    for i in 0..blocks.num_blocks {
-        let offset = spot_blocks[i].num * block_size as u64;
+    io_options.progress.phase(phase, spot_blocks.len());
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.phase(phase, spot_blocks.len());
+    }
+    log::info!(
+        "Starting phase {}: reading {} blocks",
+        phase,
+        spot_blocks.len()
+    );
+    let mut throughput = Throughput::default();
+    let start = Instant::now();
+    for (i, block_idx) in spot_blocks.iter().enumerate().take(blocks.num_blocks) {
+        if io_options.is_interrupted() {
+            break;
+        }
+        let offset = block_idx.num * block_size as u64;
         let data = &mut blocks.block_mut(i);
+        // Both reads of --double-read are throttled together, as a single I/O operation.
+        let mut io_bytes = 0;
+        let mut io_duration = Duration::ZERO;
         match drive.read(offset, data) {
             Ok(duration) => {
-                durations.push(duration);
+                blocks.durations[i] = Some(duration);
+                throughput.add(block_size, duration);
+                io_bytes += block_size;
+                io_duration += duration;
             }
             Err(err) => {
-                bar.suspend(|| {
-                    println!(
-                        "{}",
-                        console::style(format!(
-                            "Read error at block {} (offset {}): {}",
-                            spot_blocks[i].idx, offset, err
-                        ))
-                        .red()
-                    )
-                });
+                let kind = device::ErrorKind::of(&err);
+                errors.log(
+                    &bar,
+                    log::Level::Error,
+                    format!(
+                        "Read error ({}) at block {} (offset {}): {}",
+                        kind.description(),
+                        block_idx.idx,
+                        offset,
+                        err
+                    ),
+                );
                 blocks.errors[i] = IoError::ReadError;
+                if kind == device::ErrorKind::Disconnected {
+                    blocks.disconnected_at = Some(i);
+                    break;
+                }
+            }
+        }
+        if reread && blocks.errors[i] == IoError::None {
+            match drive.read(offset, reread_block.block_mut(0)) {
+                Ok(duration) => {
+                    io_bytes += block_size;
+                    io_duration += duration;
+                    if reread_block.block(0) != blocks.block(i) {
+                        errors.log(
+                            &bar,
+                            log::Level::Warn,
+                            format!(
+                                "Unstable data at block {} (offset {}): two reads differ",
+                                block_idx.idx, offset
+                            ),
+                        );
+                        blocks.errors[i] = IoError::Unstable;
+                    }
+                }
+                Err(err) => {
+                    let kind = device::ErrorKind::of(&err);
+                    errors.log(
+                        &bar,
+                        log::Level::Error,
+                        format!(
+                            "Read error ({}) at block {} (offset {}): {}",
+                            kind.description(),
+                            block_idx.idx,
+                            offset,
+                            err
+                        ),
+                    );
+                    blocks.errors[i] = IoError::ReadError;
+                    if kind == device::ErrorKind::Disconnected {
+                        blocks.disconnected_at = Some(i);
+                        break;
+                    }
+                }
+            }
+        }
+        if io_bytes > 0 {
+            io_options.throttle(i + 1, io_bytes, io_duration);
+        }
+        if let Some(expected) = &expected {
+            if blocks.errors[i] == IoError::None && blocks.durations[i].is_some() {
+                // Only the part of the last block within the drive can hold data.
+                let len = (drive.get_size() - offset).min(block_size as u64) as usize;
+                let matches = match expected {
+                    Expected::Content(keystream) => {
+                        keystream.fill(block_idx.num, &mut expected_block);
+                        blocks.block(i)[..len] == expected_block[..len]
+                    }
+                    Expected::Checksums(checksums) => {
+                        checksum::crc32(&blocks.block(i)[..len]) == checksums[i]
+                    }
+                    Expected::Any => true,
+                };
+                if !matches {
+                    blocks.errors[i] = IoError::Mismatch;
+                    // A header written for another block proves that the two blocks are stored
+                    // in the same place.
+                    if let Some(header) = keystream::parse_header(&blocks.block(i)[..len]) {
+                        if header.block_num != block_idx.num {
+                            errors.log(
+                                &bar,
+                                log::Level::Warn,
+                                format!(
+                                    "Aliasing at block {} (offset {}): it holds the data written \
+                                     for offset {} (run {})",
+                                    block_idx.idx,
+                                    offset,
+                                    header.block_num * block_size as u64,
+                                    header.run_id
+                                ),
+                            );
+                        }
+                    }
+                    if let Expected::Content(keystream) = expected {
+                        if io_options.diff_on_fail && num_diffed < MAX_DIFFED_BLOCKS {
+                            num_diffed += 1;
+                            bar.suspend(|| {
+                                print_mismatch(
+                                    block_idx,
+                                    block_size,
+                                    &expected_block[..len],
+                                    &blocks.block(i)[..len],
+                                    keystream,
+                                    spot_blocks,
+                                )
+                            });
+                        }
+                    }
+                }
             }
         }
+        io_options.progress.block(
+            phase,
+            i + 1,
+            spot_blocks.len(),
+            offset,
+            throughput.bytes_per_sec(),
+        );
+        if let Some(dashboard) = &io_options.dashboard {
+            let report = match blocks.errors[i] {
+                IoError::ReadError => Some(BlockReport::ReadError),
+                IoError::Unstable => Some(BlockReport::Unstable),
+                IoError::Mismatch => Some(BlockReport::NoStorage),
+                _ => None,
+            };
+            dashboard.block(block_idx.idx, block_size, blocks.durations[i], report);
+        }
         bar.inc(1);
     }
     bar.finish();
+    errors.log_deferred();
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.end_phase();
+    }
+    blocks.elapsed = start.elapsed();
+    log::info!(
+        "Finished phase {} in {:.3} s",
+        phase,
+        blocks.elapsed.as_secs_f64()
+    );
 
-    print_stats(&durations);
-    blocks
+    let result = PhaseResult::new(phase.to_string(), &blocks, spot_blocks);
+    if !io_options.quiet {
+        print_stats(&result);
+    }
+    (blocks, result)
+}
+
+/// The expected content of the blocks read back, to verify them as they are read.
+enum Expected<'a> {
+    /// The content generated by the keystream.
+    Content(&'a keystream::Keystream),
+    /// The CRC-32 of the content of each block, in the order of the blocks read.
+    Checksums(&'a [u32]),
+    /// Any content, which is not kept.
+    Any,
 }
 
-/// Write the blocks identified by `spot_blocks` to `drive` with the data provided in `data`.
+/// Write the blocks identified by `spot_blocks` to `drive` with the data provided in `data`, or
+/// generated by `content` right before writing each block, if provided.
 /// Blocks that are marked with a read error in `data` are skipped.
-/// `data` is updated with any write errors.
-/// Read timings statistics are printed to stdout.
-fn write_blocks(drive: &mut dyn device::Device, spot_blocks: &Vec<BlockIdx>, data: &mut Blocks) {
-    let bar = indicatif::ProgressBar::new(spot_blocks.len() as u64);
-    bar.set_style(
-        indicatif::ProgressStyle::with_template(
-            "[ETA:{eta}] {bar:40.yellow} {pos:>4}/{len:4} {msg}",
-        )
-        .unwrap(),
-    );
+/// `data` is updated with any write errors and with the write timings, and with the checksums of
+/// the written blocks if it records them.
+/// If the test is interrupted, the remaining blocks are not written.
+/// Write timings statistics are printed to stdout. Progress events are emitted for `phase`.
+/// Returns the result of the phase.
+fn write_blocks(
+    drive: &mut dyn device::Device,
+    phase: &str,
+    spot_blocks: &[BlockIdx],
+    data: &mut Blocks,
+    content: Option<&keystream::Keystream>,
+    io_options: &IoOptions,
+) -> PhaseResult {
+    let bar = io_options.progress_bar(spot_blocks.len(), "yellow", data.block_size);
+    let mut errors = ErrorLog::default();
     bar.tick();
-    let mut durations = Vec::with_capacity(spot_blocks.len());
-    for i in 0..data.num_blocks {
+    io_options.progress.phase(phase, spot_blocks.len());
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.phase(phase, spot_blocks.len());
+    }
+    log::info!(
+        "Starting phase {}: writing {} blocks",
+        phase,
+        spot_blocks.len()
+    );
+    let mut throughput = Throughput::default();
+    let mut temperatures = io_options
+        .monitor_temperature
+        .then(|| thermal::Monitor::new(drive.temperature()));
+    let start = Instant::now();
+    data.disconnected_at = None;
+    for (i, block_idx) in spot_blocks.iter().enumerate().take(data.num_blocks) {
+        if io_options.is_interrupted() {
+            break;
+        }
+        data.durations[i] = None;
+        let offset = block_idx.num * data.block_size as u64;
         if data.errors[i] == IoError::ReadError {
+            io_options.progress.block(
+                phase,
+                i + 1,
+                spot_blocks.len(),
+                offset,
+                throughput.bytes_per_sec(),
+            );
+            if let Some(dashboard) = &io_options.dashboard {
+                dashboard.block(block_idx.idx, 0, None, None);
+            }
             bar.inc(1);
             continue;
         }
-        let offset = spot_blocks[i].num * data.block_size as u64;
+        if let Some(keystream) = content {
+            keystream.fill(block_idx.num, data.block_mut(i));
+        }
         match drive.write(offset, data.block(i)) {
             Ok(duration) => {
-                durations.push(duration);
+                data.durations[i] = Some(duration);
+                let block_range = data.block_range(i);
+                if let Some(checksums) = &mut data.checksums {
+                    // Only the part of the last block within the drive is stored.
+                    let len = (drive.get_size() - offset).min(data.block_size as u64) as usize;
+                    checksums[i] = checksum::crc32(&data.data[block_range][..len]);
+                }
+                throughput.add(data.block_size, duration);
+                if let Some(temperatures) = &mut temperatures {
+                    temperatures.add(data.block_size, duration, || drive.temperature());
+                }
+                io_options.throttle(i + 1, data.block_size, duration);
             }
             Err(err) => {
-                bar.suspend(|| {
-                    println!(
-                        "{}",
-                        console::style(format!(
-                            "Write error at block {} (offset {}): {}",
-                            spot_blocks[i].idx, offset, err
-                        ))
-                        .red()
-                    )
-                });
+                let kind = device::ErrorKind::of(&err);
+                errors.log(
+                    &bar,
+                    log::Level::Error,
+                    format!(
+                        "Write error ({}) at block {} (offset {}): {}",
+                        kind.description(),
+                        block_idx.idx,
+                        offset,
+                        err
+                    ),
+                );
                 data.errors[i] = IoError::WriteError;
+                if kind == device::ErrorKind::Disconnected {
+                    data.disconnected_at = Some(i);
+                    break;
+                }
+            }
+        }
+        io_options.progress.block(
+            phase,
+            i + 1,
+            spot_blocks.len(),
+            offset,
+            throughput.bytes_per_sec(),
+        );
+        if let Some(dashboard) = &io_options.dashboard {
+            let report = match data.errors[i] {
+                IoError::WriteError => Some(BlockReport::WriteError),
+                _ => None,
+            };
+            dashboard.block(block_idx.idx, data.block_size, data.durations[i], report);
+        }
+        bar.inc(1);
+    }
+    bar.finish();
+    errors.log_deferred();
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.end_phase();
+    }
+    data.elapsed = start.elapsed();
+    log::info!(
+        "Finished phase {} in {:.3} s",
+        phase,
+        data.elapsed.as_secs_f64()
+    );
+
+    let result = PhaseResult::new(phase.to_string(), data, spot_blocks);
+    if !io_options.quiet {
+        print_stats(&result);
+        if let Some(temperatures) = &temperatures {
+            temperatures.print();
+        }
+    }
+    result
+}
+
+/// Test pairs of blocks in `spot_blocks` (sorted by position on `drive`) for interference: for each
+/// block in the first half, write it, write the block half the drive after it, and read back the
+/// first block to check whether it was overwritten. The content is generated by `keystream`.
+/// Pairs with I/O errors are reported and ignored. If the test is interrupted, the remaining pairs
+/// are not tested.
+/// Returns the pairs of indices in `spot_blocks` of the blocks found overwritten and of the blocks
+/// whose write overwrote them.
+fn test_interference(
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    block_size: usize,
+    keystream: &keystream::Keystream,
+    io_options: &IoOptions,
+) -> Vec<(usize, usize)> {
+    let phase = "interference";
+    let num_pairs = spot_blocks.len() / 2;
+    let mut block = Blocks::new(block_size, 1, drive.get_memory_alignment());
+    let mut expected_block = vec![0; block_size];
+    // Each pair takes two writes and a read.
+    let bar = io_options.progress_bar(num_pairs, "magenta", 3 * block_size);
+    let mut errors = ErrorLog::default();
+    bar.tick();
+    io_options.progress.phase(phase, num_pairs);
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.phase(phase, num_pairs);
+    }
+    log::info!("Starting phase {}: testing {} pairs", phase, num_pairs);
+    let mut throughput = Throughput::default();
+    let mut overwritten = Vec::new();
+    'pairs: for i in 0..num_pairs {
+        if io_options.is_interrupted() {
+            break;
+        }
+        let pair = [i, i + num_pairs];
+        // The two writes and the read of the pair are throttled together.
+        let mut pair_bytes = 0;
+        let mut pair_duration = Duration::ZERO;
+        for j in pair {
+            let offset = spot_blocks[j].num * block_size as u64;
+            keystream.fill(spot_blocks[j].num, block.block_mut(0));
+            match drive.write(offset, block.block(0)) {
+                Ok(duration) => {
+                    throughput.add(block_size, duration);
+                    pair_bytes += block_size;
+                    pair_duration += duration;
+                }
+                Err(err) => {
+                    errors.log(
+                        &bar,
+                        log::Level::Error,
+                        format!(
+                            "Write error at block {} (offset {}): {}",
+                            spot_blocks[j].idx, offset, err
+                        ),
+                    );
+                    if let Some(dashboard) = &io_options.dashboard {
+                        dashboard.block(
+                            spot_blocks[i].idx,
+                            pair_bytes,
+                            None,
+                            Some(BlockReport::WriteError),
+                        );
+                    }
+                    bar.inc(1);
+                    if device::ErrorKind::of(&err) == device::ErrorKind::Disconnected {
+                        break 'pairs;
+                    }
+                    io_options.throttle(i + 1, pair_bytes, pair_duration);
+                    continue 'pairs;
+                }
+            }
+        }
+        let offset = spot_blocks[i].num * block_size as u64;
+        let report = match drive.read(offset, block.block_mut(0)) {
+            Ok(duration) => {
+                throughput.add(block_size, duration);
+                pair_bytes += block_size;
+                pair_duration += duration;
+                // Only the part of the last block within the drive can hold data.
+                let len = (drive.get_size() - offset).min(block_size as u64) as usize;
+                keystream.fill(spot_blocks[i].num, &mut expected_block);
+                if block.block(0)[..len] != expected_block[..len] {
+                    overwritten.push((pair[0], pair[1]));
+                    Some(BlockReport::NoStorage)
+                } else {
+                    None
+                }
+            }
+            Err(err) => {
+                errors.log(
+                    &bar,
+                    log::Level::Error,
+                    format!(
+                        "Read error at block {} (offset {}): {}",
+                        spot_blocks[i].idx, offset, err
+                    ),
+                );
+                if device::ErrorKind::of(&err) == device::ErrorKind::Disconnected {
+                    break;
+                }
+                Some(BlockReport::ReadError)
+            }
+        };
+        io_options.throttle(i + 1, pair_bytes, pair_duration);
+        io_options
+            .progress
+            .block(phase, i + 1, num_pairs, offset, throughput.bytes_per_sec());
+        if let Some(dashboard) = &io_options.dashboard {
+            let duration = (report != Some(BlockReport::ReadError)).then_some(pair_duration);
+            dashboard.block(spot_blocks[i].idx, pair_bytes, duration, report);
+        }
+        bar.inc(1);
+    }
+    bar.finish();
+    errors.log_deferred();
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.end_phase();
+    }
+    log::info!("Finished phase {}", phase);
+    overwritten
+}
+
+/// Write each block in `spot_blocks` with the content generated by `keystream` and read it back
+/// right away, before writing the next one. If the test is interrupted, the remaining blocks are
+/// not tested.
+/// Returns the report of each block, in the order of `spot_blocks`: Unknown for the blocks not
+/// tested.
+fn immediate_verify(
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    block_size: usize,
+    keystream: &keystream::Keystream,
+    io_options: &IoOptions,
+) -> Vec<BlockReport> {
+    let phase = "immediate_verify";
+    let mut block = Blocks::new(block_size, 1, drive.get_memory_alignment());
+    let mut expected_block = vec![0; block_size];
+    let mut reports = vec![BlockReport::Unknown; spot_blocks.len()];
+    // Each block takes a write and a read.
+    let bar = io_options.progress_bar(spot_blocks.len(), "cyan", 2 * block_size);
+    let mut errors = ErrorLog::default();
+    bar.tick();
+    io_options.progress.phase(phase, spot_blocks.len());
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.phase(phase, spot_blocks.len());
+    }
+    log::info!(
+        "Starting phase {}: testing {} blocks",
+        phase,
+        spot_blocks.len()
+    );
+    let mut throughput = Throughput::default();
+    for (i, block_idx) in spot_blocks.iter().enumerate() {
+        if io_options.is_interrupted() {
+            break;
+        }
+        let offset = block_idx.num * block_size as u64;
+        keystream.fill(block_idx.num, &mut expected_block);
+        block.block_mut(0).copy_from_slice(&expected_block);
+        let result = drive
+            .write(offset, block.block(0))
+            .map_err(|err| (BlockReport::WriteError, "Write", err))
+            .and_then(|write_duration| {
+                throughput.add(block_size, write_duration);
+                drive
+                    .read(offset, block.block_mut(0))
+                    .map(|read_duration| (write_duration, read_duration))
+                    .map_err(|err| (BlockReport::ReadError, "Read", err))
+            });
+        let duration = match result {
+            Ok((write_duration, read_duration)) => {
+                throughput.add(block_size, read_duration);
+                // The write and the read of the block are throttled together.
+                io_options.throttle(i + 1, 2 * block_size, write_duration + read_duration);
+                // Only the part of the last block within the drive can hold data.
+                let len = (drive.get_size() - offset).min(block_size as u64) as usize;
+                reports[i] = if block.block(0)[..len] == expected_block[..len] {
+                    BlockReport::Validated
+                } else {
+                    BlockReport::NoStorage
+                };
+                Some(write_duration + read_duration)
+            }
+            Err((report, operation, err)) => {
+                errors.log(
+                    &bar,
+                    log::Level::Error,
+                    format!(
+                        "{} error at block {} (offset {}): {}",
+                        operation, block_idx.idx, offset, err
+                    ),
+                );
+                reports[i] = report;
+                if device::ErrorKind::of(&err) == device::ErrorKind::Disconnected {
+                    break;
+                }
+                None
             }
+        };
+        io_options.progress.block(
+            phase,
+            i + 1,
+            spot_blocks.len(),
+            offset,
+            throughput.bytes_per_sec(),
+        );
+        if let Some(dashboard) = &io_options.dashboard {
+            dashboard.block(
+                block_idx.idx,
+                2 * block_size,
+                duration,
+                Some(reports[i].clone()),
+            );
         }
         bar.inc(1);
     }
     bar.finish();
+    errors.log_deferred();
+    if let Some(dashboard) = &io_options.dashboard {
+        dashboard.end_phase();
+    }
+    log::info!("Finished phase {}", phase);
+    reports
+}
+
+/// Discard the blocks identified by `spot_blocks` on `drive`. Discard failures are reported but are
+/// not test failures.
+/// If the test is interrupted, the remaining blocks are not discarded.
+/// Returns the number of discarded blocks.
+fn discard_blocks(
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    block_size: usize,
+    io_options: &IoOptions,
+) -> usize {
+    let mut num_discarded = 0;
+    for block_idx in spot_blocks {
+        if io_options.is_interrupted() {
+            break;
+        }
+        let offset = block_idx.num * block_size as u64;
+        match drive.discard(offset, block_size as u64) {
+            Ok(true) => num_discarded += 1,
+            Ok(false) => {
+                log::warn!("The drive does not support discard, not discarding blocks");
+                break;
+            }
+            Err(err) => log::warn!(
+                "Discard error at block {} (offset {}): {:#}",
+                block_idx.idx,
+                offset,
+                err
+            ),
+        }
+    }
+    num_discarded
+}
+
+/// The I/O errors of a phase. The first errors are logged as they happen. Past that, logging each
+/// error would thrash the terminal redrawing the progress bar: they are only counted in the
+/// progress bar, and logged at the end of the phase.
+#[derive(Default)]
+struct ErrorLog {
+    count: usize,
+    /// The errors to log at the end of the phase.
+    deferred: Vec<(log::Level, String)>,
+}
+
+impl ErrorLog {
+    /// Log `message` at `level`, or defer it if too many errors were already logged. The error
+    /// count is shown as the message of `bar`.
+    fn log(&mut self, bar: &indicatif::ProgressBar, level: log::Level, message: String) {
+        self.count += 1;
+        if self.count <= MAX_IMMEDIATE_ERRORS {
+            bar.suspend(|| log::log!(level, "{}", message));
+        } else {
+            self.deferred.push((level, message));
+        }
+        bar.set_message(format!("errors: {}", self.count));
+    }
+
+    /// Log the deferred errors.
+    fn log_deferred(&mut self) {
+        if self.deferred.is_empty() {
+            return;
+        }
+        log::warn!(
+            "{} more errors were encountered in this phase:",
+            self.deferred.len()
+        );
+        for (level, message) in self.deferred.drain(..) {
+            log::log!(level, "{}", message);
+        }
+    }
+}
 
-    print_stats(&durations);
+/// Running throughput of the I/O operations of a phase.
+#[derive(Default)]
+struct Throughput {
+    bytes: usize,
+    duration: Duration,
+}
+
+impl Throughput {
+    /// Account for an I/O operation of `bytes` bytes, which took `duration`.
+    fn add(&mut self, bytes: usize, duration: Duration) {
+        self.bytes += bytes;
+        self.duration += duration;
+    }
+
+    /// Return the throughput in bytes per second, or 0 if no I/O operation was accounted for.
+    fn bytes_per_sec(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.duration.as_secs_f64()
+    }
 }
 
 #[derive(Clone, PartialEq)]
@@ -154,56 +1469,82 @@ enum IoError {
     None,
     ReadError,
     WriteError,
+    /// Two successive reads of the block returned different data.
+    Unstable,
+    /// The data read differs from the data written to the block.
+    Mismatch,
 }
 
 /// Structure holding the buffer for the blocks content.
 struct Blocks {
-    /// The buffer holding the blocks content. The blocks data starts at `start_offset` and the
-    /// blocks are stored in the order they are read/written (not in the order they are present on
-    /// the drive).
-    data: Vec<u8>,
+    /// The buffer holding the blocks content, aligned to multiples of the sector size as required
+    /// for O_DIRECT operations. The blocks are stored in the order they are read/written (not in
+    /// the order they are present on the drive).
+    data: aligned::AlignedBuffer,
     /// The errors encountered when reading/writing the blocks. The vector has one element per
     /// block.
     errors: Vec<IoError>,
+    /// The duration of the last successful read/write of each block. The vector has one element
+    /// per block.
+    durations: Vec<Option<Duration>>,
+    /// The wall-clock duration of the last read/write of all the blocks, including the time spent
+    /// outside of the I/O operations.
+    elapsed: Duration,
     /// The size of a block in bytes.
     block_size: usize,
-    /// The offset in `data` where the blocks data starts. This is used to align the buffer to
-    /// multiples of the sector size, required for O_DIRECT operations.
-    start_offset: usize,
     /// The number of blocks to test.
     num_blocks: usize,
+    /// The index of the block whose last read/write found the device disconnected, if any. The
+    /// following blocks were not read/written.
+    disconnected_at: Option<usize>,
+    /// Whether the blocks content is not kept: all the blocks share the same buffer, holding
+    /// the content of the block last read/written.
+    streaming: bool,
+    /// The CRC-32 of the content of each block last written, if recorded.
+    checksums: Option<Vec<u32>>,
 }
 
 impl Blocks {
     /// Create a new `Blocks` structure with `num_blocks` blocks of size `block_size` bytes.
     /// The buffer is aligned to multiple of `mem_align` bytes.
     fn new(block_size: usize, num_blocks: usize, mem_align: usize) -> Self {
-        // Align the beginning of the data stored in the buffer to multiples of `mem_align` bytes,
-        // as it is required for O_DIRECT operations.
-        // Using Rust's allocator_api would be a better solutions, but that feature is still
-        // available only on nightly builds.
-        let data = vec![0; num_blocks * block_size as usize + mem_align];
-        let mut start_offset = 0;
-        if mem_align > 0 && data.as_ptr() as usize % mem_align != 0 {
-            start_offset = mem_align - data.as_ptr() as usize % mem_align;
-        }
         Self {
-            data,
+            data: aligned::AlignedBuffer::new(num_blocks * block_size, mem_align),
             errors: vec![IoError::None; num_blocks],
+            durations: vec![None; num_blocks],
+            elapsed: Duration::ZERO,
             block_size,
-            start_offset,
             num_blocks,
+            disconnected_at: None,
+            streaming: false,
+            checksums: None,
+        }
+    }
+
+    /// Create a new `Blocks` structure with `num_blocks` blocks of size `block_size` bytes, whose
+    /// content is not kept, so that the buffer holds a single block.
+    /// The buffer is aligned to multiple of `mem_align` bytes.
+    fn new_streaming(block_size: usize, num_blocks: usize, mem_align: usize) -> Self {
+        Self {
+            errors: vec![IoError::None; num_blocks],
+            durations: vec![None; num_blocks],
+            num_blocks,
+            streaming: true,
+            ..Self::new(block_size, 1, mem_align)
         }
     }
 
     /// Return the offset in `data` where the block with index `i` starts.
     fn block_offset(&self, i: usize) -> usize {
-        self.start_offset + i * self.block_size
+        if self.streaming {
+            return 0;
+        }
+        i * self.block_size
     }
 
     /// Return the range in `data` where the block with index `i` is stored.
     fn block_range(&self, i: usize) -> Range<usize> {
-        self.block_offset(i)..self.block_offset(i + 1)
+        self.block_offset(i)..self.block_offset(i) + self.block_size
     }
 
     /// Return a reference to the block with index `i`.
@@ -217,9 +1558,9 @@ impl Blocks {
         &mut self.data[block_range]
     }
 
-    /// Return a mutable reference to the buffer holding the blocks data.
-    fn data_mut(&mut self) -> &mut [u8] {
-        &mut self.data
+    /// Return the durations of the successful reads/writes of the blocks.
+    fn io_durations(&self) -> Vec<Duration> {
+        self.durations.iter().flatten().copied().collect()
     }
 }
 
@@ -230,6 +1571,65 @@ struct BlockIdx {
     num: u64,
 }
 
+/// A phase of the test, selected with --phases.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Phase {
+    /// Read the original content of the tested blocks.
+    ReadOriginal,
+    /// Write random data to the tested blocks.
+    Write,
+    /// Read back the written blocks and verify them.
+    Verify,
+    /// Write back the original content of the tested blocks.
+    Restore,
+}
+
+/// The pattern written to every block by --wipe.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum WipePattern {
+    /// Random data, different for every block.
+    Random,
+    /// All bytes 0x00.
+    Zeros,
+    /// All bytes 0xFF.
+    Ones,
+}
+
+/// The characterization of a drive from the pattern of the blocks that can and cannot store data.
+enum DriveDiagnosis {
+    /// No tested block lost its data.
+    Genuine,
+    /// The blocks storing data are followed only by blocks that cannot store data: the drive
+    /// reports a larger size than its real capacity.
+    TruncatedFake,
+    /// Regions of blocks storing data are interleaved with regions of blocks that cannot, e.g.
+    /// real storage at the start and the end of the drive and fake storage in the middle.
+    ComplexFake {
+        /// The number of regions of consecutive blocks storing data.
+        good_regions: usize,
+    },
+}
+
+/// The test phase whose latencies are shown in the speed map.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SpeedMapPhase {
+    /// The reads verifying the written blocks, or reading the original blocks if the blocks are
+    /// not written.
+    Read,
+    /// The writes of random data.
+    Write,
+}
+
+/// The definition of the validated drive size.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum CapacityMetric {
+    /// The end of the highest validated block, such that all the previous blocks are also
+    /// validated.
+    Contiguous,
+    /// The sum of the drive areas represented by all the validated blocks.
+    Total,
+}
+
 /// Enumeration of the possible validation results for a block.
 #[derive(Clone, PartialEq)]
 enum BlockReport {
@@ -239,227 +1639,3950 @@ enum BlockReport {
     ReadSuccessful,
     WriteError,
     NoStorage,
+    Unstable,
+    /// The block was not tested, because its original content could not be read.
+    Skipped,
+    /// The block was not tested, because it is in a range excluded with --exclude.
+    Excluded,
+    /// The block was written, but not read back, because it was not sampled by --verify-fraction.
+    Unverified,
+}
+
+/// The symbols representing each block in the validation map.
+struct MapSymbols {
+    unknown: &'static str,
+    validated: &'static str,
+    read_error: &'static str,
+    read_successful: &'static str,
+    write_error: &'static str,
+    no_storage: &'static str,
+    unstable: &'static str,
+    skipped: &'static str,
+    excluded: &'static str,
+    unverified: &'static str,
+    /// The symbols of the latency buckets of the speed map, from the fastest to the slowest.
+    speed: [&'static str; 4],
 }
 
-/// Print the validation map to stdout, with header and legend.
-fn print_validation_map(validation_map: &Vec<BlockReport>, map_width: usize) {
-    println!("{}", console::style("\nValidation map:").bold());
-    for i in 0..validation_map.len() {
-        match validation_map[i] {
-            BlockReport::Validated => print!("{}", console::style("◼").green()),
-            BlockReport::ReadError => print!("{}", console::style("R").blue()),
-            BlockReport::ReadSuccessful => print!("{}", console::style("R").green()),
-            BlockReport::WriteError => print!("{}", console::style("W").yellow()),
-            BlockReport::NoStorage => print!("{}", console::style("✖").red()),
+/// The default symbols of the validation map.
+const UNICODE_MAP_SYMBOLS: MapSymbols = MapSymbols {
+    unknown: "?",
+    validated: "◼",
+    read_error: "R",
+    read_successful: "R",
+    write_error: "W",
+    no_storage: "✖",
+    unstable: "U",
+    skipped: "S",
+    excluded: "-",
+    unverified: "◻",
+    speed: ["▁", "▃", "▅", "█"],
+};
+
+/// The symbols of the validation map for terminals without good Unicode support. Unlike the
+/// default ones, they do not rely on colors to distinguish read errors from successful reads.
+const ASCII_MAP_SYMBOLS: MapSymbols = MapSymbols {
+    unknown: "?",
+    validated: ".",
+    read_error: "r",
+    read_successful: "+",
+    write_error: "w",
+    no_storage: "X",
+    unstable: "u",
+    skipped: "s",
+    excluded: "-",
+    unverified: "_",
+    speed: [".", ":", "o", "#"],
+};
+
+impl MapSymbols {
+    /// Return the symbols selected on the command line.
+    fn from_cli(cli: &TestOptions) -> &'static Self {
+        if cli.ascii_map {
+            &ASCII_MAP_SYMBOLS
+        } else {
+            &UNICODE_MAP_SYMBOLS
+        }
+    }
+
+    /// Return the symbol representing `report`.
+    fn symbol(&self, report: &BlockReport) -> &'static str {
+        match report {
+            BlockReport::Unknown => self.unknown,
+            BlockReport::Validated => self.validated,
+            BlockReport::ReadError => self.read_error,
+            BlockReport::ReadSuccessful => self.read_successful,
+            BlockReport::WriteError => self.write_error,
+            BlockReport::NoStorage => self.no_storage,
+            BlockReport::Unstable => self.unstable,
+            BlockReport::Skipped => self.skipped,
+            BlockReport::Excluded => self.excluded,
+            BlockReport::Unverified => self.unverified,
+        }
+    }
+
+    /// Return the symbol representing `report`, colored for the terminal.
+    fn styled_symbol(&self, report: &BlockReport) -> console::StyledObject<&'static str> {
+        let symbol = console::style(self.symbol(report));
+        match report {
+            BlockReport::Validated => symbol.green(),
+            BlockReport::ReadError => symbol.blue(),
+            BlockReport::ReadSuccessful => symbol.green(),
+            BlockReport::WriteError => symbol.yellow(),
+            BlockReport::NoStorage => symbol.red(),
+            BlockReport::Unstable => symbol.magenta(),
+            BlockReport::Skipped => symbol.cyan(),
+            BlockReport::Excluded => symbol.dim(),
+            BlockReport::Unverified => symbol.green().dim(),
             // We should never have an un unknown block in the validation map.
-            _ => print!("{}", console::style("?").white()),
+            BlockReport::Unknown => symbol.white(),
+        }
+    }
+
+    /// Return the symbol representing the latency `bucket` in the speed map, colored for the
+    /// terminal.
+    fn styled_speed_symbol(&self, bucket: usize) -> console::StyledObject<&'static str> {
+        let symbol = console::style(self.speed[bucket]);
+        match bucket {
+            0 => symbol.green(),
+            1 => symbol.yellow(),
+            2 => symbol.red(),
+            _ => symbol.magenta(),
+        }
+    }
+}
+
+/// Print the speed map to stdout using `symbols`, with header and legend: the latency of the I/O
+/// operation of each block in `phase`, laid out like the validation map of `map_len` blocks.
+/// `spot_blocks` maps the blocks tested in `phase` to their position in the map.
+fn print_speed_map(
+    phase: &PhaseResult,
+    spot_blocks: &[BlockIdx],
+    map_len: usize,
+    map_width: usize,
+    symbols: &MapSymbols,
+) {
+    let mut sorted_durations = phase.durations.clone();
+    sorted_durations.sort_unstable();
+    let Some(median) = sorted_durations.get(sorted_durations.len() / 2).copied() else {
+        return;
+    };
+    let bounds = SPEED_MAP_BUCKETS.map(|multiple| median.mul_f64(multiple));
+    let bucket = |duration: Duration| {
+        bounds
+            .iter()
+            .position(|bound| duration <= *bound)
+            .unwrap_or(bounds.len())
+    };
+
+    let idx_by_num = spot_blocks
+        .iter()
+        .map(|block_idx| (block_idx.num, block_idx.idx))
+        .collect::<HashMap<_, _>>();
+    let mut latencies = vec![None; map_len];
+    for (num, duration) in phase.block_nums.iter().zip(phase.block_durations.iter()) {
+        if let Some(idx) = idx_by_num.get(num) {
+            latencies[*idx] = *duration;
+        }
+    }
+
+    println!(
+        "{}",
+        console::style(format!("\nSpeed map ({}):", phase.name)).bold()
+    );
+    for (i, latency) in latencies.iter().enumerate() {
+        match latency {
+            Some(duration) => print!("{}", symbols.styled_speed_symbol(bucket(*duration))),
+            None => print!("{}", console::style(symbols.unknown).dim()),
         }
         if i % map_width == map_width - 1 {
             println!();
         }
     }
-    if validation_map.len() % map_width != 0 {
+    if !map_len.is_multiple_of(map_width) {
         println!();
     }
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
     println!(
-        "Legend: {} Validated   {} Read Error       {} Write Error",
-        console::style("◼").green(),
-        console::style("R").blue(),
-        console::style("W").yellow(),
-    );
-    println!(
-        "        {} No storage  {} Read Successful",
-        console::style("✖").red(),
-        console::style("R").green(),
+        "Legend: {} <= {:.3} ms  {} <= {:.3} ms  {} <= {:.3} ms  {} > {:.3} ms  {} Not measured",
+        symbols.styled_speed_symbol(0),
+        ms(bounds[0]),
+        symbols.styled_speed_symbol(1),
+        ms(bounds[1]),
+        symbols.styled_speed_symbol(2),
+        ms(bounds[2]),
+        symbols.styled_speed_symbol(3),
+        ms(bounds[2]),
+        console::style(symbols.unknown).dim(),
     );
 }
 
-/// Print statistics about the duration of I/O operations.
-fn print_stats(durations: &Vec<std::time::Duration>) {
-    if durations.is_empty() {
+/// Print the speed map of the phase selected with --speed-map, if any, among the `phases` of the
+/// test. Reads show the verification of the written blocks or, if the blocks were not verified,
+/// the reads of their original content.
+fn print_selected_speed_map(
+    cli: &TestOptions,
+    phases: &[PhaseResult],
+    spot_blocks: &[BlockIdx],
+    map_len: usize,
+) {
+    let Some(speed_map) = cli.speed_map else {
         return;
+    };
+    let phase = match speed_map {
+        SpeedMapPhase::Read => phases
+            .iter()
+            .rev()
+            .find(|phase| phase.name.starts_with("Random read"))
+            .or_else(|| phases.iter().find(|phase| phase.name == "Original read")),
+        SpeedMapPhase::Write => phases
+            .iter()
+            .rev()
+            .find(|phase| phase.name.starts_with("Write")),
+    };
+    match phase {
+        Some(phase) => print_speed_map(
+            phase,
+            spot_blocks,
+            map_len,
+            cli.map_width,
+            MapSymbols::from_cli(cli),
+        ),
+        None => log::warn!("No timings to show in the speed map"),
+    }
+}
+
+/// Print `validation_map` to stdout using `symbols`, under the header `title`, with legend.
+fn print_map(title: &str, validation_map: &[BlockReport], map_width: usize, symbols: &MapSymbols) {
+    println!("{}", console::style(format!("\n{}:", title)).bold());
+    for (i, report) in validation_map.iter().enumerate() {
+        print!("{}", symbols.styled_symbol(report));
+        if i % map_width == map_width - 1 {
+            println!();
+        }
+    }
+    if !validation_map.len().is_multiple_of(map_width) {
+        println!();
     }
-    let sum = durations.iter().sum::<std::time::Duration>();
-    let avg = sum / durations.len() as u32;
-    let variance = durations
-        .iter()
-        .map(|&duration| {
-            let diff = as_millis_f64(&duration) - as_millis_f64(&avg);
-            diff * diff
-        })
-        .sum::<f64>()
-        / durations.len() as f64;
-    let std_dev = variance.sqrt();
-    // CV is the Coefficient of Variation.
     println!(
-        "avg: {:.3} ms, stddev: {:.3} ms, CV: {:.3}",
-        as_millis_f64(&avg),
-        std_dev,
-        std_dev / as_millis_f64(&avg)
+        "Legend: {} Validated   {} Read Error       {} Write Error",
+        symbols.styled_symbol(&BlockReport::Validated),
+        symbols.styled_symbol(&BlockReport::ReadError),
+        symbols.styled_symbol(&BlockReport::WriteError),
     );
-
-    // print min and max duration from durations
-    let min = durations.iter().min().unwrap();
-    let max = durations.iter().max().unwrap();
     println!(
-        "min: {:.3} ms, max: {:.3} ms",
+        "        {} No storage  {} Read Successful  {} Unstable",
+        symbols.styled_symbol(&BlockReport::NoStorage),
+        symbols.styled_symbol(&BlockReport::ReadSuccessful),
+        symbols.styled_symbol(&BlockReport::Unstable),
+    );
+    if validation_map.contains(&BlockReport::Skipped) {
+        println!(
+            "        {} Skipped (original content unreadable)",
+            symbols.styled_symbol(&BlockReport::Skipped),
+        );
+    }
+    if validation_map.contains(&BlockReport::Excluded) {
+        println!(
+            "        {} Excluded (--exclude)",
+            symbols.styled_symbol(&BlockReport::Excluded),
+        );
+    }
+    if validation_map.contains(&BlockReport::Unverified) {
+        println!(
+            "        {} Written, not verified (--verify-fraction)",
+            symbols.styled_symbol(&BlockReport::Unverified),
+        );
+    }
+}
+
+/// Print the map of the immediate verification (--immediate-verify) to stdout using `symbols`,
+/// with the number of blocks read back correctly and the limits of such a verification.
+fn print_immediate_map(immediate_map: &[BlockReport], map_width: usize, symbols: &MapSymbols) {
+    print_map(
+        "Immediate verification map",
+        immediate_map,
+        map_width,
+        symbols,
+    );
+    let num_tested = immediate_map
+        .iter()
+        .filter(|report| {
+            !matches!(
+                report,
+                BlockReport::Unknown | BlockReport::Skipped | BlockReport::Excluded
+            )
+        })
+        .count();
+    let num_validated = immediate_map
+        .iter()
+        .filter(|report| **report == BlockReport::Validated)
+        .count();
+    let summary = format!(
+        "Immediate verification: {} of {} blocks read back correctly right after being written",
+        num_validated, num_tested
+    );
+    if num_validated == num_tested {
+        println!("{}", console::style(summary).green());
+    } else {
+        println!("{}", console::style(summary).red());
+    }
+    println!(
+        "{}",
+        console::style(
+            "Reading a block right after writing it checks the media and the write path, but not \
+             the real capacity: the drive cache may still hold the data."
+        )
+        .dim()
+    );
+}
+
+/// Return the path of an output file for the drive at `drive_path`. When testing multiple drives,
+/// the drive name is appended to the file stem of `path`, so that each drive has its own file.
+/// Likewise, when testing at multiple block sizes, the block size is appended.
+fn output_path_for_drive(cli: &TestOptions, path: &Path, drive_path: &str) -> PathBuf {
+    if !cli.multiple_drives && !cli.multiple_block_sizes {
+        return path.to_path_buf();
+    }
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    if cli.multiple_drives {
+        let drive_name = Path::new(drive_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        file_name.push(format!("-{}", drive_name));
+    }
+    if cli.multiple_block_sizes {
+        file_name.push(format!("-{}", size::format_size(cli.block_size())));
+    }
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// Export the validation map of the blocks in `spot_blocks` of `drive` to the files requested on
+/// the command line, if any.
+/// Errors are reported but do not stop the test, so that the original blocks can still be
+/// restored.
+fn export_validation_map(
+    cli: &TestOptions,
+    drive_path: &str,
+    drive: &dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    validation_map: &[BlockReport],
+) {
+    if let Some(map_out) = &cli.map_out {
+        let path = output_path_for_drive(cli, map_out, drive_path);
+        match map_export::write_map(&path, validation_map, cli.map_width) {
+            Ok(()) => println!("Validation map written to {}", path.display()),
+            Err(err) => log::error!("{:#}", err),
+        }
+    }
+    if let Some(csv_out) = &cli.csv_out {
+        let block_size = cli.block_size();
+        let mut block_results = spot_blocks
+            .iter()
+            .map(|block_idx| {
+                let offset = block_idx.num * block_size;
+                results::BlockResult {
+                    idx: block_idx.idx,
+                    block: block_idx.num,
+                    offset,
+                    size: (drive.get_size() - offset).min(block_size),
+                    report: validation_map[block_idx.idx].clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        block_results.sort_by_key(|result| result.idx);
+        // Retested blocks replace their earlier results, keeping the earlier validation map.
+        if let Some(retest_from) = &cli.retest_from {
+            match results::read_csv(retest_from) {
+                Ok(mut earlier_results) => {
+                    let new_reports = block_results
+                        .into_iter()
+                        .map(|result| (result.block, result.report))
+                        .collect::<HashMap<_, _>>();
+                    for result in &mut earlier_results {
+                        if let Some(report) = new_reports.get(&result.block) {
+                            result.report = report.clone();
+                        }
+                    }
+                    block_results = earlier_results;
+                }
+                Err(err) => log::error!("{:#}", err),
+            }
+        }
+        let path = output_path_for_drive(cli, csv_out, drive_path);
+        match results::write_csv(&path, &block_results) {
+            Ok(()) => println!("Results written to {}", path.display()),
+            Err(err) => log::error!("{:#}", err),
+        }
+    }
+}
+
+/// Return the random seed stored in `path`, or generate one and save it to `path` if the file does
+/// not exist.
+fn load_or_create_seed(path: &Path) -> Result<u64> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .context(format!("parsing seed file {:?}", path)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let seed = rand::random();
+            std::fs::write(path, format!("{}\n", seed))
+                .context(format!("writing seed file {:?}", path))?;
+            println!("Random seed {} saved to {}", seed, path.display());
+            Ok(seed)
+        }
+        Err(err) => Err(err).context(format!("reading seed file {:?}", path)),
+    }
+}
+
+/// Create the generator of the content written to the blocks: the content of `data_file` if
+/// provided, or random data from a seed taken from `rng` otherwise, with headers holding `run_id`
+/// if requested.
+fn new_keystream(
+    cli: &TestOptions,
+    data_file: Option<&std::rc::Rc<[u8]>>,
+    rng: &mut impl rand::RngCore,
+    run_id: u64,
+) -> keystream::Keystream {
+    let keystream = match data_file {
+        Some(data) => keystream::Keystream::from_data(data.clone()),
+        None => keystream::Keystream::new(rng),
+    };
+    if cli.block_header {
+        keystream.with_header(run_id)
+    } else {
+        keystream
+    }
+}
+
+/// Export the checksums of the blocks in `spot_blocks` of `drive`, written in `blocks`, to the file
+/// requested with --checksums-out, if any. Blocks that could not be written are not exported.
+/// Errors are reported but do not stop the test.
+fn export_checksums(
+    cli: &TestOptions,
+    drive_path: &str,
+    drive: &dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    blocks: &Blocks,
+) {
+    let (Some(checksums_out), Some(checksums)) = (&cli.checksums_out, &blocks.checksums) else {
+        return;
+    };
+    let block_size = cli.block_size();
+    let mut block_checksums = spot_blocks
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| blocks.durations[*i].is_some())
+        .map(|(i, block_idx)| {
+            let offset = block_idx.num * block_size;
+            results::BlockChecksum {
+                block: block_idx.num,
+                offset,
+                size: (drive.get_size() - offset).min(block_size),
+                crc32: checksums[i],
+            }
+        })
+        .collect::<Vec<_>>();
+    block_checksums.sort_by_key(|checksum| checksum.block);
+    let path = output_path_for_drive(cli, checksums_out, drive_path);
+    match results::write_checksums_csv(&path, &block_checksums) {
+        Ok(()) => println!("Checksums written to {}", path.display()),
+        Err(err) => log::error!("{:#}", err),
+    }
+}
+
+/// Export the per-block timings of the test `phases` to the file requested with --timings-out, if
+/// any.
+/// Errors are reported but do not stop the test.
+fn export_timings(cli: &TestOptions, drive_path: &str, phases: &[PhaseResult]) {
+    let Some(timings_out) = &cli.timings_out else {
+        return;
+    };
+    let block_size = cli.block_size();
+    let timings = phases
+        .iter()
+        .flat_map(|phase| {
+            phase
+                .block_durations
+                .iter()
+                .enumerate()
+                .filter_map(move |(seq, duration)| {
+                    duration.map(|duration| results::BlockTiming {
+                        phase: &phase.name,
+                        seq,
+                        block: phase.block_nums[seq],
+                        offset: phase.block_nums[seq] * block_size,
+                        duration,
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+    let path = output_path_for_drive(cli, timings_out, drive_path);
+    match results::write_timings_csv(&path, &timings) {
+        Ok(()) => println!("Timings written to {}", path.display()),
+        Err(err) => log::error!("{:#}", err),
+    }
+}
+
+/// Print statistics about the duration of the I/O operations of `phase`.
+fn print_stats(phase: &PhaseResult) {
+    let durations = &phase.durations;
+    if durations.is_empty() {
+        return;
+    }
+    let sum = durations.iter().sum::<std::time::Duration>();
+    let avg = sum / durations.len() as u32;
+    let variance = durations
+        .iter()
+        .map(|&duration| {
+            let diff = as_millis_f64(&duration) - as_millis_f64(&avg);
+            diff * diff
+        })
+        .sum::<f64>()
+        / durations.len() as f64;
+    let std_dev = variance.sqrt();
+    // CV is the Coefficient of Variation.
+    let cv = std_dev / as_millis_f64(&avg);
+    println!(
+        "avg: {:.3} ms, stddev: {:.3} ms, CV: {:.3}",
+        as_millis_f64(&avg),
+        std_dev,
+        cv
+    );
+
+    // print min and max duration from durations
+    let min = durations.iter().min().unwrap();
+    let max = durations.iter().max().unwrap();
+    println!(
+        "min: {:.3} ms, max: {:.3} ms",
         as_millis_f64(min),
         as_millis_f64(max)
     );
+
+    let mut sorted_durations = durations.clone();
+    sorted_durations.sort();
+    println!(
+        "p50: {:.3} ms, p90: {:.3} ms, p99: {:.3} ms, p99.9: {:.3} ms",
+        as_millis_f64(&percentile(&sorted_durations, 50.0)),
+        as_millis_f64(&percentile(&sorted_durations, 90.0)),
+        as_millis_f64(&percentile(&sorted_durations, 99.0)),
+        as_millis_f64(&percentile(&sorted_durations, 99.9)),
+    );
+    // Drives mixing a fast cache with much slower flash have some operations far slower than the
+    // average.
+    if durations.len() >= MIN_CV_SAMPLES && cv > HIGH_CV_THRESHOLD {
+        println!(
+            "{}",
+            console::style(format!(
+                "Inconsistent performance (CV {:.2} > {:.1}): the durations vary widely, which \
+                 may come from a cache hiding slower storage; --speed-map shows where the slow \
+                 blocks are",
+                cv, HIGH_CV_THRESHOLD
+            ))
+            .yellow()
+        );
+    }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// The result of a test phase, reading or writing the tested blocks.
+struct PhaseResult {
+    name: String,
+    /// The error of each block, in the order they were tested.
+    errors: Vec<IoError>,
+    /// The durations of the successful I/O operations.
+    durations: Vec<Duration>,
+    /// The duration of the I/O operation of each block, in the order they were tested, if
+    /// successful.
+    block_durations: Vec<Option<Duration>>,
+    /// The block number on the drive of each block, in the order they were tested.
+    block_nums: Vec<u64>,
+    /// The number of bytes successfully read/written.
+    bytes: u64,
+    /// The wall-clock duration of the whole phase.
+    elapsed: Duration,
+}
+
+impl PhaseResult {
+    /// Create a new `PhaseResult` with the errors and timings of the last read/write of `blocks`,
+    /// identified by `spot_blocks`.
+    fn new(name: String, blocks: &Blocks, spot_blocks: &[BlockIdx]) -> Self {
+        let durations = blocks.io_durations();
+        Self {
+            name,
+            errors: blocks.errors.clone(),
+            bytes: (durations.len() * blocks.block_size) as u64,
+            durations,
+            block_durations: blocks.durations.clone(),
+            block_nums: spot_blocks.iter().map(|block_idx| block_idx.num).collect(),
+            elapsed: blocks.elapsed,
+        }
+    }
+
+    /// Return the phase with its name replaced by `name`, for reporting.
+    fn named(self, name: String) -> Self {
+        Self { name, ..self }
+    }
+
+    /// Return the number of blocks that failed in the phase.
+    fn num_errors(&self) -> usize {
+        self.errors
+            .iter()
+            .filter(|error| **error != IoError::None)
+            .count()
+    }
+
+    /// Return the throughput in MB/s of the I/O operations, or `None` if there were none or they
+    /// took no measurable time.
+    fn throughput(&self) -> Option<f64> {
+        let total = self.durations.iter().sum::<Duration>();
+        if total.is_zero() {
+            return None;
+        }
+        Some(self.bytes as f64 / total.as_secs_f64() / 1_000_000.0)
+    }
+
+    /// Return the throughput in MB/s over the wall-clock duration of the phase, or `None` if the
+    /// phase took no measurable time, e.g. if it was interrupted before any I/O.
+    fn wall_throughput(&self) -> Option<f64> {
+        if self.elapsed.is_zero() {
+            return None;
+        }
+        Some(self.bytes as f64 / self.elapsed.as_secs_f64() / 1_000_000.0)
+    }
+}
+
+/// Print a table comparing the I/O timings of the test phases, so that changes in performance
+/// between them (e.g. writes slowing down once the drive cache is full, or reads of the written
+/// data being slower than reads of the original data) are easy to spot.
+/// The throughput is reported both for the time spent in I/O operations and for the wall-clock
+/// time of the phase, which includes the overhead of the tool (and any throttling), along with the
+/// number of blocks that failed in each phase.
+fn print_phase_comparison(phases: &[PhaseResult]) {
+    println!("{}", console::style("\nPhase comparison:").bold());
+    println!(
+        "{:<16} {:>10} {:>10} {:>12} {:>12} {:>8}",
+        "Phase", "avg (ms)", "p99 (ms)", "MB/s", "wall MB/s", "errors"
+    );
+    for phase in phases {
+        if phase.durations.is_empty() {
+            println!(
+                "{:<16} {:>10} {:>10} {:>12} {:>12} {:>8}",
+                phase.name,
+                "-",
+                "-",
+                "-",
+                "-",
+                phase.num_errors()
+            );
+            continue;
+        }
+        let sum = phase.durations.iter().sum::<Duration>();
+        let mut sorted_durations = phase.durations.clone();
+        sorted_durations.sort();
+        let format_mbps =
+            |mbps: Option<f64>| mbps.map_or(String::from("-"), |mbps| format!("{:.3}", mbps));
+        println!(
+            "{:<16} {:>10.3} {:>10.3} {:>12} {:>12} {:>8}",
+            phase.name,
+            as_millis_f64(&(sum / phase.durations.len() as u32)),
+            as_millis_f64(&percentile(&sorted_durations, 99.0)),
+            format_mbps(phase.throughput()),
+            format_mbps(phase.wall_throughput()),
+            phase.num_errors()
+        );
+    }
+}
+
+/// Return the `p`-th percentile (nearest-rank method) of `sorted_durations`, which must be sorted
+/// in ascending order and not empty.
+fn percentile(sorted_durations: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted_durations.len() as f64).ceil() as usize;
+    sorted_durations[rank.clamp(1, sorted_durations.len()) - 1]
+}
+
+/// Select `num_blocks` blocks spread evenly over a drive of `num_drive_blocks` blocks: the drive is
+/// divided in `num_blocks` areas, and the block best covering the end of each area is selected.
+/// `num_blocks` must be between 1 and `num_drive_blocks`.
+fn select_spot_blocks(num_blocks: usize, num_drive_blocks: u64) -> Vec<BlockIdx> {
+    // The end of each area is rounded to the nearest block with integer arithmetic: on large
+    // drives, the intermediate products neither fit in a u64 nor are exactly represented by a f64.
+    let num_blocks_u128 = num_blocks as u128;
+    (0..num_blocks)
+        .map(|i| {
+            let area_end = (2 * (i as u128 + 1) * num_drive_blocks as u128 + num_blocks_u128)
+                / (2 * num_blocks_u128);
+            BlockIdx {
+                idx: i,
+                num: area_end as u64 - 1,
+            }
+        })
+        .collect()
+}
+
+/// Return the number of blocks to test on a drive of `drive_size` bytes, so that each block
+/// represents at most `granularity` bytes of the drive. The result is never lower than the default
+/// number of blocks, and is capped to limit the test duration.
+fn auto_num_blocks(drive_size: u64, granularity: u64) -> usize {
+    let num_blocks = drive_size.div_ceil(granularity.max(1)) as usize;
+    num_blocks.clamp(DEFAULT_NUM_BLOCKS, MAX_AUTO_NUM_BLOCKS)
+}
+
+/// Return whether `report` is the failure of a tested block.
+fn is_failed_report(report: &BlockReport) -> bool {
+    matches!(
+        report,
+        BlockReport::ReadError
+            | BlockReport::WriteError
+            | BlockReport::NoStorage
+            | BlockReport::Unstable
+    )
+}
+
+/// Read the blocks that failed from the result file at `path`, written with --csv-out by an
+/// earlier test of a drive of `num_drive_blocks` blocks of `block_size` bytes.
+/// Returns the block number and the earlier report of each failed block.
+fn read_failed_blocks(
+    path: &Path,
+    block_size: u64,
+    num_drive_blocks: u64,
+) -> Result<Vec<(u64, BlockReport)>> {
+    let results = results::read_csv(path)?;
+    for result in &results {
+        if result.block >= num_drive_blocks || result.offset != result.block * block_size {
+            return Err(anyhow!(
+                "{:?}: block {} does not match the drive and block size (use the block size of \
+                 the earlier test)",
+                path,
+                result.block
+            ));
+        }
+    }
+    let failed = results
+        .into_iter()
+        .filter(|result| is_failed_report(&result.report))
+        .map(|result| (result.block, result.report))
+        .collect::<Vec<_>>();
+    if failed.is_empty() {
+        return Err(anyhow!("{:?} lists no failed blocks to retest", path));
+    }
+    Ok(failed)
+}
+
+/// Read the list of blocks to test from the file at `path`, for a drive of `num_drive_blocks`
+/// blocks of `block_size` bytes. Each line contains either a block number or a byte offset prefixed
+/// with '@', which must be aligned to the block size.
+/// Returns the sorted list of block numbers, without duplicates.
+fn read_block_list(path: &Path, block_size: u64, num_drive_blocks: u64) -> Result<Vec<u64>> {
+    let content =
+        std::fs::read_to_string(path).context(format!("reading block list {:?}", path))?;
+    let mut block_nums = Vec::new();
+    for (line_num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let block_num = if let Some(offset) = line.strip_prefix('@') {
+            let offset: u64 = offset.trim().parse().context(format!(
+                "{:?} line {}: invalid offset {:?}",
+                path,
+                line_num + 1,
+                line
+            ))?;
+            if !offset.is_multiple_of(block_size) {
+                return Err(anyhow!(
+                    "{:?} line {}: offset {} is not a multiple of the block size ({} bytes)",
+                    path,
+                    line_num + 1,
+                    offset,
+                    block_size
+                ));
+            }
+            offset / block_size
+        } else {
+            line.parse().context(format!(
+                "{:?} line {}: invalid block number {:?}",
+                path,
+                line_num + 1,
+                line
+            ))?
+        };
+        if block_num >= num_drive_blocks {
+            return Err(anyhow!(
+                "{:?} line {}: block {} is beyond the end of the drive ({} blocks)",
+                path,
+                line_num + 1,
+                block_num,
+                num_drive_blocks
+            ));
+        }
+        block_nums.push(block_num);
+    }
+    block_nums.sort_unstable();
+    block_nums.dedup();
+    if block_nums.is_empty() {
+        return Err(anyhow!("{:?} does not list any block", path));
+    }
+    Ok(block_nums)
+}
+
+/// Return the number of read/write passes over the tested blocks performed by the test.
+fn num_passes(cli: &TestOptions) -> usize {
+    if cli.read_only {
+        return 1;
+    }
+    let read_original = !cli.no_read_original;
+    let restore_original = read_original && !cli.no_restore_original;
+    let verify_reads = if cli.no_verify {
+        0
+    } else if cli.double_read {
+        2
+    } else {
+        1
+    };
+    read_original as usize + cli.repeat as usize * (1 + verify_reads) + restore_original as usize
+}
+
+/// Estimate the duration of the whole test of `spot_blocks` on `drive`, by timing the read of a
+/// few of them. Writes are assumed to take as long as reads, and the throttling options are taken
+/// into account.
+/// Returns `None` if none of the calibration reads succeeded, or an error if the number of blocks
+/// or passes is too large to multiply a Duration by.
+fn estimate_duration(
+    cli: &TestOptions,
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    io_options: &IoOptions,
+) -> Result<Option<Duration>> {
+    let to_u32 = |count: usize| {
+        u32::try_from(count).context(format!(
+            "{} is too large to estimate the test duration",
+            count
+        ))
+    };
+    let block_size = cli.block_size() as usize;
+    let num_calibration = CALIBRATION_BLOCKS.min(spot_blocks.len());
+    let mut buffer = Blocks::new(block_size, 1, drive.get_memory_alignment());
+    let mut durations = Vec::with_capacity(num_calibration);
+    for i in 0..num_calibration {
+        // Spread the calibration reads over the tested blocks.
+        let block_idx = &spot_blocks[i * spot_blocks.len() / num_calibration];
+        match drive.read(block_idx.num * block_size as u64, buffer.block_mut(0)) {
+            Ok(duration) => durations.push(duration),
+            Err(err) => log::debug!(
+                "Calibration read of block {} failed: {}",
+                block_idx.idx,
+                err
+            ),
+        }
+    }
+    if durations.is_empty() {
+        return Ok(None);
+    }
+    let mut block_duration = durations.iter().sum::<Duration>() / to_u32(durations.len())?;
+    if let Some(max_bytes_per_sec) = io_options.max_bytes_per_sec {
+        block_duration = block_duration.max(Duration::from_secs_f64(
+            block_size as f64 / max_bytes_per_sec,
+        ));
+    }
+    let mut pass_duration = block_duration * to_u32(spot_blocks.len())?;
+    if let Some((every, pause)) = io_options.pause {
+        if let Some(num_pauses) = spot_blocks.len().checked_div(every) {
+            pass_duration += pause * to_u32(num_pauses)?;
+        }
+    }
+    Ok(Some(pass_duration * to_u32(num_passes(cli))?))
+}
+
+/// Read `num_warmup` random blocks of `drive`, among its first `num_drive_blocks`, and write back
+/// their content unless `read_only`, so that the following phases measure the steady-state
+/// performance of the drive rather than its burst cache.
+/// The blocks are chosen with their own random generator, so that a warmup does not change the
+/// blocks tested and the data written with a given --seed.
+/// Errors are logged and do not stop the warmup.
+fn warm_up(
+    drive: &mut dyn device::Device,
+    num_drive_blocks: u64,
+    num_warmup: usize,
+    block_size: usize,
+    read_only: bool,
+    io_options: &IoOptions,
+) {
+    let mut rng = rand::thread_rng();
+    let mut buffer = Blocks::new(block_size, 1, drive.get_memory_alignment());
+    let bar = io_options.progress_bar(num_warmup, "cyan", block_size);
+    for _ in 0..num_warmup {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let offset = rng.gen_range(0..num_drive_blocks) * block_size as u64;
+        let result = drive.read(offset, buffer.block_mut(0)).and_then(|_| {
+            if read_only {
+                return Ok(Duration::ZERO);
+            }
+            drive.write(offset, buffer.block(0))
+        });
+        if let Err(err) = result {
+            log::warn!("Warmup at offset {} failed: {:#}", offset, err);
+        }
+        bar.inc(1);
+    }
+    bar.finish();
+}
+
+/// Format `duration` in hours, minutes and seconds.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+/// Parse `value` as a fraction: a number greater than 0 and at most 1.
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("invalid fraction {:?}: it must be a number", value))?;
+    if fraction > 0.0 && fraction <= 1.0 {
+        Ok(fraction)
+    } else {
+        Err(format!(
+            "invalid fraction {:?}: it must be greater than 0 and at most 1",
+            value
+        ))
+    }
+}
+
+/// Parse `value` as a throughput in MB/s: a finite number greater than 0.
+fn parse_throughput(value: &str) -> Result<f64, String> {
+    let throughput = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("invalid throughput {:?}: it must be a number", value))?;
+    if throughput.is_finite() && throughput > 0.0 {
+        Ok(throughput)
+    } else {
+        Err(format!(
+            "invalid throughput {:?}: it must be greater than 0",
+            value
+        ))
+    }
+}
+
+/// Parse `value` as a duration: a number of seconds, optionally followed by the unit `s`, `m`
+/// (minutes), `h` (hours) or `d` (days).
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration {:?}: it must start with a number", value))?;
+    let multiplier = match suffix.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration {:?}: unknown unit {:?}",
+                value, suffix
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {:?}: too large", value))
+}
+
+/// Read the blocks identified by `spot_blocks` from `drive` over and over for `duration`, at least
+/// once, printing a status line with the errors and reconnections so far and the throughput of the
+/// last pass every SOAK_STATUS_INTERVAL, and a summary of the blocks failing in every pass or only
+/// in some of them at the end.
+/// The soak stops early if the test is interrupted or the drive disconnects.
+/// Returns the blocks, with the errors of any pass and the durations of the last read of each
+/// block, and the result of the last pass, with the errors of any pass. Only the timings of the
+/// last pass are kept, so that a long soak does not accumulate them in memory.
+fn soak(
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    block_size: usize,
+    io_options: &IoOptions,
+    duration: Duration,
+) -> (Blocks, PhaseResult) {
+    let pass_options = IoOptions {
+        quiet: true,
+        ..io_options.clone()
+    };
+    let mut blocks =
+        Blocks::new_streaming(block_size, spot_blocks.len(), drive.get_memory_alignment());
+    // The number of passes in which each block failed, by block index. Excluded blocks are not in
+    // spot_blocks, but still have an index.
+    let num_indices = spot_blocks
+        .iter()
+        .map(|block_idx| block_idx.idx + 1)
+        .max()
+        .unwrap_or(0);
+    let mut failure_counts = vec![0; num_indices];
+    let mut num_passes = 0;
+    let reconnections_before = drive.reconnections();
+    let start = Instant::now();
+    let mut last_status = start;
+    let mut result = loop {
+        let (pass_blocks, pass_result) = read_blocks(
+            drive,
+            "soak_read",
+            spot_blocks,
+            block_size,
+            &pass_options,
+            false,
+            Some(Expected::Any),
+        );
+        num_passes += 1;
+        for (i, block_idx) in spot_blocks.iter().enumerate() {
+            if pass_blocks.errors[i] != IoError::None {
+                blocks.errors[i] = pass_blocks.errors[i].clone();
+                failure_counts[block_idx.idx] += 1;
+            }
+            if pass_blocks.durations[i].is_some() {
+                blocks.durations[i] = pass_blocks.durations[i];
+            }
+        }
+        blocks.disconnected_at = pass_blocks.disconnected_at;
+        let done = start.elapsed() >= duration
+            || io_options.is_interrupted()
+            || blocks.disconnected_at.is_some();
+        if done || last_status.elapsed() >= SOAK_STATUS_INTERVAL {
+            last_status = Instant::now();
+            println!(
+                "Soak {} / {}: {} passes, {} read errors ({} in the last pass), {} reconnections, \
+                 {:.3} MB/s",
+                format_duration(start.elapsed()),
+                format_duration(duration),
+                num_passes,
+                failure_counts.iter().sum::<usize>(),
+                pass_result.num_errors(),
+                drive.reconnections() - reconnections_before,
+                pass_result.throughput().unwrap_or_default()
+            );
+        }
+        if done {
+            break pass_result;
+        }
+    };
+    blocks.elapsed = start.elapsed();
+    result.errors.clone_from(&blocks.errors);
+    print_stats(&result);
+    if num_passes > 1 {
+        print_repeat_summary(&failure_counts, num_passes);
+    }
+    (blocks, result)
+}
+
+/// Write back the original content `orig_data` of the blocks identified by `spot_blocks`, and
+/// report the blocks that could not be restored. If `verify` is set, read back the restored blocks
+/// and check that they hold their original content.
+fn restore_original(
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    orig_data: &mut Blocks,
+    verify: bool,
+    io_options: &IoOptions,
+) {
+    println!("{}", console::style("\nWriting original blocks").bold());
+    write_blocks(
+        drive,
+        "restore_original",
+        spot_blocks,
+        orig_data,
+        None,
+        &io_options.uninterruptible(),
+    );
+    let num_restored = spot_blocks
+        .len()
+        .min(orig_data.disconnected_at.unwrap_or(usize::MAX));
+    let mut failed: Vec<u64> = (0..spot_blocks.len())
+        .filter(|&i| i >= num_restored || orig_data.errors[i] == IoError::WriteError)
+        .map(|i| spot_blocks[i].num * orig_data.block_size as u64)
+        .collect();
+
+    if verify && num_restored > 0 {
+        // Blocks whose original content could not be read were not written back.
+        let restored: Vec<usize> = (0..num_restored)
+            .filter(|&i| orig_data.errors[i] == IoError::None)
+            .collect();
+        let verify_blocks: Vec<BlockIdx> = restored
+            .iter()
+            .map(|&i| BlockIdx {
+                idx: spot_blocks[i].idx,
+                num: spot_blocks[i].num,
+            })
+            .collect();
+        let checksums: Vec<u32> = restored
+            .iter()
+            .map(|&i| {
+                // Only the part of the last block within the drive is stored.
+                let offset = spot_blocks[i].num * orig_data.block_size as u64;
+                let len = (drive.get_size() - offset).min(orig_data.block_size as u64) as usize;
+                checksum::crc32(&orig_data.block(i)[..len])
+            })
+            .collect();
+        println!("{}", console::style("\nVerifying original blocks").bold());
+        let (read_back, _) = read_blocks(
+            drive,
+            "verify_restore",
+            &verify_blocks,
+            orig_data.block_size,
+            &io_options.uninterruptible(),
+            false,
+            Some(Expected::Checksums(&checksums)),
+        );
+        failed.extend(
+            verify_blocks
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| read_back.errors[*j] != IoError::None)
+                .map(|(_, block_idx)| block_idx.num * orig_data.block_size as u64),
+        );
+        failed.sort_unstable();
+        failed.dedup();
+        if failed.is_empty() {
+            println!(
+                "{}",
+                console::style("Original content of all blocks restored and verified").green()
+            );
+        }
+    }
+
+    if !failed.is_empty() {
+        log::error!(
+            "The original content of {} blocks could not be restored",
+            failed.len()
+        );
+        println!(
+            "{}",
+            console::style(format!(
+                "\nRESTORE FAILED: the original data of {} blocks may be lost, at offsets:",
+                failed.len()
+            ))
+            .red()
+            .bold()
+        );
+        for offset in failed.iter().take(MAX_LISTED_RESTORE_FAILURES) {
+            println!("  {}", console::style(offset).red());
+        }
+        if failed.len() > MAX_LISTED_RESTORE_FAILURES {
+            println!(
+                "  {}",
+                console::style(format!(
+                    "... and {} more",
+                    failed.len() - MAX_LISTED_RESTORE_FAILURES
+                ))
+                .red()
+            );
+        }
+    }
+}
+
+/// Find where the storage of `drive` ends, between the block number `last_good` (the highest
+/// validated block, if any) and the block number `first_bad` (the following failed block): test
+/// BREAKPOINT_PROBES blocks evenly spaced between them with the content generated by `keystream`,
+/// restoring their original content afterwards, and repeat between the highest validated probe
+/// and the following failed one, until no block is left between them.
+/// Returns the number of the last block storing data, if any. If the test is interrupted, the
+/// highest validated block found so far is returned.
+fn find_breakpoint(
+    drive: &mut dyn device::Device,
+    cli: &TestOptions,
+    mut last_good: Option<u64>,
+    mut first_bad: u64,
+    keystream: &keystream::Keystream,
+    io_options: &IoOptions,
+) -> Option<u64> {
+    let block_size = cli.block_size();
+    loop {
+        let start = last_good.map_or(0, |num| num + 1);
+        let interval = first_bad - start;
+        if interval == 0 || io_options.is_interrupted() {
+            return last_good;
+        }
+        let num_probes = interval.min(BREAKPOINT_PROBES);
+        let probes = (0..num_probes)
+            .map(|i| BlockIdx {
+                idx: i as usize,
+                num: start + i * interval / num_probes,
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{}",
+            console::style(format!(
+                "\nSearching the end of the storage between offsets {} and {}",
+                start * block_size,
+                first_bad * block_size
+            ))
+            .bold()
+        );
+        let orig_data = (!cli.no_read_original).then(|| {
+            read_blocks(
+                drive,
+                "breakpoint_read_original",
+                &probes,
+                block_size as usize,
+                io_options,
+                false,
+                None,
+            )
+            .0
+        });
+        let mut probe_blocks = Blocks::new_streaming(
+            block_size as usize,
+            probes.len(),
+            drive.get_memory_alignment(),
+        );
+        if let Some(orig_data) = &orig_data {
+            // Blocks whose original content could not be read are not written.
+            probe_blocks.errors.clone_from(&orig_data.errors);
+        }
+        write_blocks(
+            drive,
+            "breakpoint_write",
+            &probes,
+            &mut probe_blocks,
+            Some(keystream),
+            io_options,
+        );
+        if cli.drop_caches {
+            if let Err(err) = drive.flush_caches() {
+                log::warn!("Cannot drop the caches of the drive: {:#}", err);
+            }
+        }
+        let (read_back, _) = read_blocks(
+            drive,
+            "breakpoint_verify",
+            &probes,
+            block_size as usize,
+            io_options,
+            false,
+            Some(Expected::Content(keystream)),
+        );
+        let interrupted = io_options.is_interrupted();
+        if let Some(mut orig_data) = orig_data {
+            restore_original(
+                drive,
+                &probes,
+                &mut orig_data,
+                cli.verify_restore,
+                io_options,
+            );
+        }
+        // Blocks not tested because of the interruption are neither validated nor failed.
+        if interrupted {
+            return last_good;
+        }
+        match (0..probes.len()).find(|&i| {
+            probe_blocks.errors[i] != IoError::None || read_back.errors[i] != IoError::None
+        }) {
+            Some(0) => first_bad = probes[0].num,
+            Some(i) => {
+                last_good = Some(probes[i - 1].num);
+                first_bad = probes[i].num;
+            }
+            None => last_good = probes.last().map(|probe| probe.num),
+        }
+    }
+}
+
+/// Stop the test after the user interrupted it: print the partial validation map, write back the
+/// original blocks content in `orig_data_option` (if any) and return an error.
+fn stop_interrupted(
+    drive: &mut dyn device::Device,
+    spot_blocks: &[BlockIdx],
+    validation_map: &[BlockReport],
+    cli: &TestOptions,
+    orig_data_option: Option<Blocks>,
+    io_options: &IoOptions,
+) -> Result<TestOutcome> {
+    print_map(
+        "Validation map",
+        validation_map,
+        cli.map_width,
+        MapSymbols::from_cli(cli),
+    );
+    if let Some(mut orig_data) = orig_data_option {
+        restore_original(
+            drive,
+            spot_blocks,
+            &mut orig_data,
+            cli.verify_restore,
+            io_options,
+        );
+    }
+    println!(
+        "\n{}",
+        console::style("INCOMPLETE: test interrupted")
+            .yellow()
+            .bold()
+    );
+    Err(anyhow!("Test interrupted"))
+}
+
+/// Stop the test after the drive was disconnected while testing the block with index `i` in
+/// `spot_blocks`: print the partial validation map and return an error. The original blocks
+/// content cannot be restored.
+fn stop_disconnected(
+    spot_blocks: &[BlockIdx],
+    i: usize,
+    validation_map: &[BlockReport],
+    map_width: usize,
+    symbols: &MapSymbols,
+    has_orig_data: bool,
+) -> Result<TestOutcome> {
+    print_map("Validation map", validation_map, map_width, symbols);
+    if has_orig_data {
+        log::error!("The original content of the tested blocks could not be restored");
+    }
+    println!(
+        "\n{}",
+        console::style("FAIL: drive disconnected, test aborted")
+            .red()
+            .bold()
+    );
+    Err(anyhow!(
+        "Drive disconnected at block {}, test aborted",
+        spot_blocks[i].idx
+    ))
+}
+
+/// Record the `report` of the block with index `idx` in the validation map, in a test pass.
+/// Failures are kept across passes, so that the map shows any block that ever failed, and are
+/// counted in `failure_counts`.
+fn record_pass_report(
+    validation_map: &mut [BlockReport],
+    failure_counts: &mut [usize],
+    idx: usize,
+    report: BlockReport,
+) {
+    if report != BlockReport::Validated {
+        failure_counts[idx] += 1;
+        validation_map[idx] = report;
+    } else if failure_counts[idx] == 0 {
+        validation_map[idx] = report;
+    }
+}
+
+/// Print the number of blocks that failed in every one of the `num_passes` passes, and list the
+/// marginal blocks, which failed only in some of them, with their failure counts.
+fn print_repeat_summary(failure_counts: &[usize], num_passes: u32) {
+    let num_passes = num_passes as usize;
+    println!("{}", console::style("\nRepeated passes:").bold());
+    println!(
+        "Blocks failing in every pass: {}",
+        failure_counts
+            .iter()
+            .filter(|count| **count == num_passes)
+            .count()
+    );
+    let marginal = failure_counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0 && **count < num_passes)
+        .collect::<Vec<_>>();
+    println!(
+        "Marginal blocks (failing in some passes): {}",
+        marginal.len()
+    );
+    for (idx, count) in marginal {
+        println!(
+            "  {} {}: failed {} of {} passes",
+            console::style("Block").yellow(),
+            idx,
+            count,
+            num_passes
+        );
+    }
+}
+
+/// Return the total validated size of a drive of `drive_size` bytes, tested on `spot_blocks` of
+/// `block_size` bytes with the results in `validation_map`: the sum of the drive areas represented
+/// by the validated blocks, each area spanning from the end of the previous tested block to the
+/// end of the block.
+fn total_validated_size(
+    spot_blocks: &[BlockIdx],
+    validation_map: &[BlockReport],
+    block_size: u64,
+    drive_size: u64,
+) -> u64 {
+    // The end offset of each tested block, in the order of the validation map.
+    let mut block_ends = vec![0; validation_map.len()];
+    for b in spot_blocks.iter() {
+        block_ends[b.idx] = ((b.num + 1) * block_size).min(drive_size);
+    }
+    let mut area_start = 0;
+    let mut total = 0;
+    for (report, block_end) in validation_map.iter().zip(block_ends) {
+        if *report == BlockReport::Validated {
+            total += block_end - area_start;
+        }
+        area_start = block_end;
+    }
+    total
+}
+
+/// Classify the drive from the pattern of validated blocks and blocks that cannot store data in
+/// `validation_map`. Blocks with I/O errors and untested blocks do not tell whether the drive is
+/// fake and are ignored.
+fn diagnose_drive(validation_map: &[BlockReport]) -> DriveDiagnosis {
+    let mut good_regions = 0;
+    let mut prev_good = None;
+    let mut bad_found = false;
+    for report in validation_map {
+        let good = match report {
+            BlockReport::Validated => true,
+            BlockReport::NoStorage | BlockReport::Unstable => false,
+            _ => continue,
+        };
+        if good && prev_good != Some(true) {
+            good_regions += 1;
+        }
+        bad_found |= !good;
+        prev_good = Some(good);
+    }
+    if !bad_found {
+        DriveDiagnosis::Genuine
+    } else if good_regions > 1 || (good_regions == 1 && prev_good == Some(true)) {
+        DriveDiagnosis::ComplexFake { good_regions }
+    } else {
+        DriveDiagnosis::TruncatedFake
+    }
+}
+
+/// Print how the blocks in `spot_blocks`, retested with --retest-from, compare with their
+/// `previous_reports`: the failures found again are persistent, the others transient.
+fn print_retest_summary(
+    spot_blocks: &[BlockIdx],
+    previous_reports: &[BlockReport],
+    validation_map: &[BlockReport],
+    block_size: u64,
+) {
+    // A read-only retest cannot tell whether a block that could not store data can now, as its
+    // content is not verified.
+    let is_unverified = |block_idx: &&BlockIdx| {
+        validation_map[block_idx.idx] == BlockReport::ReadSuccessful
+            && previous_reports[block_idx.idx] == BlockReport::NoStorage
+    };
+    let mut transient = spot_blocks
+        .iter()
+        .filter(|block_idx| {
+            matches!(
+                validation_map[block_idx.idx],
+                BlockReport::Validated | BlockReport::ReadSuccessful
+            )
+        })
+        .filter(|block_idx| !is_unverified(block_idx))
+        .collect::<Vec<_>>();
+    transient.sort_by_key(|block_idx| block_idx.num);
+    let num_persistent = spot_blocks
+        .iter()
+        .filter(|block_idx| is_failed_report(&validation_map[block_idx.idx]))
+        .count();
+    println!(
+        "{}: {} of {} earlier failures persistent, {} transient",
+        console::style("Retest").bold(),
+        num_persistent,
+        spot_blocks.len(),
+        transient.len()
+    );
+    let num_unverified = spot_blocks.iter().filter(is_unverified).count();
+    if num_unverified > 0 {
+        println!(
+            "  {} blocks that could not store data were read but not verified",
+            num_unverified
+        );
+    }
+    for block_idx in transient {
+        println!(
+            "  {}",
+            console::style(format!(
+                "offset {}: {} before, now passed",
+                block_idx.num * block_size,
+                results::report_name(&previous_reports[block_idx.idx])
+            ))
+            .yellow()
+        );
+    }
+}
+
+/// Print the characterization of the drive from `validation_map`.
+fn print_diagnosis(validation_map: &[BlockReport]) {
+    let diagnosis = match diagnose_drive(validation_map) {
+        DriveDiagnosis::Genuine => console::style("genuine".to_string()).green(),
+        DriveDiagnosis::TruncatedFake => console::style(
+            "simple truncated fake (real storage at the start, then no storage)".to_string(),
+        )
+        .red(),
+        DriveDiagnosis::ComplexFake { good_regions } => console::style(format!(
+            "complex fake ({} regions of real storage interleaved with fake storage)",
+            good_regions
+        ))
+        .red(),
+    };
+    println!("{}: {}", console::style("Diagnosis").bold(), diagnosis);
+}
+
+/// Print the final verdict of the test of a drive of `drive_size` bytes, from its `validation_map`
+/// and its validated size (if it could be estimated): FAIL if the validated size is below
+/// `min_capacity`, PASS if all the blocks not excluded were validated, FAIL if some blocks could
+/// not store data, or a warning if some blocks had I/O errors.
+fn print_verdict(
+    validation_map: &[BlockReport],
+    validated_size_option: Option<u64>,
+    drive_size: u64,
+    min_capacity: Option<u64>,
+) {
+    let num_no_storage = validation_map
+        .iter()
+        .filter(|report| **report == BlockReport::NoStorage)
+        .count();
+    let num_errors = validation_map
+        .iter()
+        .filter(|report| {
+            !matches!(
+                report,
+                BlockReport::Validated | BlockReport::Excluded | BlockReport::Unverified
+            )
+        })
+        .count();
+    let verdict = if let Some((validated_size, min_capacity)) = validated_size_option
+        .zip(min_capacity)
+        .filter(|(validated_size, min_capacity)| validated_size < min_capacity)
+    {
+        console::style(format!(
+            "FAIL: validated capacity below required minimum ({} < {})",
+            size::format_capacity(validated_size),
+            size::format_capacity(min_capacity)
+        ))
+        .red()
+    } else if num_errors == 0 {
+        console::style(format!(
+            "PASS: drive capacity verified ({})",
+            size::format_capacity(drive_size)
+        ))
+        .green()
+    } else if num_no_storage > 0 {
+        console::style(match validated_size_option {
+            Some(validated_size) => format!(
+                "FAIL: real capacity is only {}, advertised {} (fake drive)",
+                size::format_capacity(validated_size),
+                size::format_capacity(drive_size)
+            ),
+            None => format!(
+                "FAIL: {} of {} tested blocks cannot store data (fake drive)",
+                num_no_storage,
+                validation_map.len()
+            ),
+        })
+        .red()
+    } else {
+        console::style(format!("WARNING: I/O errors on {} blocks", num_errors)).yellow()
+    };
+    println!("\n{}", verdict.bold());
+}
+
+/// Print the verdict of a test ending after reading the original blocks, from its
+/// `validation_map`: a read-only test, or a test aborted because some blocks could not be read.
+fn print_read_verdict(validation_map: &[BlockReport], read_only: bool) {
+    let count = |target: BlockReport| {
+        validation_map
+            .iter()
+            .filter(|report| **report == target)
+            .count()
+    };
+    let num_mismatches = count(BlockReport::NoStorage);
+    let num_read_errors = count(BlockReport::ReadError);
+    let verdict = if num_mismatches > 0 {
+        console::style(format!(
+            "FAIL: {} blocks do not match their recorded checksums",
+            num_mismatches
+        ))
+        .red()
+    } else if num_read_errors > 0 && !read_only {
+        console::style(format!(
+            "FAIL: {} blocks could not be read, test aborted before writing",
+            num_read_errors
+        ))
+        .red()
+    } else if num_read_errors > 0 {
+        console::style(format!(
+            "FAIL: {} blocks could not be read",
+            num_read_errors
+        ))
+        .red()
+    } else if count(BlockReport::Validated) > 0 {
+        console::style(format!(
+            "PASS: {} blocks match their recorded checksums",
+            count(BlockReport::Validated)
+        ))
+        .green()
+    } else {
+        console::style(format!(
+            "PASS: {} blocks read without errors (capacity not verified)",
+            count(BlockReport::ReadSuccessful)
+        ))
+        .green()
+    };
+    println!("\n{}", verdict.bold());
+}
+
+/// Print whether the usable size found by f3 in `report` agrees with `validated_size`, measured in
+/// the same way by valixdrive, within `resolution`: the size of the area of the drive represented
+/// by each tested block.
+fn print_f3_comparison(report: &f3::Report, validated_size: u64, resolution: u64) {
+    println!("{}", console::style("\nComparison with f3:").bold());
+    if let Some(verdict) = &report.verdict {
+        println!("{}: {}", report.tool.name(), verdict);
+    }
+    let (f3_label, label) = match report.tool {
+        f3::Tool::Probe => ("f3probe usable size", "Contiguous validated size"),
+        f3::Tool::Read => ("f3read intact data", "Total validated size"),
+    };
+    println!(
+        "{}: {}",
+        console::style(f3_label).bold(),
+        size::format_bytes(report.usable_size)
+    );
+    println!(
+        "{}: {}",
+        console::style(label).bold(),
+        size::format_bytes(validated_size)
+    );
+    let difference = report.usable_size.abs_diff(validated_size);
+    if difference <= resolution {
+        println!(
+            "{}",
+            console::style(format!(
+                "Agreement: the sizes differ by less than the {} represented by each tested block",
+                size::format_size(resolution)
+            ))
+            .green()
+        );
+    } else {
+        println!(
+            "{}",
+            console::style(format!(
+                "DISCREPANCY: the sizes differ by {}, more than the {} represented by each tested \
+                 block",
+                size::format_size(difference),
+                size::format_size(resolution)
+            ))
+            .yellow()
+            .bold()
+        );
+    }
+    if report.tool == f3::Tool::Read {
+        println!(
+            "{}",
+            console::style(
+                "f3read only reads the files written by f3write in the free space of the \
+                 filesystem: its size is smaller than the drive by the space the filesystem uses."
+            )
+            .dim()
+        );
+    }
+}
+
+/// Print the verdict of a canary check from the `report` of the last block of the drive, with the
+/// limits of such a check.
+fn print_canary_verdict(report: &BlockReport) {
+    let verdict = match report {
+        BlockReport::Validated => {
+            console::style("PASS: the last block of the drive stores data".to_string()).green()
+        }
+        BlockReport::NoStorage => console::style(
+            "FAIL: the last block of the drive cannot store data (fake drive)".to_string(),
+        )
+        .red(),
+        _ => console::style(format!(
+            "WARNING: I/O errors on the last block of the drive ({})",
+            results::report_name(report)
+        ))
+        .yellow(),
+    };
+    println!("\n{}", verdict.bold());
+    println!(
+        "{}",
+        console::style(
+            "Only one block was tested: this detects drives failing beyond their real capacity, \
+             but not fakes wrapping writes around to the start of the drive or missing storage \
+             elsewhere. Run the full test for a reliable verdict."
+        )
+        .dim()
+    );
+}
+
+/// Print the regions of a drive of `drive_size` bytes, fully scanned with blocks of `block_size`
+/// bytes, that could not be read according to `validation_map`.
+fn print_unreadable_regions(validation_map: &[BlockReport], block_size: u64, drive_size: u64) {
+    // The ranges of consecutive unreadable blocks.
+    let mut regions: Vec<Range<u64>> = Vec::new();
+    for (i, report) in validation_map.iter().enumerate() {
+        if *report != BlockReport::ReadError {
+            continue;
+        }
+        match regions.last_mut() {
+            Some(region) if region.end == i as u64 => region.end += 1,
+            _ => regions.push(i as u64..i as u64 + 1),
+        }
+    }
+    for region in regions.iter() {
+        let start = region.start * block_size;
+        let end = (region.end * block_size).min(drive_size);
+        println!(
+            "{}",
+            console::style(format!(
+                "Unreadable: bytes {}-{} ({} bytes, blocks {}-{})",
+                start,
+                end - 1,
+                end - start,
+                region.start,
+                region.end - 1
+            ))
+            .red()
+        );
+    }
+    let num_unreadable = validation_map
+        .iter()
+        .filter(|report| **report == BlockReport::ReadError)
+        .count();
+    let summary = format!(
+        "{} of {} blocks unreadable, in {} regions",
+        num_unreadable,
+        validation_map.len(),
+        regions.len()
+    );
+    if num_unreadable == 0 {
+        println!("{}", console::style(summary).bold().green());
+    } else {
+        println!("{}", console::style(summary).bold().red());
+    }
+}
+
+/// Return an error if the block device at `drive_path`, described by `device_info`, holds the
+/// root filesystem or does not look like removable media, unless --allow-internal is given, in
+/// which case only warn. Image files are not checked.
+fn check_internal_drive(
+    cli: &TestOptions,
+    drive_path: &str,
+    device_info: &device::DeviceInfo,
+) -> Result<()> {
+    if !device_info.is_block_device {
+        return Ok(());
+    }
+    let problem = if device_info.holds_root_filesystem {
+        format!(
+            "{} is the system disk: it holds the root filesystem of the running system",
+            drive_path
+        )
+    } else if device_info.subsystems.is_empty() && device_info.removable.is_none() {
+        // Without sysfs, there is no way to tell an internal disk from a removable drive.
+        format!("cannot determine whether {} is removable media", drive_path)
+    } else if !device_info.is_removable() {
+        format!(
+            "{} is not removable media: it looks like an internal disk",
+            drive_path
+        )
+    } else {
+        return Ok(());
+    };
+    if !cli.allow_internal {
+        return Err(anyhow!(
+            "{}. Refusing to write to it: the test overwrites its data. Pass --allow-internal \
+             if you are sure this is the drive to test",
+            problem
+        ));
+    }
+    println!(
+        "{}",
+        console::style(format!(
+            "WARNING: {}. Writing to it as requested with --allow-internal: any data not \
+             restored at the end of the test is lost.",
+            problem
+        ))
+        .red()
+        .bold()
+    );
+    Ok(())
+}
+
+/// Ask the user to confirm wiping the drive at `drive_path`, typing "wipe" on stdin.
+fn confirm_wipe(drive_path: &str) -> Result<()> {
+    print!(
+        "{} Type \"wipe\" to confirm: ",
+        console::style(format!(
+            "All the data on {} will be permanently erased.",
+            drive_path
+        ))
+        .red()
+        .bold()
+    );
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("reading the wipe confirmation")?;
+    if answer.trim() != "wipe" {
+        return Err(anyhow!("Wipe of {} not confirmed", drive_path));
+    }
+    Ok(())
+}
+
+/// Print the summary of the wipe of a drive, whose blocks were verified with the results in
+/// `validation_map`.
+fn print_wipe_summary(validation_map: &[BlockReport]) {
+    let num_wiped = validation_map
+        .iter()
+        .filter(|report| **report != BlockReport::Excluded)
+        .count();
+    let num_failed = validation_map
+        .iter()
+        .filter(|report| !matches!(report, BlockReport::Validated | BlockReport::Excluded))
+        .count();
+    if num_failed == 0 {
+        println!(
+            "{}: all {} blocks were written and verified",
+            console::style("\nWipe verified").bold().green(),
+            num_wiped
+        );
+    } else {
+        println!(
+            "{}: {} of {} blocks failed verification",
+            console::style("\nWipe not verified").bold().red(),
+            num_failed,
+            num_wiped
+        );
+    }
+}
+
+/// Print the information about the drive at `drive_path`, without testing it.
+fn print_drive_info(drive_path: &str) -> Result<()> {
+    let mut drive = device::open(drive_path, true, false, true)?;
+    drive.get_device_info()?.print();
+    if drive.get_size() == 0 {
+        println!(
+            "{}",
+            console::style(
+                "The drive reports a size of 0 bytes: if it is a card reader, is a card inserted?"
+            )
+            .yellow()
+        );
+    }
+    Ok(())
+}
+
+/// Print a table of the block devices present on the system, with their size, bus, model and
+/// serial number.
+fn print_device_list() -> Result<()> {
+    let devices = device::list()?;
+    if devices.is_empty() {
+        println!("No block devices found");
+        return Ok(());
+    }
+    let header = ["Path", "Size", "Bus", "Removable", "Model", "Serial number"];
+    let rows: Vec<[String; 6]> = devices
+        .iter()
+        .map(|(path, info)| {
+            let bus = if info.subsystems.iter().any(|s| s == "usb") {
+                "USB"
+            } else if info.subsystems.iter().any(|s| s == "mmc") {
+                "SD/MMC"
+            } else {
+                ""
+            };
+            let model = [info.vendor.as_str(), info.model.as_str()]
+                .iter()
+                .filter(|field| !field.is_empty())
+                .copied()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let model = [model.as_str(), &info.usb_product, &info.mmc_name]
+                .into_iter()
+                .find(|field| !field.is_empty())
+                .unwrap_or_default();
+            let serial = [&info.serial, &info.usb_serial_number, &info.mmc_serial]
+                .into_iter()
+                .find(|field| !field.is_empty())
+                .map(String::as_str)
+                .unwrap_or_default();
+            [
+                path.clone(),
+                size::format_capacity(info.size),
+                String::from(bus),
+                String::from(match info.removable {
+                    Some(true) => "yes",
+                    Some(false) => "no",
+                    None => "",
+                }),
+                String::from(model),
+                String::from(serial),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let format_row = |row: &[&str]| {
+        row.iter()
+            .zip(&widths)
+            .map(|(field, width)| format!("{:<width$}", field, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", console::style(format_row(&header)).bold());
+    for row in &rows {
+        let row: Vec<&str> = row.iter().map(String::as_str).collect();
+        // USB and SD/MMC drives are the usual candidates for testing.
+        if row[2].is_empty() {
+            println!("{}", format_row(&row));
+        } else {
+            println!("{}", console::style(format_row(&row)).cyan());
+        }
+    }
+    Ok(())
+}
+
+/// The outcome of the test of a drive.
+struct TestOutcome {
+    /// Whether all the tested blocks were validated (or, for a read-only test, read successfully).
+    passed: bool,
+    /// The information about the drive.
+    device_info: device::DeviceInfo,
+    /// The validated size of the drive, if it could be estimated.
+    validated_size: Option<u64>,
+    /// Whether the validated size is below the minimum capacity required with --min-capacity.
+    below_min_capacity: bool,
+    /// The result of the validation of each tested block.
+    validation_map: Vec<BlockReport>,
+    /// The I/O timings of each test phase.
+    phases: Vec<PhaseResult>,
+}
+
+/// The error returned when the validated size of some drives is below --min-capacity.
+#[derive(Debug)]
+struct BelowMinCapacityError {
+    num_drives: usize,
+}
+
+impl std::fmt::Display for BelowMinCapacityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "the validated capacity of {} drive(s) is below the required minimum",
+            self.num_drives
+        )
+    }
+}
+
+impl std::error::Error for BelowMinCapacityError {}
+
+/// Print the one-line summary of the test of the drive at `drive_path`, with the given `outcome`
+/// (or the error that stopped it).
+fn print_summary_line(drive_path: &str, outcome: &Result<TestOutcome>) {
+    let (info, validated_size, verdict) = match outcome {
+        Ok(outcome) => (
+            Some(&outcome.device_info),
+            outcome.validated_size,
+            if outcome.passed {
+                console::style("PASS").green()
+            } else {
+                console::style("FAIL").red()
+            },
+        ),
+        Err(_) => (None, None, console::style("ERROR").red()),
+    };
+    let or_dash = |value: &str| {
+        if value.is_empty() {
+            String::from("-")
+        } else {
+            value.to_string()
+        }
+    };
+    println!(
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        drive_path,
+        or_dash(info.map_or("", |info| info.model.as_str())),
+        or_dash(info.map_or("", |info| info.serial.as_str())),
+        info.map_or(String::from("-"), |info| size::format_capacity(info.size)),
+        validated_size.map_or(String::from("-"), size::format_capacity),
+        verdict.bold()
+    );
+}
+
+/// Return the JUnit report of the test of the drive at `drive_path`, with the given `outcome` (or
+/// the error that stopped it).
+fn junit_report(
+    cli: &TestOptions,
+    drive_path: &str,
+    outcome: &Result<TestOutcome>,
+) -> junit::DriveReport {
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            return junit::DriveReport {
+                drive_path: drive_path.to_string(),
+                failure: None,
+                error: Some(format!("{:#}", err)),
+                phases: Vec::new(),
+            }
+        }
+    };
+    let drive_size = outcome.device_info.size;
+    let failure = match outcome.validated_size {
+        _ if outcome.passed => None,
+        Some(validated_size) if outcome.below_min_capacity => Some(format!(
+            "validated capacity {} below required minimum {}",
+            size::format_capacity(validated_size),
+            size::format_capacity(cli.min_capacity.unwrap_or_default())
+        )),
+        Some(validated_size) if validated_size < drive_size => Some(format!(
+            "validated capacity {}, advertised {}",
+            size::format_capacity(validated_size),
+            size::format_capacity(drive_size)
+        )),
+        _ => Some(format!(
+            "{} of {} tested blocks failed",
+            outcome
+                .validation_map
+                .iter()
+                .filter(|report| is_failed_report(report))
+                .count(),
+            outcome.validation_map.len()
+        )),
+    };
+    junit::DriveReport {
+        drive_path: drive_path.to_string(),
+        failure,
+        error: None,
+        phases: outcome
+            .phases
+            .iter()
+            .map(|phase| junit::PhaseReport {
+                name: phase.name.clone(),
+                duration: phase.elapsed,
+            })
+            .collect(),
+    }
+}
+
+/// Write the JUnit `reports` of the tested drives to the file requested with --junit, if any.
+/// Errors are reported but do not change the result of the test.
+fn export_junit(cli: &TestOptions, reports: &[junit::DriveReport]) {
+    let Some(junit_path) = &cli.junit else {
+        return;
+    };
+    match junit::write_junit(junit_path, reports) {
+        // Keep the output of --summary-only to the summary lines.
+        Ok(()) if cli.summary_only => {}
+        Ok(()) => println!("JUnit report written to {}", junit_path.display()),
+        Err(err) => log::error!("{:#}", err),
+    }
+}
+
+/// Run the test on the drive at `drive_path`, at each of the block sizes given on the command line.
+fn test_drive(
+    cli: &TestOptions,
+    drive_path: &str,
+    progress: progress::ProgressEvents,
+) -> Result<TestOutcome> {
+    // Block lists and checksum files refer to blocks of the size used to write them, which is
+    // not necessarily the optimal I/O size of the drive.
+    if !cli.has_block_size() && cli.blocks_from.is_none() && cli.checksums_from.is_none() {
+        let auto_cli = TestOptions {
+            block_size: vec![auto_block_size(drive_path)?],
+            ..cli.clone()
+        };
+        return test_drive_at_block_size(&auto_cli, drive_path, progress);
+    }
+    if !cli.multiple_block_sizes {
+        return test_drive_at_block_size(cli, drive_path, progress);
+    }
+
+    let mut outcomes = Vec::new();
+    for block_size in cli.block_sizes() {
+        println!(
+            "{}",
+            console::style(format!(
+                "\n===== Testing {} with {} blocks =====",
+                drive_path,
+                size::format_size(block_size)
+            ))
+            .bold()
+            .cyan()
+        );
+        let size_cli = TestOptions {
+            block_size: vec![block_size],
+            ..cli.clone()
+        };
+        outcomes.push((
+            block_size,
+            test_drive_at_block_size(&size_cli, drive_path, progress)?,
+        ));
+        if interrupt::is_interrupted() {
+            break;
+        }
+    }
+    print_block_size_comparison(&outcomes);
+
+    let passed = outcomes.iter().all(|(_, outcome)| outcome.passed);
+    let below_min_capacity = outcomes
+        .iter()
+        .any(|(_, outcome)| outcome.below_min_capacity);
+    // A drive is only as large as its smallest validated size at any block size.
+    let validated_size = outcomes
+        .iter()
+        .filter_map(|(_, outcome)| outcome.validated_size)
+        .min();
+    let (_, last_outcome) = outcomes.pop().expect("at least one block size is tested");
+    Ok(TestOutcome {
+        passed,
+        validated_size,
+        below_min_capacity,
+        ..last_outcome
+    })
+}
+
+/// Return the block size to test the drive at `drive_path` with when none is given on the command
+/// line: its optimal I/O size, raised to a multiple of at least DEFAULT_BLOCK_SIZE and capped to
+/// MAX_AUTO_BLOCK_SIZE, or DEFAULT_BLOCK_SIZE if the drive does not report it.
+fn auto_block_size(drive_path: &str) -> Result<u64> {
+    // Only block devices report an optimal I/O size.
+    let is_block_device =
+        std::fs::metadata(drive_path).is_ok_and(|metadata| metadata.file_type().is_block_device());
+    let optimal_io_size = if is_block_device {
+        let mut drive = device::open(drive_path, true, false, true)?;
+        drive.get_device_info()?.optimal_io_size
+    } else {
+        0
+    };
+    if optimal_io_size == 0 || !optimal_io_size.is_multiple_of(512) {
+        println!(
+            "{}: {} (default, the drive does not report an optimal I/O size)",
+            console::style("Block size").bold(),
+            size::format_size(DEFAULT_BLOCK_SIZE)
+        );
+        return Ok(DEFAULT_BLOCK_SIZE);
+    }
+    let block_size =
+        (DEFAULT_BLOCK_SIZE.div_ceil(optimal_io_size) * optimal_io_size).min(MAX_AUTO_BLOCK_SIZE);
+    println!(
+        "{}: {} (from the optimal I/O size of the drive, {} bytes)",
+        console::style("Block size").bold(),
+        size::format_size(block_size),
+        optimal_io_size
+    );
+    Ok(block_size)
+}
+
+/// Print a comparison of the tests of a drive at different block sizes, from the `outcomes` of
+/// the test at each block size, highlighting the block sizes whose results differ from the first
+/// one.
+fn print_block_size_comparison(outcomes: &[(u64, TestOutcome)]) {
+    let failed_blocks = |outcome: &TestOutcome| {
+        outcome
+            .validation_map
+            .iter()
+            .filter(|report| {
+                !matches!(
+                    report,
+                    BlockReport::Validated
+                        | BlockReport::Skipped
+                        | BlockReport::Excluded
+                        | BlockReport::Unverified
+                )
+            })
+            .count()
+    };
+    println!("{}", console::style("\nBlock size comparison:").bold());
+    println!(
+        "{:<12} {:>8} {:>16} {:>14}",
+        "Block size", "Verdict", "Validated size", "Failed blocks"
+    );
+    let Some((_, first_outcome)) = outcomes.first() else {
+        return;
+    };
+    let mut differing = false;
+    for (block_size, outcome) in outcomes {
+        let line = format!(
+            "{:<12} {:>8} {:>16} {:>14}",
+            size::format_size(*block_size),
+            if outcome.passed { "PASS" } else { "FAIL" },
+            outcome
+                .validated_size
+                .map_or(String::from("-"), |size| { size::format_capacity(size) }),
+            failed_blocks(outcome)
+        );
+        if outcome.passed != first_outcome.passed
+            || outcome.validated_size != first_outcome.validated_size
+        {
+            differing = true;
+            println!("{}", console::style(line).yellow());
+        } else {
+            println!("{}", line);
+        }
+    }
+    if differing {
+        println!(
+            "{}",
+            console::style(
+                "The drive behaves differently depending on the block size: it may be a fake \
+                 drive fooling tests at some block sizes."
+            )
+            .yellow()
+        );
+    }
+}
+
+/// Test the drive at `drive_path` with the single block size of `cli`, and test it again through
+/// the OS cache if requested.
+fn test_drive_at_block_size(
+    cli: &TestOptions,
+    drive_path: &str,
+    progress: progress::ProgressEvents,
+) -> Result<TestOutcome> {
+    let drive = device::open(drive_path, cli.read_only, false, !cli.no_sync)?;
+    let outcome = run_test(cli, drive_path, drive, progress)?;
+    if !cli.compare_caching || interrupt::is_interrupted() {
+        return Ok(outcome);
+    }
+
+    println!(
+        "{}",
+        console::style(format!(
+            "\n===== Testing {} through the OS cache =====",
+            drive_path
+        ))
+        .bold()
+        .cyan()
+    );
+    // Keep the output files of the first test.
+    let cached_cli = TestOptions {
+        map_out: None,
+        csv_out: None,
+        timings_out: None,
+        checksums_out: None,
+        ..cli.clone()
+    };
+    let drive = device::open(drive_path, cli.read_only, true, !cli.no_sync)?;
+    let cached_outcome = run_test(&cached_cli, drive_path, drive, progress)?;
+    print_caching_comparison(&outcome, &cached_outcome);
+    Ok(outcome)
+}
+
+/// Print a comparison of the `direct` test of a drive, bypassing the OS cache, and the `cached`
+/// test through it.
+fn print_caching_comparison(direct: &TestOutcome, cached: &TestOutcome) {
+    let verdict =
+        |outcome: &TestOutcome| String::from(if outcome.passed { "PASS" } else { "FAIL" });
+    let size = |outcome: &TestOutcome| {
+        outcome
+            .validated_size
+            .map_or(String::from("-"), size::format_capacity)
+    };
+    println!("{}", console::style("\nCaching comparison:").bold());
+    println!("{:<20} {:>14} {:>14}", "", "direct", "cached");
+    println!(
+        "{:<20} {:>14} {:>14}",
+        "Verdict",
+        verdict(direct),
+        verdict(cached)
+    );
+    println!(
+        "{:<20} {:>14} {:>14}",
+        "Validated size",
+        size(direct),
+        size(cached)
+    );
+    for (direct_phase, cached_phase) in direct.phases.iter().zip(cached.phases.iter()) {
+        let mbps = |phase: &PhaseResult| {
+            phase
+                .throughput()
+                .map_or(String::from("-"), |mbps| format!("{:.3} MB/s", mbps))
+        };
+        println!(
+            "{:<20} {:>14} {:>14}",
+            direct_phase.name,
+            mbps(direct_phase),
+            mbps(cached_phase)
+        );
+    }
+    let num_different = direct
+        .validation_map
+        .iter()
+        .zip(cached.validation_map.iter())
+        .filter(|(direct_report, cached_report)| direct_report != cached_report)
+        .count();
+    println!("{:<20} {:>14}", "Blocks differing", num_different);
+    if cached.passed && !direct.passed {
+        println!(
+            "{}",
+            console::style(
+                "The drive passes only through the OS cache: the cache hides data the drive does \
+                 not store"
+            )
+            .red()
+            .bold()
+        );
+    }
+}
+
+/// Warn if the image file at `drive_path`, described by `device_info`, is sparse, and more so if
+/// its filesystem does not have enough free space to allocate the rest of it.
+fn check_preallocation(drive_path: &str, device_info: &device::DeviceInfo) {
+    let Some(allocated_size) = device_info.allocated_size else {
+        log::warn!(
+            "{} is not an image file: --preallocate-check only applies to image files",
+            drive_path
+        );
+        return;
+    };
+    if allocated_size >= device_info.size {
+        return;
+    }
+    let unallocated_size = device_info.size - allocated_size;
+    println!(
+        "{}",
+        console::style(format!(
+            "Warning: {} is a sparse file: only {} of its {} bytes are allocated",
+            drive_path, allocated_size, device_info.size
+        ))
+        .yellow()
+    );
+    if let Some(free) = device_info
+        .filesystem_free
+        .filter(|free| *free < unallocated_size)
+    {
+        println!(
+            "{}",
+            console::style(format!(
+                "Warning: its filesystem has only {} bytes free to allocate the other {} bytes: \
+                 like a fake drive, it cannot store data up to its size",
+                free, unallocated_size
+            ))
+            .red()
+        );
+    }
+}
+
+/// Run the test on the already opened `drive`, whose path is `drive_path`.
+fn run_test(
+    cli: &TestOptions,
+    drive_path: &str,
+    mut drive: Box<dyn device::Device>,
+    progress: progress::ProgressEvents,
+) -> Result<TestOutcome> {
+    drive.set_io_timeout(cli.io_timeout.map(Duration::from_millis));
+    drive.set_reconnect_wait(cli.reconnect_wait.map(Duration::from_secs));
+    // Nothing can be tested on an empty device, and the number of blocks would be 0. Card readers
+    // without a card inserted commonly report a size of 0.
+    if drive.get_size() == 0 {
+        return Err(anyhow!(
+            "{} reports a size of 0 bytes: if it is a card reader, is a card inserted?",
+            drive_path
+        ));
+    }
+    let mut io_options = IoOptions::from_cli(cli, progress);
+    if cli.checksums_from.is_some() && !cli.read_only {
+        return Err(anyhow!(
+            "--checksums-from can only be used with --read-only or the verify command"
+        ));
+    }
+    if cli.soak.is_some() && !cli.read_only {
+        return Err(anyhow!(
+            "--soak can only be used with --read-only or the verify command"
+        ));
+    }
+    if cli.full && !cli.read_only {
+        return Err(anyhow!(
+            "--full can only be used with --read-only or the verify command (use --wipe to write \
+             every block)"
+        ));
+    }
+    let data_file = match cli.wipe_pattern {
+        WipePattern::Zeros if cli.wipe => Some(std::rc::Rc::from([0x00])),
+        WipePattern::Ones if cli.wipe => Some(std::rc::Rc::from([0xff])),
+        _ => cli
+            .data_file
+            .as_deref()
+            .map(keystream::read_data_file)
+            .transpose()?,
+    };
+    let f3_report = cli.compare_f3.as_deref().map(f3::read).transpose()?;
+    // The run ID written in the block headers is the start time of the test.
+    let run_id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let device_info = drive.get_device_info()?.clone();
+    device_info.print();
+    if cli.preallocate_check {
+        check_preallocation(drive_path, &device_info);
+    }
+    // Each tested block costs a random seek on rotating media, so small blocks make the test slow
+    // while measuring mostly the seek time.
+    if device_info.rotational == Some(true) && cli.block_size() < ROTATIONAL_MIN_BLOCK_SIZE {
+        println!(
+            "{}",
+            console::style(
+                "The drive is rotational: consider testing fewer and larger blocks, e.g. \
+                 --block-size 1M --num-blocks 128"
+            )
+            .dim()
+        );
+    }
+    if cli.no_sync && !cli.drop_caches {
+        println!(
+            "{}",
+            console::style(
+                "Writes are not synchronous: the drive cache may hide missing storage, consider \
+                 --drop-caches to flush the written data before verifying it"
+            )
+            .dim()
+        );
+    }
+    if !cli.read_only {
+        check_internal_drive(cli, drive_path, &device_info)?;
+    }
+    if cli.monitor_temperature {
+        match drive.temperature() {
+            Some(temperature) => println!(
+                "{}: {:.0} °C (sampled every {} s while writing)",
+                console::style("Drive temperature").bold(),
+                temperature,
+                thermal::SAMPLE_INTERVAL.as_secs()
+            ),
+            None => {
+                log::warn!(
+                    "{} does not report its temperature (no hwmon sensor in sysfs): \
+                     --monitor-temperature is ignored",
+                    drive_path
+                );
+                io_options.monitor_temperature = false;
+            }
+        }
+    }
+    if cli.block_size() > drive.get_size() {
+        return Err(anyhow!(
+            "The block size ({} bytes) is larger than the whole drive ({} bytes): use a smaller \
+             --block-size",
+            cli.block_size(),
+            drive.get_size()
+        ));
+    }
+    if cli.wipe {
+        confirm_wipe(drive_path)?;
+    }
+
+    // The last block may be partial.
+    let mut num_drive_blocks = drive.get_size().div_ceil(cli.block_size());
+    let last_block_size = drive.get_size() % cli.block_size();
+    // With direct I/O, the length of I/O operations must be a multiple of the logical block size
+    // (or of the filesystem block size for image files), which a partial last block may not be.
+    let io_granularity = if device_info.is_block_device {
+        device_info.logical_block_size
+    } else {
+        drive.get_memory_alignment() as u64
+    };
+    if io_granularity > 1 && !last_block_size.is_multiple_of(io_granularity) {
+        num_drive_blocks -= 1;
+        if num_drive_blocks == 0 {
+            return Err(anyhow!(
+                "The drive ({} bytes) is too small to be tested with direct I/O in blocks of {} \
+                 bytes",
+                drive.get_size(),
+                cli.block_size()
+            ));
+        }
+        log::warn!(
+            "The last {} bytes of the drive cannot be accessed with direct I/O and are not tested",
+            last_block_size
+        );
+    } else if last_block_size != 0 {
+        log::warn!(
+            "The drive size ({} bytes) is not a multiple of the block size ({} bytes): the last \
+             block is only {} bytes",
+            drive.get_size(),
+            cli.block_size(),
+            last_block_size
+        );
+    }
+    // spot_blocks contains the list of blocks selected for testing.
+    let mut spot_blocks;
+    // recorded_checksums contains the checksum of each block recorded in the file given with
+    // --checksums-from, indexed like the validation map.
+    let mut recorded_checksums = None;
+    // The earlier reports of the blocks retested with --retest-from, in the order of spot_blocks.
+    let mut previous_reports: Option<Vec<BlockReport>> = None;
+    if let Some(path) = &cli.checksums_from {
+        let checksums = results::read_checksums_csv(path)?;
+        for checksum in &checksums {
+            if checksum.block >= num_drive_blocks
+                || checksum.offset != checksum.block * cli.block_size()
+            {
+                return Err(anyhow!(
+                    "{:?}: block {} does not match the drive and block size",
+                    path,
+                    checksum.block
+                ));
+            }
+        }
+        println!(
+            "Verifying {} blocks listed in {}",
+            checksums.len(),
+            path.display()
+        );
+        spot_blocks = checksums
+            .iter()
+            .enumerate()
+            .map(|(idx, checksum)| BlockIdx {
+                idx,
+                num: checksum.block,
+            })
+            .collect::<Vec<_>>();
+        recorded_checksums = Some(
+            checksums
+                .iter()
+                .map(|checksum| checksum.crc32)
+                .collect::<Vec<_>>(),
+        );
+    } else if let Some(path) = &cli.blocks_from {
+        let block_nums = read_block_list(path, cli.block_size(), num_drive_blocks)?;
+        println!(
+            "Testing {} blocks listed in {}",
+            block_nums.len(),
+            path.display()
+        );
+        spot_blocks = block_nums
+            .into_iter()
+            .enumerate()
+            .map(|(idx, num)| BlockIdx { idx, num })
+            .collect::<Vec<_>>();
+    } else if let Some(path) = &cli.retest_from {
+        let failed = read_failed_blocks(path, cli.block_size(), num_drive_blocks)?;
+        println!(
+            "Retesting {} blocks that failed in {}",
+            failed.len(),
+            path.display()
+        );
+        spot_blocks = failed
+            .iter()
+            .enumerate()
+            .map(|(idx, (num, _))| BlockIdx { idx, num: *num })
+            .collect::<Vec<_>>();
+        previous_reports = Some(failed.into_iter().map(|(_, report)| report).collect());
+    } else if let Some(stride) = cli.stride {
+        if stride == 0 || !stride.is_multiple_of(cli.block_size()) {
+            return Err(anyhow!(
+                "the stride ({} bytes) must be a positive multiple of the block size ({} bytes)",
+                stride,
+                cli.block_size()
+            ));
+        }
+        let stride_blocks = stride / cli.block_size();
+        let num_blocks = num_drive_blocks.div_ceil(stride_blocks) as usize;
+        println!(
+            "Testing {} blocks, one every {}",
+            num_blocks,
+            size::format_size(stride)
+        );
+        spot_blocks = (0..num_blocks)
+            .map(|i| BlockIdx {
+                idx: i,
+                num: i as u64 * stride_blocks,
+            })
+            .collect::<Vec<_>>();
+    } else if cli.canary {
+        let num = num_drive_blocks - 1;
+        println!(
+            "Canary check: testing only the last block of the drive, at offset {}",
+            num * cli.block_size()
+        );
+        spot_blocks = vec![BlockIdx { idx: 0, num }];
+    } else {
+        let mut num_blocks = if cli.wipe || cli.full {
+            num_drive_blocks as usize
+        } else if cli.auto_blocks {
+            auto_num_blocks(drive.get_size(), cli.granularity_mib * 1024 * 1024)
+        } else if let Some(test_size) = cli.test_size {
+            test_size.div_ceil(cli.block_size()).max(1) as usize
+        } else {
+            cli.num_blocks
+        };
+        if num_blocks as u64 > num_drive_blocks {
+            // Selecting more blocks than available would test some of them multiple times.
+            log::warn!(
+                "The drive has only {} blocks, testing all of them instead of {}",
+                num_drive_blocks,
+                num_blocks
+            );
+            num_blocks = num_drive_blocks as usize;
+        }
+        println!(
+            "Testing {} blocks, each representing ~{} of the drive",
+            num_blocks,
+            size::format_mib(drive.get_size() as f64 / num_blocks as f64)
+        );
+        spot_blocks = select_spot_blocks(num_blocks, num_drive_blocks);
+    }
+    // Blocks beyond the end of the drive would fail for reasons unrelated to the drive.
+    if let Some(block_idx) = spot_blocks
+        .iter()
+        .find(|block_idx| block_idx.num >= num_drive_blocks)
+    {
+        return Err(anyhow!(
+            "block {} is beyond the end of the drive, which has {} blocks",
+            block_idx.num,
+            num_drive_blocks
+        ));
+    }
+    let num_blocks = spot_blocks.len();
+    // Only the sampled blocks are actually read and written.
+    let tested_size = (num_blocks as u64 * cli.block_size()).min(drive.get_size());
+    println!(
+        "Sampled coverage: {} bytes ({}), {:.4}% of the drive",
+        tested_size,
+        size::format_mib(tested_size as f64),
+        tested_size as f64 * 100.0 / drive.get_size().max(1) as f64
+    );
+    if cli.tui {
+        io_options.dashboard = tui::Dashboard::start(
+            drive_path,
+            num_blocks,
+            cli.map_width,
+            MapSymbols::from_cli(cli),
+        );
+        if io_options.dashboard.is_none() {
+            log::warn!("stdout is not a terminal, showing progress bars instead of the dashboard");
+        }
+    }
+
+    let seed = match (cli.seed, &cli.seed_file) {
+        (Some(seed), _) => Some(seed),
+        (None, Some(path)) => Some(load_or_create_seed(path)?),
+        (None, None) => None,
+    };
+    // ChaCha8 generates the same sequence from a seed on every platform and version of rand.
+    let mut rng = match seed {
+        Some(seed) => {
+            log::info!("Using random seed {}", seed);
+            rand_chacha::ChaCha8Rng::seed_from_u64(seed)
+        }
+        None => rand_chacha::ChaCha8Rng::from_entropy(),
+    };
+    // Shuffle the blocks to test, so that they are not tested in the order they are present on the
+    // drive. A full scan only reads, so it is faster in ascending order.
+    if !cli.no_shuffle && !cli.full {
+        spot_blocks.shuffle(&mut rng);
+    }
+
+    match estimate_duration(cli, drive.deref_mut(), &spot_blocks, &io_options)? {
+        // A read-only test makes a single pass.
+        Some(pass_duration) if cli.soak.is_some() => println!(
+            "{}: {} (~{} per pass over the tested blocks)",
+            console::style("Soak duration").bold(),
+            format_duration(cli.soak.unwrap_or_default()),
+            format_duration(pass_duration)
+        ),
+        Some(duration) => println!(
+            "{}: ~{} ({} over the tested blocks)",
+            console::style("Estimated test duration").bold(),
+            format_duration(duration),
+            match num_passes(cli) {
+                1 => "1 pass".to_string(),
+                n => format!("{} passes", n),
+            }
+        ),
+        None => log::warn!("Could not estimate the test duration: calibration reads failed"),
+    }
+
+    if let Some(num_warmup) = cli.warmup {
+        println!("{}", console::style("\nWarming up").bold());
+        warm_up(
+            drive.deref_mut(),
+            num_drive_blocks,
+            num_warmup,
+            cli.block_size() as usize,
+            cli.read_only,
+            &io_options,
+        );
+    }
+
+    // validation_map contains the result of the validation of each block.
+    let mut validation_map = vec![BlockReport::Unknown; num_blocks];
+
+    // Blocks overlapping the ranges excluded with --exclude are neither read nor written.
+    let mut num_excluded = 0;
+    for block_idx in &spot_blocks {
+        let block = block_idx.num * cli.block_size()..(block_idx.num + 1) * cli.block_size();
+        if cli
+            .exclude
+            .iter()
+            .any(|range| range.start < block.end && block.start < range.end)
+        {
+            validation_map[block_idx.idx] = BlockReport::Excluded;
+            num_excluded += 1;
+        }
+    }
+    if num_excluded == num_blocks {
+        return Err(anyhow!("all the tested blocks are in the excluded ranges"));
+    } else if num_excluded > 0 {
+        println!(
+            "Excluding {} blocks overlapping the ranges given with --exclude",
+            num_excluded
+        );
+    }
+    // original_blocks contains the blocks whose original content is read (and restored), excluding
+    // the excluded ones.
+    let original_blocks = spot_blocks
+        .iter()
+        .filter(|block_idx| validation_map[block_idx.idx] != BlockReport::Excluded)
+        .map(|block_idx| BlockIdx {
+            idx: block_idx.idx,
+            num: block_idx.num,
+        })
+        .collect::<Vec<_>>();
+
+    // phases contains the I/O timings of each test phase, for the final comparison.
+    let mut phases = Vec::new();
+
+    // orig_data_option contains the original blocks data, if they were read and need to be
+    // restored at the end of the test.
+    let mut orig_data_option = None;
+
+    if !cli.no_read_original {
+        if cli.soak.is_some() {
+            println!("{}", console::style("\nSoak reading the blocks").bold());
+        } else {
+            println!("{}", console::style("\nReading original blocks").bold());
+        }
+        // The recorded checksums, in the order the blocks are read.
+        let expected_checksums = recorded_checksums.as_ref().map(|checksums| {
+            original_blocks
+                .iter()
+                .map(|block_idx| checksums[block_idx.idx])
+                .collect::<Vec<_>>()
+        });
+        let (orig_data, orig_result) = match cli.soak {
+            Some(duration) => soak(
+                drive.deref_mut(),
+                &original_blocks,
+                cli.block_size() as usize,
+                &io_options,
+                duration,
+            ),
+            None => read_blocks(
+                drive.deref_mut(),
+                "read_original",
+                &original_blocks,
+                cli.block_size() as usize,
+                &io_options,
+                false,
+                match &expected_checksums {
+                    Some(checksums) => Some(Expected::Checksums(checksums)),
+                    // The content is not needed when only reading.
+                    None if cli.read_only => Some(Expected::Any),
+                    None => None,
+                },
+            ),
+        };
+
+        phases.push(orig_result.named(if cli.soak.is_some() {
+            "Soak read".to_string()
+        } else {
+            "Original read".to_string()
+        }));
+
+        // Record any read error, and the result of the verification against the recorded
+        // checksums, in the validation map.
+        for (i, block_idx) in original_blocks.iter().enumerate() {
+            if orig_data.errors[i] == IoError::ReadError {
+                validation_map[block_idx.idx] = BlockReport::ReadError;
+            } else if orig_data.errors[i] == IoError::Mismatch {
+                validation_map[block_idx.idx] = BlockReport::NoStorage;
+            } else if orig_data.durations[i].is_some() {
+                validation_map[block_idx.idx] = if recorded_checksums.is_some() {
+                    BlockReport::Validated
+                } else {
+                    BlockReport::ReadSuccessful
+                };
+            }
+        }
+        if let Some(dashboard) = &io_options.dashboard {
+            dashboard.map(&validation_map);
+        }
+
+        if let Some(i) = orig_data.disconnected_at {
+            return stop_disconnected(
+                &original_blocks,
+                i,
+                &validation_map,
+                cli.map_width,
+                MapSymbols::from_cli(cli),
+                false,
+            );
+        }
+
+        if interrupt::is_interrupted() {
+            return stop_interrupted(
+                drive.deref_mut(),
+                &original_blocks,
+                &validation_map,
+                cli,
+                None,
+                &io_options,
+            );
+        }
+
+        let has_read_errors = validation_map.contains(&BlockReport::ReadError);
+        if has_read_errors && cli.ignore_read_errors {
+            log::warn!(
+                "Skipping {} blocks whose original content could not be read",
+                validation_map
+                    .iter()
+                    .filter(|report| **report == BlockReport::ReadError)
+                    .count()
+            );
+            for report in validation_map.iter_mut() {
+                if *report == BlockReport::ReadError {
+                    *report = BlockReport::Skipped;
+                }
+            }
+        } else if has_read_errors || cli.read_only {
+            // Typically, we would print the validation map at the end, but
+            // if there were read errors, print the validation map and exit.
+            // A full scan reads every block, making the map too large to be useful.
+            if cli.full {
+                print_unreadable_regions(&validation_map, cli.block_size(), drive.get_size());
+            } else {
+                print_map(
+                    "Validation map",
+                    &validation_map,
+                    cli.map_width,
+                    MapSymbols::from_cli(cli),
+                );
+                print_selected_speed_map(cli, &phases, &spot_blocks, validation_map.len());
+            }
+            export_validation_map(
+                cli,
+                drive_path,
+                drive.deref(),
+                &spot_blocks,
+                &validation_map,
+            );
+            print_read_verdict(&validation_map, cli.read_only);
+        }
+        if cli.read_only {
+            if let Some(previous_reports) = &previous_reports {
+                print_retest_summary(
+                    &spot_blocks,
+                    previous_reports,
+                    &validation_map,
+                    cli.block_size(),
+                );
+            }
+            return Ok(TestOutcome {
+                passed: !has_read_errors && !validation_map.contains(&BlockReport::NoStorage),
+                device_info,
+                validated_size: None,
+                below_min_capacity: false,
+                validation_map,
+                phases,
+            });
+        }
+        if has_read_errors && !cli.ignore_read_errors {
+            log::error!("I/O errors encountered reading original blocks, exiting");
+            return Err(anyhow!("I/O errors reading original blocks"));
+        }
+        if !cli.no_restore_original {
+            orig_data_option = Some(orig_data);
+        }
+    }
 
-    let mut drive = device::open(&cli.drive, cli.read_only)?;
-    drive.get_device_info()?.print();
+    // test_blocks contains the blocks to write and verify, excluding the skipped ones.
+    let test_blocks = original_blocks
+        .iter()
+        .filter(|block_idx| validation_map[block_idx.idx] != BlockReport::Skipped)
+        .map(|block_idx| BlockIdx {
+            idx: block_idx.idx,
+            num: block_idx.num,
+        })
+        .collect::<Vec<_>>();
 
-    if drive.get_size() % (cli.block_size_kb * 1024) != 0 {
-        return Err(anyhow!(
-            "The drive size ({} bytes) is not a multiple of the block size ({} KiB)",
-            drive.get_size(),
-            cli.block_size_kb
-        ));
-    }
-    let num_drive_blocks = drive.get_size() / (cli.block_size_kb * 1024);
-    // spot_blocks contains the list of blocks selected for testing.
-    let mut spot_blocks = Vec::with_capacity(cli.num_blocks);
-    for i in 0..cli.num_blocks {
-        // Divide the drive in cli.num_blocks areas, and select the block best covering the end of
-        // each area.
-        spot_blocks.push(BlockIdx {
-            idx: i,
-            num: (((i + 1) as u64 * num_drive_blocks) as f64 / cli.num_blocks as f64).round()
-                as u64
-                - 1,
-        });
+    if cli.trim_before_write {
+        println!("{}", console::style("\nDiscarding blocks").bold());
+        let num_discarded = discard_blocks(
+            drive.deref_mut(),
+            &test_blocks,
+            cli.block_size() as usize,
+            &io_options,
+        );
+        println!(
+            "Discarded {} of {} blocks",
+            num_discarded,
+            test_blocks.len()
+        );
     }
 
-    let mut rng = rngs::SmallRng::from_entropy();
-    // Shuffle the blocks to test, so that they are not tested in the order they are present on the
-    // drive.
-    spot_blocks.shuffle(&mut rng);
+    // verified[i] tells whether test_blocks[i] is read back after being written: with
+    // --verify-fraction, only a random sample of them is, the same one in every pass.
+    let verified = match cli.verify_fraction {
+        Some(fraction) => {
+            let num_verified = ((test_blocks.len() as f64 * fraction).round() as usize)
+                .max(1)
+                .min(test_blocks.len());
+            let mut verified = vec![false; test_blocks.len()];
+            for i in rand::seq::index::sample(&mut rng, test_blocks.len(), num_verified) {
+                verified[i] = true;
+            }
+            verified
+        }
+        None => vec![true; test_blocks.len()],
+    };
 
-    // validation_map contains the result of the validation of each block.
-    let mut validation_map = vec![BlockReport::Unknown; cli.num_blocks];
+    // The content of a data file or a wipe pattern is not random data.
+    let content = if data_file.is_some() {
+        ""
+    } else {
+        " with random data"
+    };
+    // failure_counts contains the number of passes in which each block failed.
+    let mut failure_counts = vec![0; num_blocks];
+    for pass in 1..=cli.repeat {
+        let pass_name = |name: &str| {
+            if cli.repeat > 1 {
+                format!("{} #{}", name, pass)
+            } else {
+                name.to_string()
+            }
+        };
+        if cli.repeat > 1 {
+            println!(
+                "{}",
+                console::style(format!("\nPass {} of {}", pass, cli.repeat))
+                    .bold()
+                    .cyan()
+            );
+        }
 
-    // orig_data_option contains the original blocks data, if they were read, so that it can be
-    // restored at the end of the test.
-    let mut orig_data_option = None;
+        println!(
+            "{}",
+            console::style(format!("\nWriting blocks{}", content)).bold()
+        );
+
+        // The data written to each block is generated right before writing it, and regenerated
+        // when verifying it, so that it never needs to be kept in memory.
+        let keystream = new_keystream(cli, data_file.as_ref(), &mut rng, run_id);
+        let mut random_blocks = Blocks::new_streaming(
+            cli.block_size() as usize,
+            test_blocks.len(),
+            drive.get_memory_alignment(),
+        );
+        if cli.checksum || cli.checksums_out.is_some() {
+            random_blocks.checksums = Some(vec![0; test_blocks.len()]);
+        }
 
-    if !cli.no_restore_original {
-        println!("{}", console::style("\nReading original blocks").bold());
-        let orig_data = read_blocks(
+        let write_result = write_blocks(
             drive.deref_mut(),
-            &spot_blocks,
-            cli.block_size_kb as usize * 1024,
+            "write_random",
+            &test_blocks,
+            &mut random_blocks,
+            Some(&keystream),
+            &io_options,
         );
+        phases.push(write_result.named(pass_name("Write")));
+        if pass == cli.repeat {
+            export_checksums(cli, drive_path, drive.deref(), &test_blocks, &random_blocks);
+        }
 
-        // Record any read error in the validation map.
-        for i in 0..cli.num_blocks {
-            if orig_data.errors[i] == IoError::ReadError {
-                validation_map[spot_blocks[i].idx] = BlockReport::ReadError;
-            } else {
-                validation_map[spot_blocks[i].idx] = BlockReport::ReadSuccessful;
+        // Record any write error in the validation map, and the written blocks that are not
+        // verified.
+        for i in 0..test_blocks.len() {
+            let report = &mut validation_map[test_blocks[i].idx];
+            if random_blocks.errors[i] == IoError::WriteError {
+                *report = BlockReport::WriteError;
+            } else if !verified[i]
+                && random_blocks.durations[i].is_some()
+                && *report != BlockReport::WriteError
+            {
+                *report = BlockReport::Unverified;
             }
         }
 
-        let has_read_errors = validation_map.contains(&BlockReport::ReadError);
-        if has_read_errors || cli.read_only {
-            // Typically, we would print the validation map at the end, but
-            // if there were read errors, print the validation map and exit.
-            print_validation_map(&validation_map, cli.map_width);
+        if let Some(i) = random_blocks.disconnected_at {
+            return stop_disconnected(
+                &test_blocks,
+                i,
+                &validation_map,
+                cli.map_width,
+                MapSymbols::from_cli(cli),
+                orig_data_option.is_some(),
+            );
         }
-        if cli.read_only {
-            return Ok(());
+
+        if interrupt::is_interrupted() {
+            return stop_interrupted(
+                drive.deref_mut(),
+                &original_blocks,
+                &validation_map,
+                cli,
+                orig_data_option,
+                &io_options,
+            );
+        }
+
+        if cli.no_verify {
+            continue;
+        }
+
+        if cli.drop_caches {
+            if let Err(err) = drive.flush_caches() {
+                log::warn!("Cannot drop the caches of the drive: {:#}", err);
+            }
+        }
+
+        println!(
+            "{}",
+            console::style(format!("\nReading blocks{}", content)).bold()
+        );
+        // The indices in test_blocks of the blocks in the order they are read back.
+        let mut verify_order = (0..test_blocks.len())
+            .filter(|i| verified[*i])
+            .collect::<Vec<_>>();
+        if verify_order.len() < test_blocks.len() {
+            println!(
+                "Verifying {} of the {} written blocks (--verify-fraction)",
+                verify_order.len(),
+                test_blocks.len()
+            );
+        }
+        if cli.reverse_verify {
+            verify_order.sort_by_key(|i| std::cmp::Reverse(test_blocks[*i].num));
+        }
+        let verify_blocks = verify_order
+            .iter()
+            .map(|i| BlockIdx {
+                idx: test_blocks[*i].idx,
+                num: test_blocks[*i].num,
+            })
+            .collect::<Vec<_>>();
+        let verify_checksums = random_blocks.checksums.as_ref().map(|checksums| {
+            verify_order
+                .iter()
+                .map(|i| checksums[*i])
+                .collect::<Vec<_>>()
+        });
+        let (read_random_blocks, read_result) = read_blocks(
+            drive.deref_mut(),
+            "read_random",
+            &verify_blocks,
+            cli.block_size() as usize,
+            &io_options,
+            cli.double_read,
+            Some(match &verify_checksums {
+                Some(checksums) if cli.checksum => Expected::Checksums(checksums),
+                _ => Expected::Content(&keystream),
+            }),
+        );
+        phases.push(read_result.named(pass_name("Random read")));
+
+        // Fill the validation map.
+        for (j, i) in verify_order.iter().enumerate() {
+            let block_idx = &test_blocks[*i];
+            let report = if random_blocks.errors[*i] == IoError::WriteError {
+                BlockReport::WriteError
+            } else if read_random_blocks.errors[j] == IoError::ReadError {
+                BlockReport::ReadError
+            } else if read_random_blocks.errors[j] == IoError::Unstable {
+                BlockReport::Unstable
+            } else if read_random_blocks.errors[j] == IoError::Mismatch {
+                BlockReport::NoStorage
+            } else if read_random_blocks.durations[j].is_none() {
+                // The block was not read because the test was interrupted.
+                continue;
+            } else {
+                BlockReport::Validated
+            };
+            record_pass_report(
+                &mut validation_map,
+                &mut failure_counts,
+                block_idx.idx,
+                report,
+            );
+        }
+        if let Some(dashboard) = &io_options.dashboard {
+            dashboard.map(&validation_map);
+        }
+
+        if let Some(i) = read_random_blocks.disconnected_at {
+            return stop_disconnected(
+                &verify_blocks,
+                i,
+                &validation_map,
+                cli.map_width,
+                MapSymbols::from_cli(cli),
+                orig_data_option.is_some(),
+            );
+        }
+
+        if interrupt::is_interrupted() {
+            return stop_interrupted(
+                drive.deref_mut(),
+                &original_blocks,
+                &validation_map,
+                cli,
+                orig_data_option,
+                &io_options,
+            );
         }
-        if has_read_errors {
+    }
+    if cli.repeat > 1 {
+        print_repeat_summary(&failure_counts, cli.repeat);
+    }
+
+    // Without the verify phase, the written blocks are never read back to detect interference.
+    if cli.interference_test && !cli.no_verify {
+        println!(
+            "{}",
+            console::style("\nTesting interference between blocks").bold()
+        );
+        // Blocks with I/O errors cannot reveal whether writing other blocks overwrites them.
+        let mut pair_blocks = test_blocks
+            .iter()
+            .filter(|block_idx| {
+                !matches!(
+                    validation_map[block_idx.idx],
+                    BlockReport::ReadError | BlockReport::WriteError
+                )
+            })
+            .map(|block_idx| BlockIdx {
+                idx: block_idx.idx,
+                num: block_idx.num,
+            })
+            .collect::<Vec<_>>();
+        // Sorting by position pairs each block with the one half the drive after it.
+        pair_blocks.sort_by_key(|block_idx| block_idx.num);
+        let keystream = new_keystream(cli, data_file.as_ref(), &mut rng, run_id);
+        let overwritten = test_interference(
+            drive.deref_mut(),
+            &pair_blocks,
+            cli.block_size() as usize,
+            &keystream,
+            &io_options,
+        );
+        for (i, j) in overwritten.iter() {
             println!(
                 "{}",
-                console::style("I/O errors encountered reading original blocks, exiting").red()
+                console::style(format!(
+                    "Writing block {} (offset {}) overwrote block {} (offset {})",
+                    pair_blocks[*j].idx,
+                    pair_blocks[*j].num * cli.block_size(),
+                    pair_blocks[*i].idx,
+                    pair_blocks[*i].num * cli.block_size()
+                ))
+                .red()
+            );
+            validation_map[pair_blocks[*i].idx] = BlockReport::NoStorage;
+        }
+        println!(
+            "{}: {} of {} pairs",
+            console::style("Interfering blocks").bold(),
+            overwritten.len(),
+            pair_blocks.len() / 2
+        );
+
+        if interrupt::is_interrupted() {
+            return stop_interrupted(
+                drive.deref_mut(),
+                &original_blocks,
+                &validation_map,
+                cli,
+                orig_data_option,
+                &io_options,
             );
-            return Err(anyhow!("I/O errors reading original blocks"));
         }
-        orig_data_option = Some(orig_data);
     }
 
-    println!(
-        "{}",
-        console::style("\nWriting blocks with random data").bold()
-    );
+    // immediate_map contains the result of the immediate verification of each block, if any.
+    let mut immediate_map = None;
+    if cli.immediate_verify {
+        println!(
+            "{}",
+            console::style("\nWriting and immediately reading back each block").bold()
+        );
+        let keystream = new_keystream(cli, data_file.as_ref(), &mut rng, run_id);
+        let reports = immediate_verify(
+            drive.deref_mut(),
+            &test_blocks,
+            cli.block_size() as usize,
+            &keystream,
+            &io_options,
+        );
+        // The blocks not tested keep their report, like Skipped or Excluded.
+        let mut map = validation_map
+            .iter()
+            .map(|report| match report {
+                BlockReport::Skipped | BlockReport::Excluded => report.clone(),
+                _ => BlockReport::Unknown,
+            })
+            .collect::<Vec<_>>();
+        for (block_idx, report) in test_blocks.iter().zip(reports) {
+            map[block_idx.idx] = report;
+        }
+        immediate_map = Some(map);
+
+        if interrupt::is_interrupted() {
+            return stop_interrupted(
+                drive.deref_mut(),
+                &original_blocks,
+                &validation_map,
+                cli,
+                orig_data_option,
+                &io_options,
+            );
+        }
+    }
 
-    // Generate the random data to write to the blocks.
-    let mut random_blocks = Blocks::new(
-        cli.block_size_kb as usize * 1024,
-        cli.num_blocks,
-        drive.get_memory_alignment(),
+    // A wipe tests every block, making the map too large to be useful.
+    if cli.wipe {
+        print_wipe_summary(&validation_map);
+    } else {
+        print_map(
+            "Validation map",
+            &validation_map,
+            cli.map_width,
+            MapSymbols::from_cli(cli),
+        );
+        print_selected_speed_map(cli, &phases, &spot_blocks, validation_map.len());
+    }
+    if let Some(immediate_map) = &immediate_map {
+        print_immediate_map(immediate_map, cli.map_width, MapSymbols::from_cli(cli));
+    }
+    export_validation_map(
+        cli,
+        drive_path,
+        drive.deref(),
+        &spot_blocks,
+        &validation_map,
     );
-    rng.fill_bytes(random_blocks.data_mut());
 
-    write_blocks(drive.deref_mut(), &spot_blocks, &mut random_blocks);
+    // The validated size can only be estimated when the tested blocks are spread over the whole
+    // drive, and have been verified.
+    let mut validated_size_option = None;
+    if cli.blocks_from.is_none() && cli.retest_from.is_none() && !cli.no_verify && !cli.canary {
+        // Find highest validated block (where all previous blocks are also validated, or skipped,
+        // excluded or not verified).
+        let mut highest_validated_block_idx = -1;
+        for (i, v) in validation_map.iter().enumerate() {
+            if matches!(
+                v,
+                BlockReport::Skipped | BlockReport::Excluded | BlockReport::Unverified
+            ) {
+                continue;
+            }
+            if *v != BlockReport::Validated {
+                break;
+            }
+            highest_validated_block_idx = i as i64;
+        }
+        let mut validated_drive_size = 0;
+        if highest_validated_block_idx >= 0 {
+            for b in spot_blocks.iter() {
+                if b.idx == highest_validated_block_idx as usize {
+                    // The validated drive size is the equal to the end of this block,
+                    // i.e. the beginning offset of the following block.
+                    validated_drive_size = ((b.num + 1) * cli.block_size()).min(drive.get_size());
+                    break;
+                }
+            }
+        }
+        let total_validated_size = total_validated_size(
+            &spot_blocks,
+            &validation_map,
+            cli.block_size(),
+            drive.get_size(),
+        );
+        let (size, other_label, other_size) = match cli.capacity_metric {
+            CapacityMetric::Contiguous => (
+                validated_drive_size,
+                "Total validated size",
+                total_validated_size,
+            ),
+            CapacityMetric::Total => (
+                total_validated_size,
+                "Contiguous validated size",
+                validated_drive_size,
+            ),
+        };
+        validated_size_option = Some(size);
+        println!(
+            "{}: {}",
+            console::style("Validated drive size").bold(),
+            size::format_bytes(size)
+        );
+        // When validated blocks follow failed ones, the two metrics differ: report both.
+        if other_size != size {
+            println!(
+                "{}: {}",
+                console::style(other_label).bold(),
+                size::format_bytes(other_size)
+            );
+        }
+        if tested_size < drive.get_size() {
+            println!(
+                "{}",
+                console::style(
+                    "The validated size is inferred from the sampled blocks: it does not guarantee \
+                     that every byte in that range can be stored."
+                )
+                .dim()
+            );
+        }
+        // The contiguous validated size, refined with --find-breakpoint.
+        let mut contiguous_size = validated_drive_size;
+        // As only sampled blocks are tested, the actual end of the storage lies somewhere between
+        // the end of the highest validated block and the beginning of the following (failed) block.
+        let first_failed_block_idx = validation_map
+            .iter()
+            .enumerate()
+            .skip((highest_validated_block_idx + 1) as usize)
+            .find(|(_, report)| {
+                !matches!(
+                    report,
+                    BlockReport::Skipped | BlockReport::Excluded | BlockReport::Unverified
+                )
+            })
+            .map(|(i, _)| i);
+        if let Some(first_failed_block_idx) = first_failed_block_idx {
+            for b in spot_blocks.iter() {
+                if b.idx == first_failed_block_idx {
+                    let capacity_upper_bound = b.num * cli.block_size();
+                    println!(
+                        "{}: between {} and {}",
+                        console::style("Estimated real capacity").bold(),
+                        size::format_bytes(validated_drive_size),
+                        size::format_bytes(capacity_upper_bound)
+                    );
+                    if cli.find_breakpoint {
+                        let keystream = new_keystream(cli, data_file.as_ref(), &mut rng, run_id);
+                        let last_good = find_breakpoint(
+                            drive.deref_mut(),
+                            cli,
+                            (validated_drive_size / cli.block_size()).checked_sub(1),
+                            b.num,
+                            &keystream,
+                            &io_options,
+                        );
+                        let refined_size = last_good.map_or(0, |num| (num + 1) * cli.block_size());
+                        println!(
+                            "{}: {}",
+                            console::style("Refined validated drive size").bold(),
+                            size::format_bytes(refined_size)
+                        );
+                        contiguous_size = refined_size;
+                        if cli.capacity_metric == CapacityMetric::Contiguous {
+                            validated_size_option = Some(refined_size);
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        print_diagnosis(&validation_map);
+        if let Some(report) = &f3_report {
+            // f3probe measures the size usable from the beginning of the drive, f3read the total
+            // size of the data read back intact.
+            let size = match report.tool {
+                f3::Tool::Probe => contiguous_size,
+                f3::Tool::Read => total_validated_size,
+            };
+            print_f3_comparison(report, size, drive.get_size().div_ceil(num_blocks as u64));
+        }
+    } else if f3_report.is_some() {
+        log::warn!("No validated size is estimated by this test: it cannot be compared with f3");
+    }
+    if let Some(previous_reports) = &previous_reports {
+        print_retest_summary(
+            &spot_blocks,
+            previous_reports,
+            &validation_map,
+            cli.block_size(),
+        );
+    }
+    if cli.double_read {
+        println!(
+            "{}: {}",
+            console::style("Unstable blocks").bold(),
+            validation_map
+                .iter()
+                .filter(|report| **report == BlockReport::Unstable)
+                .count()
+        );
+    }
 
-    // Record any write error in the validation map.
-    for i in 0..cli.num_blocks {
-        if random_blocks.errors[i] == IoError::WriteError {
-            validation_map[spot_blocks[i].idx] = BlockReport::WriteError;
+    if let Some(mut orig_data) = orig_data_option {
+        restore_original(
+            drive.deref_mut(),
+            &original_blocks,
+            &mut orig_data,
+            cli.verify_restore,
+            &io_options,
+        );
+    }
+    // Without O_SYNC, the restored content may still be in the drive cache.
+    if cli.no_sync {
+        if let Err(err) = drive.flush_caches() {
+            log::warn!("Cannot flush the data written to {}: {:#}", drive_path, err);
         }
     }
 
+    print_phase_comparison(&phases);
+    export_timings(cli, drive_path, &phases);
+    if cli.no_verify {
+        println!(
+            "{}",
+            console::style(format!(
+                "NOT VERIFIED: {} blocks written, their content was not read back",
+                validation_map
+                    .iter()
+                    .filter(|report| **report != BlockReport::WriteError)
+                    .count()
+            ))
+            .yellow()
+            .bold()
+        );
+    } else if cli.canary {
+        print_canary_verdict(&validation_map[0]);
+    } else {
+        print_verdict(
+            &validation_map,
+            validated_size_option,
+            drive.get_size(),
+            cli.min_capacity,
+        );
+    }
+
+    let below_min_capacity = validated_size_option
+        .zip(cli.min_capacity)
+        .is_some_and(|(validated_size, min_capacity)| validated_size < min_capacity);
+    let passed = if cli.no_verify {
+        !validation_map.contains(&BlockReport::WriteError)
+    } else {
+        validation_map.iter().all(|report| {
+            matches!(
+                report,
+                BlockReport::Validated | BlockReport::Excluded | BlockReport::Unverified
+            )
+        })
+    };
+    Ok(TestOutcome {
+        passed: !below_min_capacity && passed,
+        device_info,
+        validated_size: validated_size_option,
+        below_min_capacity,
+        validation_map,
+        phases,
+    })
+}
+
+/// Run the benchmark on a memory device of `size_mib` MiB.
+fn benchmark(size_mib: u64, options: &TestOptions) -> Result<()> {
+    let progress = progress::ProgressEvents::new(options.progress_fd)?;
     println!(
         "{}",
-        console::style("\nReading blocks with random data").bold()
+        console::style(format!("Benchmarking on a {} MiB memory device", size_mib)).bold()
     );
-    let read_random_blocks = read_blocks(
-        drive.deref_mut(),
-        &spot_blocks,
-        cli.block_size_kb as usize * 1024,
+    let start = Instant::now();
+    run_test(
+        options,
+        "memory",
+        device::open_memory(size_mib * 1024 * 1024),
+        progress,
+    )?;
+    println!(
+        "\nBenchmark completed in {:.3} s. The throughput above is the maximum achievable by the \
+         tool on this system with the same options.",
+        start.elapsed().as_secs_f64()
     );
+    Ok(())
+}
 
-    // Fill the validation map.
-    for i in 0..cli.num_blocks {
-        if random_blocks.errors[i] == IoError::WriteError {
-            validation_map[spot_blocks[i].idx] = BlockReport::WriteError;
-        } else if read_random_blocks.errors[i] == IoError::ReadError {
-            validation_map[spot_blocks[i].idx] = BlockReport::ReadError;
-        } else if read_random_blocks.block(i) == random_blocks.block(i) {
-            validation_map[spot_blocks[i].idx] = BlockReport::Validated;
-        } else {
-            validation_map[spot_blocks[i].idx] = BlockReport::NoStorage;
+/// Print the information about the drives in `args`.
+fn print_drives_info(args: &InfoArgs) -> Result<()> {
+    let drives = &args.drives.drives;
+    for drive_path in drives.iter() {
+        if drives.len() > 1 {
+            println!(
+                "{}",
+                console::style(format!("\n===== Drive {} =====", drive_path))
+                    .bold()
+                    .cyan()
+            );
+        }
+        let result = print_drive_info(drive_path);
+        // sysfs can be dumped even when the drive cannot be opened.
+        if args.dump_sysfs {
+            println!("{}", console::style("\nsysfs:").bold());
+            device::dump_sysfs(drive_path)?;
         }
+        result?;
     }
+    Ok(())
+}
 
-    print_validation_map(&validation_map, cli.map_width);
+/// Test the drives in `args` in sequence, and print a summary if there are multiple drives.
+/// Returns an error if any drive failed the test.
+fn test_drives(mut args: TestArgs) -> Result<()> {
+    let drives = &args.drives.drives;
+    let progress = progress::ProgressEvents::new(args.options.progress_fd)?;
+    args.options.multiple_drives = drives.len() > 1;
+    args.options.multiple_block_sizes = args.options.block_sizes().len() > 1;
+    // The original content is not restored after a wipe, so there is no need to read it.
+    args.options.no_read_original |= args.options.wipe;
+    args.options.apply_phases()?;
+    let cli = &args.options;
+    if cli.summary_only && cli.progress_fd == Some(libc::STDOUT_FILENO) {
+        return Err(anyhow!(
+            "--progress-fd 1 cannot be used with --summary-only"
+        ));
+    }
 
-    // Find highest validated block (where all previous blocks are also validated).
-    let mut highest_validated_block_idx = -1;
-    for (i, v) in validation_map.iter().enumerate() {
-        if *v != BlockReport::Validated {
+    let mut num_passed = 0;
+    let mut num_failed = 0;
+    let mut num_below_min_capacity = 0;
+    let mut junit_reports = Vec::new();
+    for (i, drive_path) in drives.iter().enumerate() {
+        if drives.len() > 1 && !cli.summary_only {
+            println!(
+                "{}",
+                console::style(format!(
+                    "\n===== Drive {} ({}/{}) =====",
+                    drive_path,
+                    i + 1,
+                    drives.len()
+                ))
+                .bold()
+                .cyan()
+            );
+        }
+        progress.drive(drive_path, i + 1, drives.len());
+        let outcome = if cli.summary_only {
+            let silenced = silence::silence_stdout()?;
+            let outcome = test_drive(cli, drive_path, progress);
+            drop(silenced);
+            print_summary_line(drive_path, &outcome);
+            outcome
+        } else {
+            test_drive(cli, drive_path, progress)
+        };
+        if cli.junit.is_some() {
+            junit_reports.push(junit_report(cli, drive_path, &outcome));
+        }
+        match outcome {
+            Ok(outcome) if outcome.passed => num_passed += 1,
+            Ok(outcome) => {
+                num_failed += 1;
+                if outcome.below_min_capacity {
+                    num_below_min_capacity += 1;
+                }
+            }
+            // With a single drive, report the error as before.
+            Err(err) if drives.len() == 1 => {
+                export_junit(cli, &junit_reports);
+                return Err(err);
+            }
+            Err(err) => {
+                log::error!("Error testing {}: {:#}", drive_path, err);
+                num_failed += 1;
+            }
+        }
+        if interrupt::is_interrupted() {
             break;
         }
-        highest_validated_block_idx = i as i64;
     }
-    let mut validated_drive_size = 0;
-    if highest_validated_block_idx >= 0 {
-        for b in spot_blocks.iter() {
-            if b.idx == highest_validated_block_idx as usize {
-                // The validated drive size is the equal to the end of this block,
-                // i.e. the beginning offset of the following block.
-                validated_drive_size = (b.num + 1) * cli.block_size_kb * 1024;
-                break;
+
+    export_junit(cli, &junit_reports);
+    if drives.len() > 1 && !cli.summary_only {
+        println!(
+            "{}: {} passed, {} failed",
+            console::style("\nSummary").bold(),
+            num_passed,
+            num_failed
+        );
+    }
+    if num_below_min_capacity > 0 {
+        return Err(BelowMinCapacityError {
+            num_drives: num_below_min_capacity,
+        }
+        .into());
+    }
+    if num_failed > 0 {
+        return Err(anyhow!(
+            "{} of {} drive(s) failed validation",
+            num_failed,
+            drives.len()
+        ));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logger::init(cli.verbose)?;
+    size::set_units(cli.units);
+    interrupt::install_handler()?;
+    let result = match cli.command {
+        None => test_drives(cli.test),
+        Some(Command::Test(args)) => test_drives(args),
+        Some(Command::Verify(mut args)) => {
+            if args.options.no_read_original {
+                return Err(anyhow!("--no-read-original cannot be used with verify"));
             }
+            args.options.read_only = true;
+            test_drives(args)
         }
+        Some(Command::Info(args)) => print_drives_info(&args),
+        Some(Command::List) => print_device_list(),
+        Some(Command::Diff { old, new }) => results::diff(&old, &new),
+        Some(Command::Benchmark { size_mib, options }) => benchmark(size_mib, &options),
+    };
+    // An interrupted test exits with the status of a process killed by the signal, once the
+    // original blocks content has been restored and the partial results printed.
+    if let Some(status) = interrupt::exit_status_if_interrupted() {
+        if let Err(err) = &result {
+            eprintln!("Error: {:#}", err);
+        }
+        std::process::exit(status);
     }
-    println!(
-        "{}: {} bytes ({:.3} GiB, {:.3} GB)",
-        console::style("Validated drive size").bold(),
-        validated_drive_size,
-        validated_drive_size as f64 / 1024.0 / 1024.0 / 1024.0,
-        validated_drive_size as f64 / 1000_000_000.0
-    );
+    if let Err(err) = &result {
+        if err.is::<BelowMinCapacityError>() {
+            eprintln!("Error: {:#}", err);
+            std::process::exit(EXIT_STATUS_BELOW_MIN_CAPACITY);
+        }
+    }
+    result
+}
 
-    if let Some(mut orig_data) = orig_data_option {
-        println!("{}", console::style("\nWriting original blocks").bold());
-        write_blocks(drive.deref_mut(), &spot_blocks, &mut orig_data);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A device backed by a memory buffer shared with the test, so that its content can be checked
+    /// after the test consumed the device. The offsets written are recorded.
+    struct SharedMemoryDevice {
+        data: Rc<RefCell<Vec<u8>>>,
+        written: Rc<RefCell<Vec<u64>>>,
+        device_info: device::DeviceInfo,
+    }
+
+    impl device::Device for SharedMemoryDevice {
+        fn get_size(&self) -> u64 {
+            self.data.borrow().len() as u64
+        }
+
+        fn get_device_info(&mut self) -> Result<&device::DeviceInfo> {
+            Ok(&self.device_info)
+        }
+
+        fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<Duration> {
+            let device_data = self.data.borrow();
+            let start = offset as usize;
+            let len = data.len().min(device_data.len() - start);
+            data[..len].copy_from_slice(&device_data[start..start + len]);
+            data[len..].fill(0);
+            Ok(Duration::ZERO)
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<Duration> {
+            let mut device_data = self.data.borrow_mut();
+            let start = offset as usize;
+            let len = data.len().min(device_data.len() - start);
+            device_data[start..start + len].copy_from_slice(&data[..len]);
+            self.written.borrow_mut().push(offset);
+            Ok(Duration::ZERO)
+        }
+
+        fn get_memory_alignment(&self) -> usize {
+            0
+        }
+
+        fn set_io_timeout(&mut self, _timeout: Option<Duration>) {}
+
+        fn set_reconnect_wait(&mut self, _wait: Option<Duration>) {}
+
+        fn discard(&mut self, _offset: u64, _len: u64) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn flush_caches(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reconnections(&self) -> usize {
+            0
+        }
+
+        fn temperature(&self) -> Option<f64> {
+            None
+        }
+    }
+
+    /// Run the test with the command line `args` against a device of `size` bytes filled with
+    /// random content, and check that the test passes and leaves the content of the device
+    /// unchanged, at the tested offsets and elsewhere.
+    /// Returns the offsets of the writes, in order.
+    fn assert_restored(size: usize, args: &[&str]) -> Vec<u64> {
+        let mut original = vec![0; size];
+        rand_chacha::ChaCha8Rng::seed_from_u64(0).fill(&mut original[..]);
+        let data = Rc::new(RefCell::new(original.clone()));
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let drive = SharedMemoryDevice {
+            data: data.clone(),
+            written: written.clone(),
+            device_info: device::DeviceInfo::new(),
+        };
+        let cli = Cli::try_parse_from(["valixdrive", "--drive", "mock"].iter().chain(args))
+            .unwrap()
+            .test
+            .options;
+        let outcome = run_test(
+            &cli,
+            "mock",
+            Box::new(drive),
+            progress::ProgressEvents::new(None).unwrap(),
+        )
+        .unwrap();
+
+        assert!(outcome.passed);
+        assert!(!written.borrow().is_empty());
+        let data = data.borrow();
+        for &offset in written.borrow().iter() {
+            let start = offset as usize;
+            let end = size.min(start + cli.block_size() as usize);
+            assert!(
+                data[start..end] == original[start..end],
+                "block at offset {} not restored",
+                offset
+            );
+        }
+        assert!(*data == original);
+        written.take()
+    }
+
+    /// Run a test with the command line `args` on a memory device holding `data`, check that it is
+    /// rejected without writing anything, and return the error.
+    fn assert_rejected(data: Vec<u8>, args: &[&str]) -> anyhow::Error {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let drive = SharedMemoryDevice {
+            data: Rc::new(RefCell::new(data)),
+            written: written.clone(),
+            device_info: device::DeviceInfo::new(),
+        };
+        let cli = Cli::try_parse_from(["valixdrive", "--drive", "mock"].iter().chain(args))
+            .unwrap()
+            .test
+            .options;
+        let result = run_test(
+            &cli,
+            "mock",
+            Box::new(drive),
+            progress::ProgressEvents::new(None).unwrap(),
+        );
+
+        assert!(written.borrow().is_empty());
+        result.err().expect("the test must be rejected")
+    }
+
+    #[test]
+    fn restore_leaves_device_identical() {
+        assert_restored(4 << 20, &["--num-blocks", "64", "--block-size-kb", "4"]);
+    }
+
+    #[test]
+    fn canary_writes_only_last_block() {
+        let written = assert_restored(4 << 20, &["--block-size-kb", "4", "--canary"]);
+        // The random data, then the original content.
+        assert_eq!(written, [(4 << 20) - 4096; 2]);
+    }
+
+    #[test]
+    fn immediate_verify_leaves_device_identical() {
+        assert_restored(
+            4 << 20,
+            &[
+                "--num-blocks",
+                "64",
+                "--block-size-kb",
+                "4",
+                "--immediate-verify",
+            ],
+        );
+    }
+
+    #[test]
+    fn restore_leaves_device_identical_with_partial_last_block() {
+        assert_restored(
+            (4 << 20) + 1000,
+            &[
+                "--num-blocks",
+                "64",
+                "--block-size-kb",
+                "4",
+                "--repeat",
+                "2",
+            ],
+        );
+    }
+
+    #[test]
+    fn block_size_larger_than_device_is_rejected() {
+        assert_rejected(vec![0; 1 << 20], &["--block-size", "2M"]);
+    }
+
+    #[test]
+    fn empty_device_is_rejected() {
+        let err = assert_rejected(Vec::new(), &[]);
+        assert!(err.to_string().contains("size of 0 bytes"));
+    }
+
+    #[test]
+    fn f3_output_is_parsed() {
+        let probe = "Bad news: The device `/dev/sdb' is a counterfeit of type limbo\n\n\
+            Device geometry:\n\
+            \t         *Usable* size: 7.86 GB (16477879 blocks)\n\
+            \t        Announced size: 15.33 GB (32155648 blocks)\n\
+            \t   Physical block size: 512.00 Byte (2^9 Bytes)\n";
+        assert_eq!(
+            f3::parse(probe).unwrap(),
+            f3::Report {
+                tool: f3::Tool::Probe,
+                usable_size: 16477879 * 512,
+                verdict: Some(
+                    "Bad news: The device `/dev/sdb' is a counterfeit of type limbo".to_string()
+                ),
+            }
+        );
+        let read = "  Data OK: 7.86 GB (16477879 sectors)\nData LOST: 7.46 GB (15644969 sectors)\n";
+        assert_eq!(
+            f3::parse(read).unwrap(),
+            f3::Report {
+                tool: f3::Tool::Read,
+                usable_size: 16477879 * 512,
+                verdict: None,
+            }
+        );
+        assert!(f3::parse("Probe time: 1'13\"\n").is_err());
+    }
+
+    #[test]
+    fn thermal_throttling_needs_a_temperature_rise() {
+        let sample = |secs, temperature, mbps: f64| thermal::Sample {
+            elapsed: Duration::from_secs(secs),
+            temperature,
+            bytes_per_sec: mbps * 1_000_000.0,
+        };
+        // A throughput drop without heating, e.g. when an SLC cache fills up, is not throttling.
+        let cache_full = [sample(5, 40.0, 400.0), sample(10, 41.0, 80.0)];
+        assert_eq!(thermal::find_throttling(&cache_full), None);
+        let throttled = [
+            sample(5, 40.0, 300.0),
+            sample(10, 45.0, 400.0),
+            sample(15, 55.0, 350.0),
+            sample(20, 70.0, 100.0),
+        ];
+        assert_eq!(
+            thermal::find_throttling(&throttled),
+            Some((&throttled[1], &throttled[3]))
+        );
+    }
+
+    /// Check that the blocks selected on a drive of `num_drive_blocks` blocks are distinct, in
+    /// ascending order, and each the nearest to the end of its area of the drive.
+    fn assert_spot_blocks(num_blocks: usize, num_drive_blocks: u64) {
+        let spot_blocks = select_spot_blocks(num_blocks, num_drive_blocks);
+
+        assert_eq!(spot_blocks.len(), num_blocks);
+        assert_eq!(spot_blocks.last().unwrap().num, num_drive_blocks - 1);
+        for (i, block_idx) in spot_blocks.iter().enumerate() {
+            assert_eq!(block_idx.idx, i);
+            assert!(i == 0 || spot_blocks[i - 1].num < block_idx.num);
+            let area_end = (i as u128 + 1) * num_drive_blocks as u128;
+            let block_end = (block_idx.num as u128 + 1) * num_blocks as u128;
+            assert!(
+                2 * area_end.abs_diff(block_end) <= num_blocks as u128,
+                "block {} selected for area {}",
+                block_idx.num,
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn spot_blocks_cover_whole_drive() {
+        assert_spot_blocks(4096, 4096);
+        assert_spot_blocks(576, 4096);
+        assert_spot_blocks(3, 10);
+    }
+
+    #[test]
+    fn spot_blocks_on_16_tb_drive() {
+        // 16 TB in blocks of 512 bytes, with areas ending beyond the precision of a f64.
+        let num_drive_blocks = 16_000_000_000_000 / 512;
+        assert_spot_blocks(576, num_drive_blocks);
+        assert_spot_blocks(1_000_003, num_drive_blocks);
+        assert_spot_blocks(576, u64::MAX / 512);
+    }
+
+    #[test]
+    fn mismatch_describes_content_read_back() {
+        let expected: Vec<u8> = (0..=255).collect();
+        assert_eq!(mismatch::describe(&expected, &[0; 256]), "all zeros");
+        assert_eq!(
+            mismatch::describe(&expected, &[0xff; 256]),
+            "every byte is 0xff"
+        );
+        let mut shifted = vec![0; 3];
+        shifted.extend_from_slice(&expected[..253]);
+        assert_eq!(
+            mismatch::describe(&expected, &shifted),
+            "255 of 256 bytes differ, from byte 1: the expected data shifted by 3 bytes"
+        );
+        assert_eq!(
+            mismatch::describe(&expected, &expected[5..]),
+            "251 of 251 bytes differ, from byte 0: the expected data shifted by -5 bytes"
+        );
+        let mut flipped = expected.clone();
+        flipped[100] ^= 1;
+        assert_eq!(
+            mismatch::describe(&expected, &flipped),
+            "1 of 256 bytes differ, from byte 100"
+        );
     }
-    Ok(())
 }
@@ -0,0 +1,134 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Export of the validation map to files, for visualization tools.
+//! The map is laid out in physical block order, i.e. in the order of the blocks on the drive.
+use anyhow::{Context, Result};
+use flate2::{write::ZlibEncoder, Compression};
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{checksum::crc32, compressed, BlockReport};
+
+/// The size in pixels of the square representing each block in PNG maps.
+const PNG_BLOCK_SIZE: usize = 8;
+
+/// Return the code used to represent `report` in exported maps. It is also the index of the
+/// corresponding color in the palette of PNG maps.
+fn report_code(report: &BlockReport) -> u8 {
+    match report {
+        BlockReport::Unknown => 0,
+        BlockReport::Validated => 1,
+        BlockReport::ReadError => 2,
+        BlockReport::ReadSuccessful => 3,
+        BlockReport::WriteError => 4,
+        BlockReport::NoStorage => 5,
+        BlockReport::Unstable => 6,
+        BlockReport::Skipped => 7,
+        BlockReport::Excluded => 8,
+        BlockReport::Unverified => 9,
+    }
+}
+
+/// The colors (RGB) of the PNG palette, indexed by report code. They match the colors used by the
+/// validation map printed on the terminal.
+const PALETTE: [[u8; 3]; 10] = [
+    [0xc0, 0xc0, 0xc0], // Unknown: gray
+    [0x00, 0xc0, 0x00], // Validated: green
+    [0x00, 0x00, 0xff], // Read error: blue
+    [0x80, 0xff, 0x80], // Read successful: light green
+    [0xff, 0xd0, 0x00], // Write error: yellow
+    [0xff, 0x00, 0x00], // No storage: red
+    [0xff, 0x00, 0xff], // Unstable: magenta
+    [0x00, 0xc0, 0xc0], // Skipped: cyan
+    [0x40, 0x40, 0x40], // Excluded: dark gray
+    [0x00, 0x60, 0x00], // Unverified: dark green
+];
+
+/// Description of the report codes, stored in PNG maps.
+const LEGEND: &str = "0: unknown (gray), 1: validated (green), 2: read error (blue), \
+    3: read successful (light green), 4: write error (yellow), 5: no storage (red), \
+    6: unstable (magenta), 7: skipped (cyan), 8: excluded (dark gray), \
+    9: unverified (dark green)";
+
+/// Write `validation_map` to the file at `path`.
+/// If the file name ends with `.png` (or `.png.gz`), a PNG image is written, with `map_width`
+/// blocks per row and each block drawn as a colored square. Otherwise, a binary file is written,
+/// with one byte per block containing the report code.
+pub fn write_map(path: &Path, validation_map: &[BlockReport], map_width: usize) -> Result<()> {
+    let codes: Vec<u8> = validation_map.iter().map(report_code).collect();
+    let is_png = compressed::strip_gz(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+    let content = if is_png {
+        encode_png(&codes, map_width.max(1)).context("encoding the validation map as PNG")?
+    } else {
+        codes
+    };
+    compressed::write(path, content).context(format!("writing validation map to {:?}", path))
+}
+
+/// Encode the report `codes` as a PNG image with a palette, with `map_width` blocks per row.
+fn encode_png(codes: &[u8], map_width: usize) -> io::Result<Vec<u8>> {
+    let width = map_width.min(codes.len().max(1)) * PNG_BLOCK_SIZE;
+    let height = codes.len().div_ceil(map_width).max(1) * PNG_BLOCK_SIZE;
+
+    // Each row of the image starts with the filter type (0, none), followed by one palette index
+    // per pixel. Pixels past the last block are left as "unknown".
+    let mut pixels = Vec::with_capacity((width + 1) * height);
+    for y in 0..height {
+        pixels.push(0);
+        for x in 0..width {
+            let i = y / PNG_BLOCK_SIZE * map_width + x / PNG_BLOCK_SIZE;
+            pixels.push(codes.get(i).copied().unwrap_or(0));
+        }
+    }
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, color type 3 (palette), default compression, filter and interlace methods.
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"PLTE", PALETTE.as_flattened());
+    let mut text = b"Comment\0".to_vec();
+    text.extend_from_slice(LEGEND.as_bytes());
+    write_png_chunk(&mut png, b"tEXt", &text);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&pixels)?;
+    write_png_chunk(&mut png, b"IDAT", &encoder.finish()?);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+/// Append a PNG chunk of type `chunk_type` containing `data` to `png`.
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
@@ -0,0 +1,123 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Description of how the data read back from a block differs from the data written to it, to help
+//! diagnosing how a drive fails to store data.
+
+/// The number of bytes shown side by side.
+const DUMP_LEN: usize = 64;
+
+/// The number of bytes in each row of the side by side dump.
+const ROW_LEN: usize = 8;
+
+/// The number of bytes searched for to recognize shifted data.
+const SHIFT_PROBE_LEN: usize = 16;
+
+/// Return a short description of `actual`, read back instead of `expected`.
+pub fn describe(expected: &[u8], actual: &[u8]) -> String {
+    if let Some(&first) = actual.first() {
+        if actual.iter().all(|&byte| byte == first) {
+            return if first == 0 {
+                String::from("all zeros")
+            } else {
+                format!("every byte is 0x{:02x}", first)
+            };
+        }
+    }
+    let num_different = expected
+        .iter()
+        .zip(actual)
+        .filter(|(expected, actual)| expected != actual)
+        .count();
+    let first_different = expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or_default();
+    let mut description = format!(
+        "{} of {} bytes differ, from byte {}",
+        num_different,
+        actual.len(),
+        first_different
+    );
+    if let Some(shift) = find_shift(expected, actual) {
+        description.push_str(&format!(": the expected data shifted by {} bytes", shift));
+    }
+    description
+}
+
+/// Return the number of bytes by which `expected` is shifted in `actual`, positive if it starts
+/// later in `actual`, if the beginning of either is found in the other.
+fn find_shift(expected: &[u8], actual: &[u8]) -> Option<i64> {
+    if expected.len() < SHIFT_PROBE_LEN || actual.len() < SHIFT_PROBE_LEN {
+        return None;
+    }
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(SHIFT_PROBE_LEN)
+            .position(|window| window == &needle[..SHIFT_PROBE_LEN])
+            .filter(|&position| position > 0)
+    };
+    find(actual, expected)
+        .map(|position| position as i64)
+        .or_else(|| find(expected, actual).map(|position| -(position as i64)))
+}
+
+/// Return the lines showing the first DUMP_LEN bytes of `expected` and `actual` side by side, in
+/// hexadecimal, with the bytes of `actual` that differ highlighted.
+pub fn side_by_side(expected: &[u8], actual: &[u8]) -> Vec<String> {
+    let hex_width = ROW_LEN * 3 - 1;
+    let mut lines = vec![format!(
+        "{:<6}  {:<width$}  read back",
+        "offset",
+        "expected",
+        width = hex_width
+    )];
+    let len = DUMP_LEN.min(expected.len()).min(actual.len());
+    for start in (0..len).step_by(ROW_LEN) {
+        let end = (start + ROW_LEN).min(len);
+        let expected_hex = expected[start..end]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let actual_hex = (start..end)
+            .map(|i| {
+                let hex = console::style(format!("{:02x}", actual[i]));
+                if actual[i] == expected[i] {
+                    hex.to_string()
+                } else {
+                    hex.red().bold().to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        lines.push(format!(
+            "{:06x}  {:<width$}  {}",
+            start,
+            expected_hex,
+            actual_hex,
+            width = hex_width
+        ));
+    }
+    lines
+}
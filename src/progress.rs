@@ -0,0 +1,123 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Machine-parseable progress events, for frontends that render their own progress UI.
+//! Events are written as newline-delimited JSON objects to a file descriptor, independently of
+//! the progress bars shown on the terminal.
+use anyhow::{anyhow, Result};
+use std::{
+    fs::File,
+    io::Write,
+    mem::ManuallyDrop,
+    os::fd::{FromRawFd, RawFd},
+};
+
+/// Emitter of progress events. If no file descriptor is configured, no event is emitted.
+#[derive(Clone, Copy)]
+pub struct ProgressEvents {
+    fd: Option<RawFd>,
+}
+
+impl ProgressEvents {
+    /// Create a new emitter writing events to `fd`, if any.
+    pub fn new(fd: Option<RawFd>) -> Result<Self> {
+        if let Some(fd) = fd {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                return Err(anyhow!(
+                    "invalid progress file descriptor {}: {}",
+                    fd,
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        Ok(Self { fd })
+    }
+
+    /// Emit the event for the start of the test of drive `path`, the `index`-th (starting from 1)
+    /// of `total` drives.
+    pub fn drive(&self, path: &str, index: usize, total: usize) {
+        self.emit(&format!(
+            r#"{{"event":"drive","path":{},"index":{},"total":{}}}"#,
+            json_string(path),
+            index,
+            total
+        ));
+    }
+
+    /// Emit the event for the start of `phase`, which tests `blocks_total` blocks.
+    pub fn phase(&self, phase: &str, blocks_total: usize) {
+        self.emit(&format!(
+            r#"{{"event":"phase","phase":{},"blocks_total":{}}}"#,
+            json_string(phase),
+            blocks_total
+        ));
+    }
+
+    /// Emit the event for the completion of a block I/O during `phase`, at byte `offset` of the
+    /// drive. `throughput` is the running throughput of the phase in bytes per second.
+    pub fn block(
+        &self,
+        phase: &str,
+        blocks_done: usize,
+        blocks_total: usize,
+        offset: u64,
+        throughput: f64,
+    ) {
+        self.emit(&format!(
+            r#"{{"event":"progress","phase":{},"blocks_done":{},"blocks_total":{},"offset":{},"throughput_mbps":{:.3}}}"#,
+            json_string(phase),
+            blocks_done,
+            blocks_total,
+            offset,
+            throughput / 1_000_000.0
+        ));
+    }
+
+    /// Write `event` followed by a newline to the file descriptor.
+    fn emit(&self, event: &str) {
+        if let Some(fd) = self.fd {
+            // The file descriptor is not owned, so it must not be closed when the file is dropped.
+            let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+            // Progress events are best effort: a frontend going away must not stop the test.
+            let _ = writeln!(file, "{}", event);
+        }
+    }
+}
+
+/// Return `s` as a quoted JSON string, escaping the characters that need to be escaped.
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,322 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Per-block test results saved as CSV files, and comparison of two result files of the same drive
+//! to track its degradation over time.
+//! Each row describes a tested block: its index in the validation map, its number and byte range
+//! on the drive, and its validation result.
+//! The duration of the I/O operations on each block can also be saved as CSV files, for external
+//! analysis.
+use anyhow::{anyhow, Context, Result};
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use crate::{compressed, BlockReport};
+
+/// The header line of result files.
+const HEADER: &str = "idx,block,offset,size,state";
+
+/// The header line of checksum files.
+const CHECKSUMS_HEADER: &str = "block,offset,size,crc32";
+
+/// The header line of timing files.
+const TIMINGS_HEADER: &str = "phase,seq,block,offset,duration_ns";
+
+/// The result of the test of a block.
+pub struct BlockResult {
+    /// The index of the block in the validation map.
+    pub idx: usize,
+    /// The block number on the drive.
+    pub block: u64,
+    /// The offset of the block on the drive, in bytes.
+    pub offset: u64,
+    /// The size of the block in bytes, which may be lower than the block size for the last block
+    /// of the drive.
+    pub size: u64,
+    pub report: BlockReport,
+}
+
+/// Return the name used to represent `report` in result files.
+pub fn report_name(report: &BlockReport) -> &'static str {
+    match report {
+        BlockReport::Unknown => "Unknown",
+        BlockReport::Validated => "Validated",
+        BlockReport::ReadError => "ReadError",
+        BlockReport::ReadSuccessful => "ReadSuccessful",
+        BlockReport::WriteError => "WriteError",
+        BlockReport::NoStorage => "NoStorage",
+        BlockReport::Unstable => "Unstable",
+        BlockReport::Skipped => "Skipped",
+        BlockReport::Excluded => "Excluded",
+        BlockReport::Unverified => "Unverified",
+    }
+}
+
+/// Return the report represented by `name` in result files.
+fn parse_report(name: &str) -> Option<BlockReport> {
+    Some(match name {
+        "Unknown" => BlockReport::Unknown,
+        "Validated" => BlockReport::Validated,
+        "ReadError" => BlockReport::ReadError,
+        "ReadSuccessful" => BlockReport::ReadSuccessful,
+        "WriteError" => BlockReport::WriteError,
+        "NoStorage" => BlockReport::NoStorage,
+        "Unstable" => BlockReport::Unstable,
+        "Skipped" => BlockReport::Skipped,
+        "Excluded" => BlockReport::Excluded,
+        "Unverified" => BlockReport::Unverified,
+        _ => return None,
+    })
+}
+
+/// Write `results` to the CSV file at `path`.
+pub fn write_csv(path: &Path, results: &[BlockResult]) -> Result<()> {
+    let mut csv = String::from(HEADER);
+    csv.push('\n');
+    for result in results {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            result.idx,
+            result.block,
+            result.offset,
+            result.size,
+            report_name(&result.report)
+        ));
+    }
+    compressed::write(path, csv).context(format!("writing results to {:?}", path))
+}
+
+/// The duration of the read or write of a block in a test phase.
+pub struct BlockTiming<'a> {
+    /// The name of the test phase.
+    pub phase: &'a str,
+    /// The position of the block in the order the blocks were tested.
+    pub seq: usize,
+    /// The block number on the drive.
+    pub block: u64,
+    /// The offset of the block on the drive, in bytes.
+    pub offset: u64,
+    /// The duration of the read or write, exported in nanoseconds.
+    pub duration: Duration,
+}
+
+/// Write `timings` to the CSV file at `path`.
+pub fn write_timings_csv(path: &Path, timings: &[BlockTiming]) -> Result<()> {
+    let mut csv = String::from(TIMINGS_HEADER);
+    csv.push('\n');
+    for timing in timings {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            timing.phase,
+            timing.seq,
+            timing.block,
+            timing.offset,
+            timing.duration.as_nanos()
+        ));
+    }
+    compressed::write(path, csv).context(format!("writing timings to {:?}", path))
+}
+
+/// The checksum of the content written to a block.
+pub struct BlockChecksum {
+    /// The block number on the drive.
+    pub block: u64,
+    /// The offset of the block on the drive, in bytes.
+    pub offset: u64,
+    /// The size of the block in bytes.
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// Write `checksums` to the CSV file at `path`.
+pub fn write_checksums_csv(path: &Path, checksums: &[BlockChecksum]) -> Result<()> {
+    let mut csv = String::from(CHECKSUMS_HEADER);
+    csv.push('\n');
+    for checksum in checksums {
+        csv.push_str(&format!(
+            "{},{},{},{:08x}\n",
+            checksum.block, checksum.offset, checksum.size, checksum.crc32
+        ));
+    }
+    compressed::write(path, csv).context(format!("writing checksums to {:?}", path))
+}
+
+/// Read the checksums from the CSV file at `path`.
+pub fn read_checksums_csv(path: &Path) -> Result<Vec<BlockChecksum>> {
+    let content =
+        compressed::read_to_string(path).context(format!("reading checksums from {:?}", path))?;
+    let mut lines = content.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some(CHECKSUMS_HEADER) {
+        return Err(anyhow!("{:?} is not a valixdrive checksum file", path));
+    }
+    let mut checksums = Vec::new();
+    for (line_num, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || {
+            anyhow!(
+                "{:?} line {}: invalid checksum {:?}",
+                path,
+                line_num + 1,
+                line
+            )
+        };
+        let fields: Vec<&str> = line.trim().split(',').collect();
+        if fields.len() != 4 {
+            return Err(invalid());
+        }
+        checksums.push(BlockChecksum {
+            block: fields[0].parse().map_err(|_| invalid())?,
+            offset: fields[1].parse().map_err(|_| invalid())?,
+            size: fields[2].parse().map_err(|_| invalid())?,
+            crc32: u32::from_str_radix(fields[3], 16).map_err(|_| invalid())?,
+        });
+    }
+    Ok(checksums)
+}
+
+/// Read the results from the CSV file at `path`.
+pub fn read_csv(path: &Path) -> Result<Vec<BlockResult>> {
+    let content =
+        compressed::read_to_string(path).context(format!("reading results from {:?}", path))?;
+    let mut lines = content.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some(HEADER) {
+        return Err(anyhow!("{:?} is not a valixdrive result file", path));
+    }
+    let mut results = Vec::new();
+    for (line_num, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || {
+            anyhow!(
+                "{:?} line {}: invalid result {:?}",
+                path,
+                line_num + 1,
+                line
+            )
+        };
+        let fields: Vec<&str> = line.trim().split(',').collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+        results.push(BlockResult {
+            idx: fields[0].parse().map_err(|_| invalid())?,
+            block: fields[1].parse().map_err(|_| invalid())?,
+            offset: fields[2].parse().map_err(|_| invalid())?,
+            size: fields[3].parse().map_err(|_| invalid())?,
+            report: parse_report(fields[4]).ok_or_else(invalid)?,
+        });
+    }
+    results.sort_by_key(|result| result.idx);
+    Ok(results)
+}
+
+/// Return the validated size of the drive according to `results`: the end of the highest
+/// validated block, such that all the previous blocks are also validated (or skipped, excluded or
+/// not verified).
+pub fn validated_size(results: &[BlockResult]) -> u64 {
+    results
+        .iter()
+        .filter(|result| {
+            !matches!(
+                result.report,
+                BlockReport::Skipped | BlockReport::Excluded | BlockReport::Unverified
+            )
+        })
+        .take_while(|result| result.report == BlockReport::Validated)
+        .last()
+        .map_or(0, |result| result.offset + result.size)
+}
+
+/// Compare the result files `old_path` and `new_path` of the same drive, and print the blocks
+/// whose state changed and the difference in validated size.
+pub fn diff(old_path: &Path, new_path: &Path) -> Result<()> {
+    let old_results = read_csv(old_path)?;
+    let new_results = read_csv(new_path)?;
+    let old_by_block: BTreeMap<u64, &BlockResult> = old_results
+        .iter()
+        .map(|result| (result.block, result))
+        .collect();
+    let new_by_block: BTreeMap<u64, &BlockResult> = new_results
+        .iter()
+        .map(|result| (result.block, result))
+        .collect();
+
+    let mut num_changed = 0;
+    for (block, new_result) in new_by_block.iter() {
+        let Some(old_result) = old_by_block.get(block) else {
+            continue;
+        };
+        if old_result.report == new_result.report {
+            continue;
+        }
+        num_changed += 1;
+        let change = format!(
+            "Block {} (offset {}): {} -> {}",
+            block,
+            new_result.offset,
+            report_name(&old_result.report),
+            report_name(&new_result.report)
+        );
+        if old_result.report == BlockReport::Validated {
+            println!("{}", console::style(change).red());
+        } else if new_result.report == BlockReport::Validated {
+            println!("{}", console::style(change).green());
+        } else {
+            println!("{}", change);
+        }
+    }
+    let num_only_old = old_by_block
+        .keys()
+        .filter(|block| !new_by_block.contains_key(block))
+        .count();
+    let num_only_new = new_by_block
+        .keys()
+        .filter(|block| !old_by_block.contains_key(block))
+        .count();
+    if num_only_old > 0 || num_only_new > 0 {
+        log::warn!(
+            "The result files test different blocks: {} only in {:?}, {} only in {:?}",
+            num_only_old,
+            old_path,
+            num_only_new,
+            new_path
+        );
+    }
+
+    println!(
+        "{}: {}",
+        console::style("Blocks changing state").bold(),
+        num_changed
+    );
+    let old_size = validated_size(&old_results);
+    let new_size = validated_size(&new_results);
+    println!(
+        "{}: {} -> {} bytes ({})",
+        console::style("Validated drive size").bold(),
+        old_size,
+        new_size,
+        crate::size::format_delta(new_size as f64 - old_size as f64)
+    );
+    Ok(())
+}
@@ -0,0 +1,67 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Temporary silencing of stdout, so that only a summary is printed while the detailed output of
+//! the test is discarded.
+use anyhow::{anyhow, Result};
+use std::io::Write;
+
+/// While alive, stdout is redirected to /dev/null. The original stdout is restored when dropped.
+pub struct SilencedStdout {
+    /// A duplicate of the original stdout file descriptor.
+    saved_fd: libc::c_int,
+}
+
+/// Redirect stdout to /dev/null until the returned guard is dropped.
+pub fn silence_stdout() -> Result<SilencedStdout> {
+    std::io::stdout().flush()?;
+    let saved_fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if saved_fd == -1 {
+        return Err(anyhow!(
+            "duplicating stdout: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let null_fd = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if null_fd == -1 || unsafe { libc::dup2(null_fd, libc::STDOUT_FILENO) } == -1 {
+        let err = std::io::Error::last_os_error();
+        unsafe {
+            if null_fd != -1 {
+                libc::close(null_fd);
+            }
+            libc::close(saved_fd);
+        }
+        return Err(anyhow!("redirecting stdout to /dev/null: {}", err));
+    }
+    unsafe { libc::close(null_fd) };
+    Ok(SilencedStdout { saved_fd })
+}
+
+impl Drop for SilencedStdout {
+    fn drop(&mut self) {
+        let _ = std::io::stdout().flush();
+        unsafe {
+            libc::dup2(self.saved_fd, libc::STDOUT_FILENO);
+            libc::close(self.saved_fd);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Parsing of human-friendly sizes given on the command line, such as `4K` or `64GiB`, and their
+//! formatting, in the units selected with --units.
+
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const MIB: f64 = (1 << 20) as f64;
+const GIB: f64 = (1 << 30) as f64;
+const MB: f64 = 1_000_000.0;
+const GB: f64 = 1_000_000_000.0;
+
+/// The units in which sizes are displayed.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Units {
+    /// Powers of 1024 (MiB, GiB).
+    Binary,
+    /// Powers of 1000 (MB, GB), as advertised by drive manufacturers.
+    Decimal,
+    /// Both binary and decimal units, where there is room for both.
+    Both,
+}
+
+/// The units selected with `set_units`, as the discriminant of `Units`.
+static UNITS: AtomicU8 = AtomicU8::new(Units::Both as u8);
+
+/// Select the units in which sizes are displayed for the rest of the program.
+pub fn set_units(units: Units) {
+    UNITS.store(units as u8, Ordering::Relaxed);
+}
+
+/// Return the units selected with `set_units`.
+fn units() -> Units {
+    match UNITS.load(Ordering::Relaxed) {
+        value if value == Units::Binary as u8 => Units::Binary,
+        value if value == Units::Decimal as u8 => Units::Decimal,
+        _ => Units::Both,
+    }
+}
+
+/// Format `size` bytes followed by its value in GiB and/or GB, e.g. `1000000000 bytes (0.931 GiB,
+/// 1.000 GB)`.
+pub fn format_bytes(size: u64) -> String {
+    format!("{} bytes ({})", size, format_gb(size as f64, false))
+}
+
+/// Format the difference `delta` in bytes between two sizes in GiB and/or GB, with its sign.
+pub fn format_delta(delta: f64) -> String {
+    format_gb(delta, true)
+}
+
+/// Format `size` bytes compactly, in a single unit: GiB with binary units, GB otherwise, as drive
+/// capacities are advertised in GB.
+pub fn format_capacity(size: u64) -> String {
+    match units() {
+        Units::Binary => format!("{:.3} GiB", size as f64 / GIB),
+        Units::Decimal | Units::Both => format!("{:.3} GB", size as f64 / GB),
+    }
+}
+
+/// Format `size` bytes, a fraction of the drive, in MiB or MB.
+pub fn format_mib(size: f64) -> String {
+    match units() {
+        Units::Binary | Units::Both => format!("{:.3} MiB", size / MIB),
+        Units::Decimal => format!("{:.3} MB", size / MB),
+    }
+}
+
+/// Format `size` bytes in GiB and/or GB, always with its sign if `signed`.
+fn format_gb(size: f64, signed: bool) -> String {
+    let format = |value: f64, unit: &str| {
+        if signed {
+            format!("{:+.3} {}", value, unit)
+        } else {
+            format!("{:.3} {}", value, unit)
+        }
+    };
+    match units() {
+        Units::Binary => format(size / GIB, "GiB"),
+        Units::Decimal => format(size / GB, "GB"),
+        Units::Both => format!("{}, {}", format(size / GIB, "GiB"), format(size / GB, "GB")),
+    }
+}
+
+/// Parse `value` as a size in bytes: a number optionally followed by a unit suffix. `K`, `M`, `G`
+/// and `T`, with or without the `iB` ending, are powers of 1024, while `KB`, `MB`, `GB` and `TB`
+/// are powers of 1000. A trailing `B` alone means bytes. Suffixes are case-insensitive.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size {:?}: it must start with a number", value))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => {
+            return Err(format!(
+                "invalid size {:?}: unknown unit {:?}",
+                value, suffix
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid size {:?}: too large", value))
+}
+
+/// Parse `value` as a range of bytes `START:END`, with START and END in the syntax of `parse_size`.
+/// END is excluded, and must be greater than START.
+pub fn parse_range(value: &str) -> Result<Range<u64>, String> {
+    let Some((start, end)) = value.split_once(':') else {
+        return Err(format!("invalid range {:?}: expected START:END", value));
+    };
+    let range = parse_size(start)?..parse_size(end)?;
+    if range.is_empty() {
+        return Err(format!(
+            "invalid range {:?}: the end must be greater than the start",
+            value
+        ));
+    }
+    Ok(range)
+}
+
+/// Format `size` bytes with the largest binary unit that represents it exactly, e.g. `64K` or
+/// `1M`, in the syntax accepted by `parse_size`.
+pub fn format_size(size: u64) -> String {
+    for (shift, unit) in [(40, "T"), (30, "G"), (20, "M"), (10, "K")] {
+        if size != 0 && size.is_multiple_of(1 << shift) {
+            return format!("{}{}", size >> shift, unit);
+        }
+    }
+    size.to_string()
+}
+
+/// Parse `value` as a block size in bytes, with the same syntax as `parse_size`. The block size
+/// must be a positive multiple of 512 bytes, the smallest sector size of drives.
+pub fn parse_block_size(value: &str) -> Result<u64, String> {
+    let size = parse_size(value)?;
+    if size == 0 || size % 512 != 0 {
+        return Err(format!(
+            "invalid block size {:?}: it must be a positive multiple of 512 bytes",
+            value
+        ));
+    }
+    Ok(size)
+}
@@ -0,0 +1,122 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Progress output for progress bars when stderr is not a terminal, e.g. when redirected to a log
+//! file: instead of redrawing the bar in place with control sequences, the bar is printed as a new
+//! line at regular intervals, and once more when the bar finishes.
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A terminal-like draw target printing each drawn bar as a new line on stderr, at most once per
+/// interval, except for the final draw of a finished bar.
+#[derive(Debug)]
+pub struct TextProgress {
+    /// The minimum time between two printed lines.
+    interval: Duration,
+    /// When the last line was printed, if any.
+    last_printed: Mutex<Option<Instant>>,
+    /// Whether the bar being drawn is finished, recorded by the template key from `finished_key`.
+    finished: Arc<AtomicBool>,
+}
+
+impl TextProgress {
+    /// Create a target printing a line at most every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_printed: Mutex::new(None),
+            finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Return a progress bar template key rendering nothing, which records whether the bar is
+    /// finished so that its final draw is printed regardless of the interval. The key must be
+    /// part of the template of the bar drawn to this target.
+    pub fn finished_key(
+        &self,
+    ) -> impl Fn(&indicatif::ProgressState, &mut dyn std::fmt::Write) + Send + Sync + Clone {
+        let finished = self.finished.clone();
+        move |state, _| finished.store(state.is_finished(), Ordering::Relaxed)
+    }
+
+    /// Print `line` if it is not empty and no line was printed in the last interval, or if the
+    /// bar is finished.
+    fn print(&self, line: &str) -> io::Result<()> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        let mut last_printed = self.last_printed.lock().unwrap();
+        if !self.finished.load(Ordering::Relaxed)
+            && last_printed.is_some_and(|last| last.elapsed() < self.interval)
+        {
+            return Ok(());
+        }
+        *last_printed = Some(Instant::now());
+        eprintln!("{}", line.trim_end());
+        Ok(())
+    }
+}
+
+impl indicatif::TermLike for TextProgress {
+    fn width(&self) -> u16 {
+        // Only limits the length of the line, which is not wrapped in a log file.
+        u16::MAX
+    }
+
+    fn move_cursor_up(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.print(s)
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.print(s)
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
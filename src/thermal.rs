@@ -0,0 +1,152 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Monitoring of the temperature of a drive while writing it, to tell thermal throttling apart from
+//! a failing drive when the throughput drops.
+use std::time::{Duration, Instant};
+
+/// The interval between two temperature samples.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The minimum temperature rise, in degrees Celsius, for a throughput drop to be attributed to
+/// thermal throttling.
+const THROTTLING_MIN_RISE: f64 = 5.0;
+
+/// The fraction of the highest throughput below which the throughput is considered dropped.
+const THROTTLING_MAX_FRACTION: f64 = 0.5;
+
+/// A temperature sample, with the throughput of the drive since the previous sample.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// The time elapsed since the start of the phase.
+    pub elapsed: Duration,
+    /// The temperature of the drive, in degrees Celsius.
+    pub temperature: f64,
+    /// The throughput of the drive since the previous sample, in bytes per second of I/O time.
+    pub bytes_per_sec: f64,
+}
+
+/// The temperature of a drive sampled every SAMPLE_INTERVAL during a phase, with the throughput
+/// between the samples.
+pub struct Monitor {
+    start: Instant,
+    last_sample: Instant,
+    /// The temperature at the start of the phase, if the drive reported it.
+    start_temperature: Option<f64>,
+    /// The bytes transferred, and the time spent transferring them, since the last sample.
+    bytes: u64,
+    busy: Duration,
+    samples: Vec<Sample>,
+}
+
+impl Monitor {
+    /// Start monitoring a phase, with the temperature of the drive at its start.
+    pub fn new(start_temperature: Option<f64>) -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last_sample: now,
+            start_temperature,
+            bytes: 0,
+            busy: Duration::ZERO,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Record the transfer of `bytes` in `duration`, and sample the temperature returned by
+    /// `temperature` if SAMPLE_INTERVAL elapsed since the previous sample.
+    pub fn add(
+        &mut self,
+        bytes: usize,
+        duration: Duration,
+        temperature: impl FnOnce() -> Option<f64>,
+    ) {
+        self.bytes += bytes as u64;
+        self.busy += duration;
+        if self.last_sample.elapsed() < SAMPLE_INTERVAL || self.busy.is_zero() {
+            return;
+        }
+        self.last_sample = Instant::now();
+        if let Some(temperature) = temperature() {
+            self.samples.push(Sample {
+                elapsed: self.start.elapsed(),
+                temperature,
+                bytes_per_sec: self.bytes as f64 / self.busy.as_secs_f64(),
+            });
+        }
+        self.bytes = 0;
+        self.busy = Duration::ZERO;
+    }
+
+    /// Print the range of temperatures of the drive during the phase, and the thermal throttling
+    /// found, if any.
+    pub fn print(&self) {
+        let temperatures = self
+            .start_temperature
+            .iter()
+            .chain(self.samples.iter().map(|sample| &sample.temperature));
+        let Some(peak) = temperatures.clone().copied().reduce(f64::max) else {
+            return;
+        };
+        let lowest = temperatures.copied().fold(peak, f64::min);
+        println!(
+            "temperature: {:.0} °C to {:.0} °C (peak), {} samples",
+            lowest,
+            peak,
+            self.samples.len()
+        );
+        if let Some((before, during)) = find_throttling(&self.samples) {
+            println!(
+                "{}",
+                console::style(format!(
+                    "Thermal throttling: the throughput dropped from {:.2} MB/s to {:.2} MB/s \
+                     after {:.0} s, while the temperature rose from {:.0} °C to {:.0} °C. The \
+                     drive slows down when hot: this is not a sign of failure.",
+                    before.bytes_per_sec / 1_000_000.0,
+                    during.bytes_per_sec / 1_000_000.0,
+                    during.elapsed.as_secs_f64(),
+                    before.temperature,
+                    during.temperature
+                ))
+                .yellow()
+            );
+        }
+    }
+}
+
+/// Find the first sample in `samples` whose throughput dropped below THROTTLING_MAX_FRACTION of
+/// the highest throughput before it, while the temperature rose by at least THROTTLING_MIN_RISE.
+/// Returns the pair `(fastest, throttled)`: the sample with the highest throughput before the
+/// drop, then the throttled sample.
+pub fn find_throttling(samples: &[Sample]) -> Option<(&Sample, &Sample)> {
+    let mut fastest = samples.first()?;
+    for sample in samples {
+        if sample.bytes_per_sec > fastest.bytes_per_sec {
+            fastest = sample;
+        } else if sample.bytes_per_sec < fastest.bytes_per_sec * THROTTLING_MAX_FRACTION
+            && sample.temperature >= fastest.temperature + THROTTLING_MIN_RISE
+        {
+            return Some((fastest, sample));
+        }
+    }
+    None
+}
@@ -0,0 +1,427 @@
+/*
+Copyright (c) 2024 Ludovico Cavedon <ludovico.cavedon@gmail.com>
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Full-screen dashboard for live monitoring of the test, shown instead of the progress bars.
+//! The read/write loops send their updates through a channel to a thread rendering the dashboard,
+//! so that drawing never slows down the I/O. The dashboard is shown only while a phase is running:
+//! the statistics and results printed between phases stay on the normal terminal screen.
+use crate::{BlockReport, MapSymbols};
+use log::Level;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        execute,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Block, Gauge, Paragraph, Sparkline},
+    Frame, Terminal,
+};
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal, Stdout},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The minimum interval between two redraws of the dashboard.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// The number of block latencies kept for the sparkline.
+const MAX_LATENCIES: usize = 1024;
+/// The number of log messages shown at the bottom of the dashboard.
+const MAX_MESSAGES: usize = 4;
+
+/// Updates sent to the dashboard thread.
+enum Event {
+    /// A phase testing `total` blocks started.
+    Phase { name: String, total: usize },
+    /// The I/O of a block completed. `idx` is the index of the block in the validation map, and
+    /// `report` its new state, if changed by the I/O.
+    Block {
+        idx: usize,
+        bytes: usize,
+        duration: Option<Duration>,
+        report: Option<BlockReport>,
+    },
+    /// The validation map was updated.
+    Map(Vec<BlockReport>),
+    /// A log message was emitted.
+    Message(Level, String),
+    /// The phase ended: the dashboard must be hidden before acknowledging on the sender.
+    EndPhase(mpsc::Sender<()>),
+}
+
+/// Handle to the dashboard of a drive test. The dashboard thread stops when all the handles are
+/// dropped.
+#[derive(Clone)]
+pub struct Dashboard {
+    sender: mpsc::Sender<Event>,
+}
+
+impl Dashboard {
+    /// Start the dashboard for testing `num_blocks` blocks of the drive at `drive_path`, with a
+    /// validation map `map_width` blocks wide drawn with `symbols`.
+    /// Returns `None` if stdout is not a terminal, in which case the progress bars must be used.
+    pub fn start(
+        drive_path: &str,
+        num_blocks: usize,
+        map_width: usize,
+        symbols: &'static MapSymbols,
+    ) -> Option<Self> {
+        if !std::io::stdout().is_terminal() {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let mut state = State::new(drive_path, num_blocks, map_width, symbols);
+        thread::spawn(move || state.run(receiver));
+        Some(Self { sender })
+    }
+
+    /// Show the dashboard for `phase`, which tests `total` blocks. Log messages are shown on the
+    /// dashboard until the end of the phase.
+    pub fn phase(&self, phase: &str, total: usize) {
+        self.send(Event::Phase {
+            name: phase.to_string(),
+            total,
+        });
+        let sender = self.sender.clone();
+        crate::logger::capture(Some(Box::new(move |level, message| {
+            let _ = sender.send(Event::Message(level, message));
+        })));
+    }
+
+    /// Record the completion of the I/O of `bytes` bytes of the block at index `idx` of the
+    /// validation map, which took `duration` if successful. `report` is the new state of the block,
+    /// if changed.
+    pub fn block(
+        &self,
+        idx: usize,
+        bytes: usize,
+        duration: Option<Duration>,
+        report: Option<BlockReport>,
+    ) {
+        self.send(Event::Block {
+            idx,
+            bytes,
+            duration,
+            report,
+        });
+    }
+
+    /// Update the whole validation map.
+    pub fn map(&self, validation_map: &[BlockReport]) {
+        self.send(Event::Map(validation_map.to_vec()));
+    }
+
+    /// Hide the dashboard at the end of a phase, and wait until the terminal is restored. The log
+    /// messages of the phase are then printed to stderr.
+    pub fn end_phase(&self) {
+        crate::logger::capture(None);
+        let (ack_sender, ack_receiver) = mpsc::channel();
+        self.send(Event::EndPhase(ack_sender));
+        let _ = ack_receiver.recv();
+    }
+
+    /// Send `event` to the dashboard thread. The dashboard is best effort: if the thread died,
+    /// the test goes on without it.
+    fn send(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+}
+
+/// State of the dashboard, owned by the dashboard thread.
+struct State {
+    drive_path: String,
+    map_width: usize,
+    symbols: &'static MapSymbols,
+    validation_map: Vec<BlockReport>,
+    phase: String,
+    blocks_total: usize,
+    blocks_done: usize,
+    /// Total bytes and I/O time of the phase, for the throughput.
+    bytes: usize,
+    io_time: Duration,
+    start: Instant,
+    /// The number of failed blocks of each kind, over the whole test.
+    read_errors: usize,
+    write_errors: usize,
+    unstable: usize,
+    /// The latencies of the last blocks, in microseconds.
+    latencies: VecDeque<u64>,
+    /// The log messages emitted during the phase.
+    messages: Vec<(Level, String)>,
+    /// The terminal, while the dashboard is shown.
+    terminal: Option<Terminal<CrosstermBackend<Stdout>>>,
+}
+
+impl State {
+    fn new(
+        drive_path: &str,
+        num_blocks: usize,
+        map_width: usize,
+        symbols: &'static MapSymbols,
+    ) -> Self {
+        Self {
+            drive_path: drive_path.to_string(),
+            map_width: map_width.max(1),
+            symbols,
+            validation_map: vec![BlockReport::Unknown; num_blocks],
+            phase: String::new(),
+            blocks_total: 0,
+            blocks_done: 0,
+            bytes: 0,
+            io_time: Duration::ZERO,
+            start: Instant::now(),
+            read_errors: 0,
+            write_errors: 0,
+            unstable: 0,
+            latencies: VecDeque::with_capacity(MAX_LATENCIES),
+            messages: Vec::new(),
+            terminal: None,
+        }
+    }
+
+    /// Process the events from `receiver` until all the senders are dropped.
+    fn run(&mut self, receiver: mpsc::Receiver<Event>) {
+        let mut last_draw = Instant::now();
+        loop {
+            match receiver.recv_timeout(REDRAW_INTERVAL) {
+                Ok(event) => self.handle(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            if last_draw.elapsed() >= REDRAW_INTERVAL {
+                self.draw();
+                last_draw = Instant::now();
+            }
+        }
+        self.hide();
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Phase { name, total } => {
+                self.phase = name;
+                self.blocks_total = total;
+                self.blocks_done = 0;
+                self.bytes = 0;
+                self.io_time = Duration::ZERO;
+                self.start = Instant::now();
+                self.latencies.clear();
+                self.messages.clear();
+                self.show();
+                self.draw();
+            }
+            Event::Block {
+                idx,
+                bytes,
+                duration,
+                report,
+            } => {
+                self.blocks_done += 1;
+                if let Some(duration) = duration {
+                    self.bytes += bytes;
+                    self.io_time += duration;
+                    if self.latencies.len() == MAX_LATENCIES {
+                        self.latencies.pop_front();
+                    }
+                    self.latencies.push_back(duration.as_micros() as u64);
+                }
+                if let Some(report) = report {
+                    match report {
+                        BlockReport::ReadError => self.read_errors += 1,
+                        BlockReport::WriteError => self.write_errors += 1,
+                        BlockReport::Unstable => self.unstable += 1,
+                        _ => {}
+                    }
+                    if let Some(cell) = self.validation_map.get_mut(idx) {
+                        *cell = report;
+                    }
+                }
+            }
+            Event::Map(validation_map) => self.validation_map = validation_map,
+            Event::Message(level, message) => self.messages.push((level, message)),
+            Event::EndPhase(ack) => {
+                self.draw();
+                self.hide();
+                for (level, message) in self.messages.drain(..) {
+                    crate::logger::print(level, &message);
+                }
+                let _ = ack.send(());
+            }
+        }
+    }
+
+    /// Switch to the alternate screen and start drawing the dashboard.
+    fn show(&mut self) {
+        if self.terminal.is_some() {
+            return;
+        }
+        let mut stdout = std::io::stdout();
+        if execute!(stdout, EnterAlternateScreen).is_err() {
+            return;
+        }
+        match Terminal::new(CrosstermBackend::new(stdout)) {
+            Ok(mut terminal) => {
+                let _ = terminal.hide_cursor();
+                let _ = terminal.clear();
+                self.terminal = Some(terminal);
+            }
+            Err(_) => {
+                let _ = execute!(std::io::stdout(), LeaveAlternateScreen);
+            }
+        }
+    }
+
+    /// Stop drawing the dashboard and switch back to the normal screen.
+    fn hide(&mut self) {
+        if let Some(mut terminal) = self.terminal.take() {
+            let _ = terminal.show_cursor();
+            let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+        }
+    }
+
+    fn draw(&mut self) {
+        if let Some(mut terminal) = self.terminal.take() {
+            let _ = terminal.draw(|frame| self.render(frame));
+            self.terminal = Some(terminal);
+        }
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [gauge_area, stats_area, latency_area, map_area, messages_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(1),
+            Constraint::Length(6),
+            Constraint::Min(3),
+            Constraint::Length(MAX_MESSAGES as u16 + 2),
+        ])
+        .areas(frame.area());
+
+        let ratio = if self.blocks_total == 0 {
+            0.0
+        } else {
+            (self.blocks_done as f64 / self.blocks_total as f64).min(1.0)
+        };
+        let gauge = Gauge::default()
+            .block(Block::bordered().title(format!(" {} - {} ", self.drive_path, self.phase)))
+            .gauge_style(Style::new().blue())
+            .ratio(ratio)
+            .label(format!("{}/{}", self.blocks_done, self.blocks_total));
+        frame.render_widget(gauge, gauge_area);
+
+        let throughput = if self.io_time.is_zero() {
+            0.0
+        } else {
+            self.bytes as f64 / self.io_time.as_secs_f64() / 1_000_000.0
+        };
+        let stats = Line::from(vec![
+            Span::raw(format!(" Throughput: {:.3} MB/s   ", throughput)),
+            Span::raw("Errors: "),
+            Span::styled(
+                format!("{} read", self.read_errors),
+                Style::new().fg(Color::Blue),
+            ),
+            Span::raw(", "),
+            Span::styled(
+                format!("{} write", self.write_errors),
+                Style::new().fg(Color::Yellow),
+            ),
+            Span::raw(", "),
+            Span::styled(
+                format!("{} unstable", self.unstable),
+                Style::new().fg(Color::Magenta),
+            ),
+            Span::raw(format!(
+                "   Elapsed: {:.1} s",
+                self.start.elapsed().as_secs_f64()
+            )),
+        ]);
+        frame.render_widget(Paragraph::new(stats), stats_area);
+
+        // Show the latencies of the most recent blocks fitting the sparkline width.
+        let width = latency_area.width.saturating_sub(2) as usize;
+        let skip = self.latencies.len().saturating_sub(width);
+        let sparkline = Sparkline::default()
+            .block(Block::bordered().title(" Latency (µs) "))
+            .style(Style::new().green())
+            .data(self.latencies.iter().skip(skip));
+        frame.render_widget(sparkline, latency_area);
+
+        let map_lines = self
+            .validation_map
+            .chunks(self.map_width)
+            .map(|row| {
+                Line::from(
+                    row.iter()
+                        .map(|report| map_cell(report, self.symbols))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(map_lines).block(Block::bordered().title(" Validation map ")),
+            map_area,
+        );
+
+        let skip = self.messages.len().saturating_sub(MAX_MESSAGES);
+        let messages = self
+            .messages
+            .iter()
+            .skip(skip)
+            .map(|(level, message)| {
+                let color = match level {
+                    Level::Error => Color::Red,
+                    Level::Warn => Color::Yellow,
+                    Level::Info => Color::Cyan,
+                    Level::Debug | Level::Trace => Color::DarkGray,
+                };
+                Line::styled(format!("[{}] {}", level, message), Style::new().fg(color))
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(messages).block(Block::bordered().title(" Messages ")),
+            messages_area,
+        );
+    }
+}
+
+/// Return the cell representing `report` in the validation map, with the same symbols and colors
+/// used for the map printed at the end of the test. Blocks not tested yet are shown as dots.
+fn map_cell(report: &BlockReport, symbols: &MapSymbols) -> Span<'static> {
+    let color = match report {
+        BlockReport::Unknown => return Span::styled("·", Style::new().fg(Color::DarkGray)),
+        BlockReport::Validated => Color::Green,
+        BlockReport::ReadError => Color::Blue,
+        BlockReport::ReadSuccessful => Color::Green,
+        BlockReport::WriteError => Color::Yellow,
+        BlockReport::NoStorage => Color::Red,
+        BlockReport::Unstable => Color::Magenta,
+        BlockReport::Skipped => Color::Cyan,
+        BlockReport::Excluded => Color::DarkGray,
+        BlockReport::Unverified => Color::LightGreen,
+    };
+    Span::styled(symbols.symbol(report), Style::new().fg(color))
+}